@@ -1,12 +1,298 @@
+use ratatui::buffer::Buffer;
+use ratatui::layout::Rect;
+use ratatui::prelude::BlockExt;
+use ratatui::style::Style;
+use ratatui::style::Styled;
 use ratatui::text::Line;
-use ratatui::widgets::Paragraph;
+use ratatui::text::Text;
+use ratatui::widgets::Block;
+use ratatui::widgets::Scrollbar;
+use ratatui::widgets::ScrollbarOrientation;
+use ratatui::widgets::ScrollbarState;
+use ratatui::widgets::StatefulWidget;
+use ratatui::widgets::StatefulWidgetRef;
+use ratatui::widgets::Widget;
+use ratatui::widgets::WidgetRef;
 
-pub struct DiffState {}
+// MARK: DiffLine
 
+/// What a line of a diff is, so that a view can jump between files and hunks.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum LineKind {
+    #[default]
+    Text,
+    File,
+    Hunk,
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct DiffLine<'a> {
-    inner: Line<'a>,
+    pub(crate) content: Line<'a>,
+    pub(crate) kind: LineKind,
+}
+
+impl<'a> DiffLine<'a> {
+    pub fn new<T: Into<Line<'a>>>(content: T, kind: LineKind) -> Self {
+        Self {
+            content: content.into(),
+            kind,
+        }
+    }
+
+    pub const fn kind(&self) -> LineKind {
+        self.kind
+    }
+
+    pub const fn content(&self) -> &Line<'a> {
+        &self.content
+    }
+}
+
+impl<'a> From<Line<'a>> for DiffLine<'a> {
+    fn from(content: Line<'a>) -> Self {
+        Self::new(content, LineKind::Text)
+    }
+}
+
+impl<'a> From<&'a str> for DiffLine<'a> {
+    fn from(content: &'a str) -> Self {
+        Self::new(content, LineKind::Text)
+    }
 }
 
+// MARK: Diff
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
 pub struct Diff<'a> {
-    inner: Paragraph<'a>,
+    pub(crate) block: Option<Block<'a>>,
+    pub(crate) lines: Vec<DiffLine<'a>>,
+    pub(crate) style: Style,
+}
+
+impl<'a> Diff<'a> {
+    pub fn new<T>(lines: T) -> Self
+    where
+        T: IntoIterator,
+        T::Item: Into<DiffLine<'a>>,
+    {
+        Self {
+            lines: lines.into_iter().map(Into::into).collect(),
+            ..Self::default()
+        }
+    }
+
+    #[must_use = "method moves the value of self and returns the modified value"]
+    pub fn block(mut self, block: Block<'a>) -> Self {
+        self.block = Some(block);
+        self
+    }
+
+    #[must_use = "method moves the value of self and returns the modified value"]
+    pub fn style<S: Into<Style>>(mut self, style: S) -> Self {
+        self.style = style.into();
+        self
+    }
+
+    pub fn lines(&self) -> &[DiffLine<'a>] {
+        &self.lines
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The first line of `kind` after `from`.
+    pub fn find_next(&self, from: usize, kind: LineKind) -> Option<usize> {
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(from + 1)
+            .find(|(_, line)| line.kind == kind)
+            .map(|(index, _)| index)
+    }
+
+    /// The last line of `kind` before `from`.
+    pub fn find_previous(&self, from: usize, kind: LineKind) -> Option<usize> {
+        self.lines
+            .iter()
+            .enumerate()
+            .take(from)
+            .rev()
+            .find(|(_, line)| line.kind == kind)
+            .map(|(index, _)| index)
+    }
+
+    /// Scrolls so that the line at `index` is at the top, or as close as the
+    /// end of the diff allows. Unlike [`DiffState::scroll_to`], this works
+    /// before the diff has been rendered.
+    pub fn scroll_to_line(&self, state: &mut DiffState, index: usize) {
+        state.length = self.lines.len();
+        state.scroll_to(index);
+    }
+
+    /// Scrolls to the next line of `kind`, returning whether there was one.
+    pub fn scroll_to_next(&self, state: &mut DiffState, kind: LineKind) -> bool {
+        match self.find_next(state.offset, kind) {
+            Some(index) => {
+                state.scroll_to(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Scrolls to the previous line of `kind`, returning whether there was one.
+    pub fn scroll_to_previous(&self, state: &mut DiffState, kind: LineKind) -> bool {
+        match self.find_previous(state.offset, kind) {
+            Some(index) => {
+                state.scroll_to(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'a> From<Text<'a>> for Diff<'a> {
+    fn from(text: Text<'a>) -> Self {
+        Self::new(text.lines)
+    }
+}
+
+impl<'a> Styled for Diff<'a> {
+    type Item = Self;
+
+    fn style(&self) -> Style {
+        self.style
+    }
+
+    fn set_style<S: Into<Style>>(self, style: S) -> Self::Item {
+        self.style(style)
+    }
+}
+
+// MARK: State
+
+/// How far a diff is scrolled. The length and height are those of the last
+/// render, and keep scrolling within the diff.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct DiffState {
+    pub(crate) offset: usize,
+    pub(crate) length: usize,
+    pub(crate) height: usize,
+}
+
+impl DiffState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn max_offset(&self) -> usize {
+        self.length.saturating_sub(self.height)
+    }
+
+    pub fn scroll_to(&mut self, offset: usize) {
+        self.offset = offset.min(self.max_offset());
+    }
+
+    pub fn scroll_by(&mut self, delta: isize) {
+        self.scroll_to(self.offset.saturating_add_signed(delta));
+    }
+
+    pub fn scroll_to_top(&mut self) {
+        self.offset = 0;
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.offset = self.max_offset();
+    }
+
+    /// Scrolls by a screen, keeping a line of the previous one in view.
+    pub fn page_down(&mut self) {
+        self.scroll_by(self.height.saturating_sub(1).max(1) as isize);
+    }
+
+    pub fn page_up(&mut self) {
+        self.scroll_by(-(self.height.saturating_sub(1).max(1) as isize));
+    }
+}
+
+// MARK: Rendering
+
+impl Widget for Diff<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        WidgetRef::render_ref(&self, area, buf);
+    }
+}
+
+impl WidgetRef for Diff<'_> {
+    fn render_ref(&self, area: Rect, buf: &mut Buffer) {
+        let mut state = DiffState::default();
+        StatefulWidgetRef::render_ref(self, area, buf, &mut state);
+    }
+}
+
+impl StatefulWidget for Diff<'_> {
+    type State = DiffState;
+
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        StatefulWidgetRef::render_ref(&self, area, buf, state);
+    }
+}
+
+impl StatefulWidgetRef for Diff<'_> {
+    type State = DiffState;
+
+    fn render_ref(&self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        buf.set_style(area, self.style);
+        self.block.render(area, buf);
+        let diff_area = self.block.inner_if_some(area);
+        if diff_area.is_empty() {
+            return;
+        }
+
+        state.length = self.lines.len();
+        state.height = diff_area.height as usize;
+        state.scroll_to(state.offset);
+
+        // Leave a column for the scrollbar only when there is something to scroll.
+        let scrolls = state.length > state.height;
+        let lines_area = if scrolls {
+            Rect {
+                width: diff_area.width.saturating_sub(1),
+                ..diff_area
+            }
+        } else {
+            diff_area
+        };
+        for (y, line) in (lines_area.top()..lines_area.bottom())
+            .zip(self.lines.iter().skip(state.offset))
+        {
+            let line_area = Rect {
+                y,
+                height: 1,
+                ..lines_area
+            };
+            line.content.render_ref(line_area, buf);
+        }
+
+        if scrolls {
+            let mut scrollbar = ScrollbarState::new(state.max_offset()).position(state.offset);
+            Scrollbar::new(ScrollbarOrientation::VerticalRight)
+                .begin_symbol(None)
+                .end_symbol(None)
+                .render(diff_area, buf, &mut scrollbar);
+        }
+    }
 }
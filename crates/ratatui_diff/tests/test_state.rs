@@ -0,0 +1,124 @@
+use ratatui::buffer::Buffer;
+use ratatui::layout::Rect;
+use ratatui::widgets::StatefulWidgetRef;
+use ratatui_diff::Diff;
+use ratatui_diff::DiffLine;
+use ratatui_diff::DiffState;
+use ratatui_diff::LineKind;
+
+fn diff() -> Diff<'static> {
+    Diff::new([
+        DiffLine::new("a.txt", LineKind::File),
+        DiffLine::new("@@ -1 +1 @@", LineKind::Hunk),
+        DiffLine::new("-old", LineKind::Removed),
+        DiffLine::new("+new", LineKind::Added),
+        DiffLine::new("@@ -9 +9 @@", LineKind::Hunk),
+        DiffLine::new(" same", LineKind::Context),
+        DiffLine::new("b.txt", LineKind::File),
+        DiffLine::new("@@ -1 +1 @@", LineKind::Hunk),
+        DiffLine::new("+more", LineKind::Added),
+        DiffLine::new(" end", LineKind::Context),
+    ])
+}
+
+fn render(diff: &Diff, state: &mut DiffState, height: u16) -> Buffer {
+    let area = Rect::new(0, 0, 12, height);
+    let mut buffer = Buffer::empty(area);
+    diff.render_ref(area, &mut buffer, state);
+    buffer
+}
+
+fn row(buffer: &Buffer, y: u16) -> String {
+    (0..buffer.area.width)
+        .map(|x| buffer[(x, y)].symbol())
+        .collect::<String>()
+        .trim_end()
+        .to_owned()
+}
+
+#[test]
+fn test_render_offset() {
+    let diff = diff();
+    let mut state = DiffState::new();
+    state.scroll_by(2);
+    let buffer = render(&diff, &mut state, 4);
+    // Before the first render there is no height to keep the offset within.
+    assert_eq!(state.offset(), 0);
+    assert!(row(&buffer, 0).starts_with("a.txt"));
+
+    state.scroll_by(2);
+    let buffer = render(&diff, &mut state, 4);
+    assert_eq!(state.offset(), 2);
+    assert!(row(&buffer, 0).starts_with("-old"));
+    assert!(row(&buffer, 3).starts_with(" same"));
+}
+
+#[test]
+fn test_scroll_stays_within_diff() {
+    let diff = diff();
+    let mut state = DiffState::new();
+    render(&diff, &mut state, 4);
+
+    state.scroll_by(100);
+    assert_eq!(state.offset(), 6);
+    state.scroll_by(-100);
+    assert_eq!(state.offset(), 0);
+    state.scroll_to_bottom();
+    assert_eq!(state.offset(), 6);
+    state.scroll_to_top();
+    assert_eq!(state.offset(), 0);
+}
+
+#[test]
+fn test_pages() {
+    let diff = diff();
+    let mut state = DiffState::new();
+    render(&diff, &mut state, 4);
+
+    state.page_down();
+    assert_eq!(state.offset(), 3);
+    state.page_down();
+    assert_eq!(state.offset(), 6);
+    state.page_up();
+    assert_eq!(state.offset(), 3);
+}
+
+#[test]
+fn test_jumps() {
+    let diff = diff();
+    let mut state = DiffState::new();
+    render(&diff, &mut state, 2);
+
+    assert!(diff.scroll_to_next(&mut state, LineKind::Hunk));
+    assert_eq!(state.offset(), 1);
+    assert!(diff.scroll_to_next(&mut state, LineKind::Hunk));
+    assert_eq!(state.offset(), 4);
+    assert!(diff.scroll_to_next(&mut state, LineKind::File));
+    assert_eq!(state.offset(), 6);
+    assert!(diff.scroll_to_previous(&mut state, LineKind::File));
+    assert_eq!(state.offset(), 0);
+    assert!(!diff.scroll_to_previous(&mut state, LineKind::File));
+}
+
+#[test]
+fn test_scroll_to_line_of_new_diff() {
+    let mut state = DiffState::new();
+    render(&Diff::new(["only"]), &mut state, 4);
+
+    let diff = diff();
+    diff.scroll_to_line(&mut state, 3);
+    assert_eq!(state.offset(), 3);
+    diff.scroll_to_line(&mut state, 9);
+    assert_eq!(state.offset(), 6);
+}
+
+#[test]
+fn test_scrollbar_only_when_scrolling() {
+    let diff = diff();
+    let mut state = DiffState::new();
+    let buffer = render(&diff, &mut state, 20);
+    assert!((0..20).all(|y| buffer[(11, y)].symbol() == " "));
+
+    let buffer = render(&diff, &mut state, 4);
+    assert!((0..4).any(|y| buffer[(11, y)].symbol() != " "));
+}
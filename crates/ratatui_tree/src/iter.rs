@@ -10,7 +10,12 @@ impl<'a, T: TreeView<T>> TreeIter<'a, T> {
     }
 
     pub fn new_from<R: TreeView<T> + ?Sized>(root: &'a R) -> Self {
-        Self::new(root.iter_children().rev().collect())
+        Self::new(
+            root.iter_visible_children()
+                .rev()
+                .map(|(_, child)| child)
+                .collect(),
+        )
     }
 }
 
@@ -18,9 +23,9 @@ impl<'a, T: TreeView<T>> Iterator for TreeIter<'a, T> {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop().map(|item| {
-            self.0.extend(item.iter_children().rev());
-            item
+        self.0.pop().inspect(|item| {
+            self.0
+                .extend(item.iter_visible_children().rev().map(|(_, child)| child))
         })
     }
 }
@@ -34,7 +39,12 @@ impl<'a, T: TreeView<T>> TreeIterWithDepth<'a, T> {
     }
 
     pub fn new_from<R: TreeView<T> + ?Sized>(root: &'a R) -> Self {
-        Self::new(root.iter_children().rev().map(|child| (0, child)).collect())
+        Self::new(
+            root.iter_visible_children()
+                .rev()
+                .map(|(_, child)| (0, child))
+                .collect(),
+        )
     }
 }
 
@@ -43,8 +53,11 @@ impl<'a, T: TreeView<T>> Iterator for TreeIterWithDepth<'a, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.0.pop().map(|(depth, item)| {
-            self.0
-                .extend(item.iter_children().rev().map(|child| (depth + 1, child)));
+            self.0.extend(
+                item.iter_visible_children()
+                    .rev()
+                    .map(|(_, child)| (depth + 1, child)),
+            );
             (depth, item)
         })
     }
@@ -60,8 +73,7 @@ impl<'a, T: TreeView<T>> TreeIterWithIndex<'a, T> {
 
     pub fn new_from<R: TreeView<T> + ?Sized>(root: &'a R) -> Self {
         Self::new(
-            root.iter_children()
-                .enumerate()
+            root.iter_visible_children()
                 .rev()
                 .map(|(i, child)| (TreeIndex::new(i), child))
                 .collect(),
@@ -75,8 +87,7 @@ impl<'a, T: TreeView<T>> Iterator for TreeIterWithIndex<'a, T> {
     fn next(&mut self) -> Option<Self::Item> {
         self.0.pop().map(|(index, item)| {
             self.0.extend(
-                item.iter_children()
-                    .enumerate()
+                item.iter_visible_children()
                     .rev()
                     .map(|(i, child)| (index.pushed(i), child)),
             );
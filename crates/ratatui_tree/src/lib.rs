@@ -2,6 +2,7 @@ mod index;
 pub mod index2;
 mod iter;
 mod view;
+#[allow(dead_code)]
 mod view2;
 mod widget;
 
@@ -11,15 +11,31 @@ pub trait TreeView<T: TreeView<T>> {
 
     fn iter_children(&self) -> Self::ChildIter<'_>;
 
+    /// Whether the item is left out when iterating and navigating. Hidden
+    /// items keep their place, so the indices of the items around them stay
+    /// valid.
+    fn is_hidden(&self) -> bool {
+        false
+    }
+
+    fn iter_visible_children<'a>(&'a self) -> impl DoubleEndedIterator<Item = (usize, &'a T)>
+    where
+        T: 'a,
+    {
+        self.iter_children()
+            .enumerate()
+            .filter(|(_, child)| !child.is_hidden())
+    }
+
     fn iter_descendants(&self) -> TreeIter<'_, T> {
         TreeIter::new_from(self)
     }
 
-    fn iter_descendants_with_depth(&self) -> TreeIterWithDepth<T> {
+    fn iter_descendants_with_depth(&self) -> TreeIterWithDepth<'_, T> {
         TreeIterWithDepth::new_from(self)
     }
 
-    fn iter_descendants_with_index(&self) -> TreeIterWithIndex<T> {
+    fn iter_descendants_with_index(&self) -> TreeIterWithIndex<'_, T> {
         TreeIterWithIndex::new_from(self)
     }
 
@@ -52,8 +68,8 @@ pub trait TreeView<T: TreeView<T>> {
     }
 
     fn len_descendants(&self) -> usize {
-        self.iter_children()
-            .map(|child| 1 + child.len_descendants())
+        self.iter_visible_children()
+            .map(|(_, child)| 1 + child.len_descendants())
             .sum::<usize>()
     }
 
@@ -68,71 +84,71 @@ pub trait TreeView<T: TreeView<T>> {
     fn find_offset_of_index(&self, index: &TreeIndex) -> Option<(usize, &T)> {
         self.iter_descendants_with_index()
             .enumerate()
-            .find_map(|(offset, (i, item))| match i.cmp(&index) {
+            .find_map(|(offset, (i, item))| match i.cmp(index) {
                 std::cmp::Ordering::Less => None,
-                std::cmp::Ordering::Equal => return Some(Some((offset, item))),
-                std::cmp::Ordering::Greater => return Some(None),
+                std::cmp::Ordering::Equal => Some(Some((offset, item))),
+                std::cmp::Ordering::Greater => Some(None),
             })
             .flatten()
     }
 
     fn find_nearest_to(&self, origin: &TreeIndex) -> Option<(TreeIndex, &T)> {
-        if self.is_empty() {
-            return None;
-        }
-
-        let clamped = origin.first().min(self.len_children() - 1);
-        let mut index = TreeIndex::new(clamped);
-        let mut cursor = self.get_child(clamped).unwrap();
+        let (i, mut cursor) = self.find_nearest_child_to(origin.first())?;
+        let mut index = TreeIndex::new(i);
         for &i in origin.iter_rest() {
-            if cursor.is_empty() {
-                return Some((index, cursor));
-            } else {
-                let clamped = i.min(cursor.len_children() - 1);
-                index.push(clamped);
-                cursor = cursor.get_child(clamped).unwrap();
+            match cursor.find_nearest_child_to(i) {
+                Some((i, child)) => {
+                    index.push(i);
+                    cursor = child;
+                }
+                None => break,
             }
         }
 
         Some((index, cursor))
     }
 
+    /// The visible child at the index, or else the closest one before it, or
+    /// else the first one after it.
+    fn find_nearest_child_to(&self, index: usize) -> Option<(usize, &T)> {
+        self.iter_visible_children()
+            .take_while(|(i, _)| *i <= index)
+            .last()
+            .or_else(|| self.find_first_child())
+    }
+
     fn find_first_child(&self) -> Option<(usize, &T)> {
-        self.get_child(0).map(|child| (0, child))
+        self.iter_visible_children().next()
     }
 
     fn find_first_descendant(&self) -> Option<(TreeIndex, &T)> {
-        self.get_child(0).map(|child| (TreeIndex::new(0), child))
+        self.find_first_child()
+            .map(|(i, child)| (TreeIndex::new(i), child))
     }
 
     fn find_last_descendant_in(&self, mut index: TreeIndex) -> Option<(TreeIndex, &T)> {
         let mut cursor = self.get_descendant(&index)?;
-        while !cursor.is_empty() {
-            let i = cursor.len_children() - 1;
+        while let Some((i, child)) = cursor.find_last_child() {
             index.push(i);
-            cursor = cursor.get_child(i)?;
+            cursor = child;
         }
 
         Some((index, cursor))
     }
 
     fn find_last_child(&self) -> Option<(usize, &T)> {
-        let i = self.len_children() - 1;
-        self.get_child(0).map(|child| (i, child))
+        self.iter_visible_children().next_back()
     }
 
     fn find_last_descendant(&self) -> Option<(TreeIndex, &T)> {
-        self.find_last_descendant_in(TreeIndex::new(self.len_children().saturating_sub(1)))
+        self.find_last_child()
+            .and_then(|(i, _)| self.find_last_descendant_in(TreeIndex::new(i)))
     }
 
     fn find_previous_child_to(&self, index: usize) -> Option<(usize, &T)> {
-        if index > 0 {
-            let previous_index = index - 1;
-            self.get_child(previous_index)
-                .map(|child| (previous_index, child))
-        } else {
-            None
-        }
+        self.iter_visible_children()
+            .take_while(|(i, _)| *i < index)
+            .last()
     }
 
     fn find_previous_sibling_of(&self, index: &TreeIndex) -> Option<(TreeIndex, &T)> {
@@ -170,12 +186,7 @@ pub trait TreeView<T: TreeView<T>> {
     }
 
     fn find_next_child_to(&self, index: usize) -> Option<(usize, &T)> {
-        let next_index = index + 1;
-        if next_index < self.len_children() {
-            self.get_child(next_index).map(|child| (next_index, child))
-        } else {
-            None
-        }
+        self.iter_visible_children().find(|(i, _)| *i > index)
     }
 
     fn find_next_sibling_of(&self, index: &TreeIndex) -> Option<(TreeIndex, &T)> {
@@ -1,5 +1,3 @@
-use crate::index2::{TreeIndex, TreeIndexBuf};
-
 pub trait ChildrenView {
     type Child<'b>
     where
@@ -22,6 +22,7 @@ pub struct TreeItem<'a> {
     pub(crate) content: Text<'a>,
     pub(crate) style: Style,
     pub(crate) children: Vec<TreeItem<'a>>,
+    pub(crate) hidden: bool,
 }
 
 impl<'a> TreeItem<'a> {
@@ -35,6 +36,7 @@ impl<'a> TreeItem<'a> {
             content: content.into(),
             children: children.into_iter().map(Into::into).collect(),
             style: Style::default(),
+            hidden: false,
         }
     }
 
@@ -63,6 +65,14 @@ impl<'a> TreeItem<'a> {
         self
     }
 
+    /// Leaves the item and its children out of the tree, without changing the
+    /// indices of the items after it.
+    #[must_use = "method moves the value of self and returns the modified value"]
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
     pub fn height(&self) -> usize {
         self.content.height()
     }
@@ -93,6 +103,10 @@ impl<'a> TreeView<TreeItem<'a>> for TreeItem<'a> {
     fn iter_children(&self) -> Self::ChildIter<'_> {
         self.children.iter()
     }
+
+    fn is_hidden(&self) -> bool {
+        self.hidden
+    }
 }
 
 // MARK: Tree
@@ -227,6 +241,11 @@ impl TreeState {
         &self.selected
     }
 
+    /// How many items are scrolled past, as of the last render.
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
     pub fn selected_mut(&mut self) -> &mut Option<TreeIndex> {
         &mut self.selected
     }
@@ -274,6 +293,25 @@ impl<'a> Tree<'a> {
     pub fn select_parent_state(&self, state: &mut TreeState) {
         self.select_parent(&mut state.selected);
     }
+
+    /// The item drawn on a screen row when the tree was last rendered in
+    /// `area` with this state, for example under the mouse.
+    pub fn index_at_row(&self, area: Rect, state: &TreeState, row: u16) -> Option<TreeIndex> {
+        let tree_area = self.block.inner_if_some(area);
+        if row < tree_area.top() || row >= tree_area.bottom() {
+            return None;
+        }
+
+        let row = (row - tree_area.top()) as usize;
+        let mut bottom = 0;
+        for (index, item) in self.iter_descendants_with_index().skip(state.offset) {
+            bottom += item.height();
+            if row < bottom {
+                return Some(index);
+            }
+        }
+        None
+    }
 }
 
 // MARK: Rendering
@@ -311,7 +349,7 @@ impl StatefulWidgetRef for Tree<'_> {
             return;
         }
 
-        if self.is_empty() {
+        if self.find_first_child().is_none() {
             state.select(None);
             return;
         }
@@ -320,12 +358,12 @@ impl StatefulWidgetRef for Tree<'_> {
         state.selected = state
             .selected
             .as_ref()
-            .and_then(|index| self.find_nearest_to(&index))
+            .and_then(|index| self.find_nearest_to(index))
             .map(|(index, _)| index);
         let selected_offset = state
             .selected
             .as_ref()
-            .and_then(|index| self.find_offset_of_index(&index))
+            .and_then(|index| self.find_offset_of_index(index))
             .map(|(offset, _)| offset);
 
         let tree_height = tree_area.height as usize;
@@ -384,7 +422,7 @@ impl StatefulWidgetRef for Tree<'_> {
 
             buf.set_style(row_area, item_style);
 
-            let is_selected = selected_offset.map_or(false, |s| s == i);
+            let is_selected = selected_offset == Some(i);
 
             let item_area = if selection_spacing {
                 let highlight_symbol_width = self.highlight_symbol.unwrap_or("").width() as u16;
@@ -0,0 +1,136 @@
+use ratatui::buffer::Buffer;
+use ratatui::layout::Rect;
+use ratatui::widgets::StatefulWidget;
+use ratatui_tree::TreeItem;
+use ratatui_tree::TreeState;
+use ratatui_tree::TreeView;
+use ratatui_tree::tree_index as I;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Tree(&'static str, bool, pub Vec<Tree>);
+
+impl TreeView<Tree> for Tree {
+    type ChildIter<'a> = std::slice::Iter<'a, Tree>;
+
+    fn iter_children(&self) -> Self::ChildIter<'_> {
+        self.2.iter()
+    }
+
+    fn is_hidden(&self) -> bool {
+        self.1
+    }
+}
+
+fn shown(name: &'static str, children: Vec<Tree>) -> Tree {
+    Tree(name, false, children)
+}
+
+fn hidden(name: &'static str, children: Vec<Tree>) -> Tree {
+    Tree(name, true, children)
+}
+
+fn tree() -> Tree {
+    shown(
+        "root",
+        vec![
+            hidden("a", vec![shown("b", vec![])]),
+            shown(
+                "x",
+                vec![shown("y", vec![]), hidden("z", vec![]), shown("w", vec![])],
+            ),
+            shown("p", vec![hidden("q", vec![])]),
+            hidden("m", vec![]),
+        ],
+    )
+}
+
+#[test]
+fn test_iter_skips_hidden() {
+    let tree = tree();
+    assert_eq!(
+        tree.iter_descendants().map(|t| t.0).collect::<Vec<_>>(),
+        vec!["x", "y", "w", "p"]
+    );
+    assert_eq!(tree.len_descendants(), 4);
+}
+
+#[test]
+fn test_iter_keeps_indices() {
+    let tree = tree();
+    assert_eq!(
+        tree.iter_descendants_with_index()
+            .map(|(i, t)| (i, t.0))
+            .collect::<Vec<_>>(),
+        vec![(I![1], "x"), (I![1, 0], "y"), (I![1, 2], "w"), (I![2], "p")]
+    );
+    assert_eq!(tree.find_offset_of_index(&I![1, 2]).map(|r| r.0), Some(2));
+    assert_eq!(tree.find_offset_of_index(&I![1, 1]), None);
+    assert_eq!(tree.get_child(0).map(|t| t.0), Some("a"));
+}
+
+#[test]
+fn test_navigation_skips_hidden() {
+    let tree = tree();
+    assert_eq!(tree.find_first_descendant().map(|r| r.0), Some(I![1]));
+    assert_eq!(tree.find_last_descendant().map(|r| r.0), Some(I![2]));
+    assert_eq!(
+        tree.find_next_relative_of(&I![1, 0]).map(|r| r.0),
+        Some(I![1, 2])
+    );
+    assert_eq!(
+        tree.find_next_relative_of(&I![1, 2]).map(|r| r.0),
+        Some(I![2])
+    );
+    assert_eq!(tree.find_next_relative_of(&I![2]).map(|r| r.0), None);
+    assert_eq!(
+        tree.find_previous_relative_of(&I![2]).map(|r| r.0),
+        Some(I![1, 2])
+    );
+    assert_eq!(
+        tree.find_previous_relative_of(&I![1, 2]).map(|r| r.0),
+        Some(I![1, 0])
+    );
+    assert_eq!(tree.find_previous_relative_of(&I![1]).map(|r| r.0), None);
+}
+
+#[test]
+fn test_nearest_to_hidden() {
+    let tree = tree();
+    assert_eq!(tree.find_nearest_to(&I![0, 0]).map(|r| r.0), Some(I![1, 0]));
+    assert_eq!(tree.find_nearest_to(&I![1, 1]).map(|r| r.0), Some(I![1, 0]));
+    assert_eq!(tree.find_nearest_to(&I![2, 0]).map(|r| r.0), Some(I![2]));
+    assert_eq!(tree.find_nearest_to(&I![3]).map(|r| r.0), Some(I![2]));
+
+    let tree = shown("root", vec![hidden("a", vec![])]);
+    assert_eq!(tree.find_nearest_to(&I![0]), None);
+}
+
+#[test]
+fn test_render_skips_hidden() {
+    let tree = ratatui_tree::Tree::new([
+        TreeItem::new_empty("a").hidden(true),
+        TreeItem::new(
+            "x",
+            [
+                TreeItem::new_empty("y").hidden(true),
+                TreeItem::new_empty("z"),
+            ],
+        ),
+    ]);
+    let area = Rect::new(0, 0, 8, 3);
+    let mut buffer = Buffer::empty(area);
+    let mut state = TreeState::new().with_selected(I![0]);
+    tree.render(area, &mut buffer, &mut state);
+
+    let rows = (0..area.height)
+        .map(|y| {
+            (0..area.width)
+                .map(|x| buffer[(x, y)].symbol())
+                .collect::<String>()
+                .trim_end()
+                .to_owned()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(rows, vec!["x", "  z", ""]);
+    assert_eq!(state.selected(), &Some(I![1]));
+}
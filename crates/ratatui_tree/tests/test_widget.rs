@@ -0,0 +1,46 @@
+use ratatui::buffer::Buffer;
+use ratatui::layout::Rect;
+use ratatui::widgets::Block;
+use ratatui::widgets::StatefulWidgetRef;
+use ratatui_tree::Tree;
+use ratatui_tree::TreeItem;
+use ratatui_tree::TreeState;
+use ratatui_tree::tree_index as I;
+
+fn tree() -> Tree<'static> {
+    Tree::new([
+        TreeItem::new("a", [TreeItem::new_empty("b"), TreeItem::new_empty("c")]),
+        TreeItem::new_empty("x"),
+        TreeItem::new_empty("y"),
+        TreeItem::new_empty("z"),
+    ])
+    .block(Block::bordered())
+}
+
+#[test]
+fn test_index_at_row() {
+    let tree = tree();
+    let area = Rect::new(2, 1, 10, 6);
+    let mut buffer = Buffer::empty(Rect::new(0, 0, 12, 8));
+    let mut state = TreeState::new();
+    tree.render_ref(area, &mut buffer, &mut state);
+
+    assert_eq!(tree.index_at_row(area, &state, 1), None);
+    assert_eq!(tree.index_at_row(area, &state, 2), Some(I![0]));
+    assert_eq!(tree.index_at_row(area, &state, 3), Some(I![0, 0]));
+    assert_eq!(tree.index_at_row(area, &state, 5), Some(I![1]));
+    assert_eq!(tree.index_at_row(area, &state, 6), None);
+}
+
+#[test]
+fn test_index_at_row_scrolled() {
+    let tree = tree();
+    let area = Rect::new(0, 0, 10, 4);
+    let mut buffer = Buffer::empty(area);
+    let mut state = TreeState::new().with_selected(I![3]);
+    tree.render_ref(area, &mut buffer, &mut state);
+
+    assert_eq!(state.offset(), 4);
+    assert_eq!(tree.index_at_row(area, &state, 1), Some(I![2]));
+    assert_eq!(tree.index_at_row(area, &state, 2), Some(I![3]));
+}
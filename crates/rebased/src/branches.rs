@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use git2::Commit;
+use git2::Config;
+use git2::Oid;
+use git2::Reference;
+use git2::Repository;
+
+use crate::push;
+use crate::push::StackBranch;
+use crate::rangediff;
+use crate::rewrite;
+use crate::rewrite::Stop;
+use crate::worktree;
+
+/// Trailer that recorded the review branch of a commit before branches were
+/// remembered in `.git/rebased/branches`. It is still honoured.
+pub const TRAILER: &str = "Rebased-Branch";
+
+const DEFAULT_TEMPLATE: &str = "{user}/{stack}/{index}-{slug}";
+
+// MARK: Mapping
+
+fn mapping_path(repo: &Repository) -> PathBuf {
+    rewrite::shared_state_dir(repo).join("branches")
+}
+
+/// A commit and its patch id, remembered with the branch it is reviewed on.
+struct Entry {
+    commit: Oid,
+    patch_id: Oid,
+    name: String,
+}
+
+/// The review branch of every commit, kept as `<commit> <patch-id> <branch>`
+/// lines in `.git/rebased/branches`. A commit keeps its branch when the tool
+/// rewrites it, by following the rewrites it recorded, and when it is rebased
+/// elsewhere, by its patch id.
+pub struct Branches {
+    entries: Vec<Entry>,
+    rewritten: HashMap<Oid, Oid>,
+}
+
+impl Branches {
+    pub fn load(repo: &Repository) -> anyhow::Result<Self> {
+        let path = mapping_path(repo);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let mut fields = line.splitn(3, ' ');
+            let (Some(Ok(commit)), Some(Ok(patch_id)), Some(name)) = (
+                fields.next().map(Oid::from_str),
+                fields.next().map(Oid::from_str),
+                fields.next(),
+            ) else {
+                anyhow::bail!("malformed line in {}: {}", path.display(), line);
+            };
+            entries.push(Entry {
+                commit,
+                patch_id,
+                name: name.to_owned(),
+            });
+        }
+        Ok(Self {
+            entries,
+            rewritten: rewrite::load_rewritten(repo)?,
+        })
+    }
+
+    fn latest(&self, id: Oid) -> Oid {
+        self.rewritten.get(&id).copied().unwrap_or(id)
+    }
+
+    /// The branch of a commit, or of what it was before the tool rewrote it
+    /// or of a commit with the same patch.
+    pub fn branch_of(&self, repo: &Repository, commit: &Commit<'_>) -> Option<String> {
+        let latest = self.latest(commit.id());
+        let entry = self
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| self.latest(entry.commit) == latest)
+            .or_else(|| {
+                if self.entries.is_empty() {
+                    return None;
+                }
+                let patch_id = rangediff::patch_id(repo, commit).ok()?;
+                self.entries
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.patch_id == patch_id)
+            });
+        match entry {
+            Some(entry) => Some(entry.name.clone()),
+            None => {
+                rewrite::find_trailer(commit.message().unwrap_or(""), TRAILER).map(str::to_owned)
+            }
+        }
+    }
+
+    pub fn branches_of(&self, repo: &Repository, commits: &[Commit<'_>]) -> Vec<Option<String>> {
+        commits
+            .iter()
+            .map(|commit| self.branch_of(repo, commit))
+            .collect()
+    }
+
+    /// Remembers `name` for `commit`, forgetting whatever else had the name.
+    fn set(&mut self, repo: &Repository, commit: &Commit<'_>, name: &str) -> anyhow::Result<()> {
+        let latest = self.latest(commit.id());
+        let rewritten = &self.rewritten;
+        self.entries.retain(|entry| {
+            let entry_latest = rewritten.get(&entry.commit).unwrap_or(&entry.commit);
+            entry.name != name && *entry_latest != latest
+        });
+        self.entries.push(Entry {
+            commit: commit.id(),
+            patch_id: rangediff::patch_id(repo, commit)?,
+            name: name.to_owned(),
+        });
+        Ok(())
+    }
+
+    fn save(&self, repo: &Repository) -> anyhow::Result<()> {
+        let text = self
+            .entries
+            .iter()
+            .map(|entry| format!("{} {} {}\n", entry.commit, entry.patch_id, entry.name))
+            .collect::<String>();
+        let path = mapping_path(repo);
+        fs::create_dir_all(rewrite::shared_state_dir(repo))
+            .context("failed to create state directory")?;
+        fs::write(&path, text).with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+// MARK: Names
+
+fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for word in text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        if slug.len() + word.len() > 40 {
+            break;
+        }
+        if !slug.is_empty() {
+            slug.push('-');
+        }
+        slug.push_str(&word.to_ascii_lowercase());
+    }
+    slug
+}
+
+/// Names new branches from `rebased.branchTemplate`, in which `{user}`,
+/// `{stack}`, `{index}` and `{slug}` are replaced by the user, the name of the
+/// stack branch, the position of the commit and its summary.
+struct Template {
+    pattern: String,
+    user: String,
+    stack: String,
+}
+
+impl Template {
+    fn from_config(config: &Config, stack: &str) -> Self {
+        let user = config
+            .get_string("user.email")
+            .ok()
+            .and_then(|email| email.split('@').next().map(slug))
+            .or_else(|| config.get_string("user.name").ok().map(|name| slug(&name)))
+            .filter(|user| !user.is_empty())
+            .unwrap_or_else(|| "user".to_owned());
+        Self {
+            pattern: config
+                .get_string("rebased.branchTemplate")
+                .unwrap_or_else(|_| DEFAULT_TEMPLATE.to_owned()),
+            user,
+            stack: stack.to_owned(),
+        }
+    }
+
+    fn render(&self, index: usize, summary: &str) -> String {
+        self.pattern
+            .replace("{user}", &self.user)
+            .replace("{stack}", &self.stack)
+            .replace("{index}", &format!("{:02}", index))
+            .replace("{slug}", &slug(summary))
+    }
+}
+
+// MARK: Sync
+
+fn set_upstream(config: &mut Config, name: &str, remote: &str) -> anyhow::Result<()> {
+    let key = format!("branch.{}.remote", name);
+    if config.get_string(&key).is_err() {
+        config
+            .set_str(&key, remote)
+            .and_then(|_| {
+                config.set_str(
+                    &format!("branch.{}.merge", name),
+                    &format!("refs/heads/{}", name),
+                )
+            })
+            .with_context(|| format!("failed to set upstream of {}", name))?;
+    }
+    Ok(())
+}
+
+/// Creates or updates a branch for every commit in the stack between `base`
+/// and HEAD. Commits without a branch are given a name from the template,
+/// which is remembered without rewriting them. Branches that are new get
+/// `rebased.remote`, or `origin`, as their upstream.
+pub fn sync(repo: &Repository, base: &str) -> anyhow::Result<Vec<StackBranch>> {
+    if Stop::load(repo)?.is_some() {
+        anyhow::bail!("cannot update branches while an edit is in progress");
+    }
+
+    let head = repo.head().context("failed to resolve HEAD")?;
+    let (Some(head_name), Some(stack)) = (
+        head.name().filter(|_| head.is_branch()).map(str::to_owned),
+        head.shorthand().map(str::to_owned),
+    ) else {
+        anyhow::bail!("HEAD is detached, check out the stack branch first");
+    };
+    let head = head.peel_to_commit().context("HEAD is not a commit")?.id();
+    let merge_base = rewrite::merge_base_with(repo, base, head)?;
+    let commits = rewrite::commits_between(repo, merge_base, head)?
+        .into_iter()
+        .map(|id| repo.find_commit(id))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to find stack commits")?;
+
+    let mut config = repo.config().context("failed to open git config")?;
+    let template = Template::from_config(&config, &stack);
+    let mut mapping = Branches::load(repo)?;
+    let mut names = mapping.branches_of(repo, &commits);
+    let mut owners = HashMap::new();
+    for (commit, name) in commits.iter().zip(&names) {
+        if let Some(name) = name {
+            if let Some(other) = owners.insert(name.clone(), commit.id()) {
+                anyhow::bail!(
+                    "{} is the branch of both {:.8} and {:.8}",
+                    name,
+                    other,
+                    commit.id()
+                );
+            }
+        }
+    }
+    let mut taken = owners.into_keys().collect::<HashSet<_>>();
+    for (index, (commit, name)) in commits.iter().zip(&mut names).enumerate() {
+        if name.is_some() {
+            continue;
+        }
+
+        let summary = commit.summary().unwrap_or("");
+        let base_name = template.render(index + 1, summary);
+        let mut candidate = base_name.clone();
+        for n in 2.. {
+            if !taken.contains(&candidate) {
+                break;
+            }
+            candidate = format!("{}-{}", base_name, n);
+        }
+        if !Reference::is_valid_name(&format!("refs/heads/{}", candidate)) {
+            anyhow::bail!("template produced invalid branch name {:?}", candidate);
+        }
+
+        taken.insert(candidate.clone());
+        *name = Some(candidate);
+    }
+    if let Some(name) = names
+        .iter()
+        .flatten()
+        .find(|name| head_name == format!("refs/heads/{}", name))
+    {
+        anyhow::bail!("{} is the stack branch itself", name);
+    }
+    for name in names.iter().flatten() {
+        worktree::check_not_checked_out(repo, &format!("refs/heads/{}", name))?;
+    }
+
+    for (commit, name) in commits.iter().zip(&names) {
+        if let Some(name) = name {
+            mapping.set(repo, commit, name)?;
+        }
+    }
+    mapping.save(repo)?;
+
+    let remote = config
+        .get_string("rebased.remote")
+        .unwrap_or_else(|_| "origin".to_owned());
+    let mut branches = Vec::new();
+    for (name, target) in names
+        .into_iter()
+        .zip(commits.iter().map(Commit::id))
+        .filter_map(|(name, target)| Some((name?, target)))
+    {
+        let refname = format!("refs/heads/{}", name);
+        repo.reference(&refname, target, true, "rebased: update stack branch")
+            .with_context(|| format!("failed to update {}", name))?;
+        set_upstream(&mut config, &name, &remote)?;
+        branches.push(StackBranch { refname, target });
+    }
+    Ok(branches)
+}
+
+// MARK: Main
+
+pub struct Options {
+    pub base: String,
+    pub push: bool,
+}
+
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<bool> {
+    let branches = sync(repo, &options.base)?;
+    if branches.is_empty() {
+        println!("No commits in the stack");
+    }
+
+    let mut ok = true;
+    for branch in branches {
+        if !options.push {
+            println!("{}: {:.8}", branch.name(), branch.target);
+            continue;
+        }
+        match push::push_branch(repo, &branch) {
+            Ok(outcome) => println!("{}: {}", branch.name(), outcome),
+            Err(error) => {
+                ok = false;
+                println!("{}: {:#}", branch.name(), error);
+            }
+        }
+    }
+    Ok(ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    fn names(branches: &[StackBranch]) -> Vec<String> {
+        branches
+            .iter()
+            .map(|branch| branch.name().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_remembers_names_without_rewriting() {
+        let repo = TempRepo::new("branches-sync");
+        repo.commit("base.txt", "base\n", "base");
+        repo.switch("stack");
+        let a = repo.commit("a.txt", "a\n", "Add a");
+        let b = repo.commit("b.txt", "b\n", "Add b");
+
+        let branches = sync(&repo.repo, "main").unwrap();
+        assert_eq!(
+            names(&branches),
+            vec!["tester/stack/01-add-a", "tester/stack/02-add-b"]
+        );
+        assert_eq!(repo.repo.head().unwrap().target(), Some(b));
+        assert_eq!(branches[0].target, a);
+
+        // Rewording keeps the patch, so the commit keeps its branch even
+        // though its position and summary changed.
+        let old = repo.repo.find_commit(b).unwrap();
+        let signature = TempRepo::signature();
+        let reworded = repo
+            .repo
+            .commit(
+                None,
+                &signature,
+                &signature,
+                "Add b differently",
+                &old.tree().unwrap(),
+                &[&repo.repo.find_commit(a).unwrap()],
+            )
+            .unwrap();
+        repo.repo
+            .reference("refs/heads/stack", reworded, true, "test")
+            .unwrap();
+        let branches = sync(&repo.repo, "main").unwrap();
+        assert_eq!(
+            names(&branches),
+            vec!["tester/stack/01-add-a", "tester/stack/02-add-b"]
+        );
+        assert_eq!(branches[1].target, reworded);
+    }
+
+    #[test]
+    fn test_sync_rejects_duplicate_trailers() {
+        let repo = TempRepo::new("branches-duplicate");
+        repo.commit("base.txt", "base\n", "base");
+        repo.switch("stack");
+        let a = repo.commit("a.txt", "a\n", "Add a\n\nRebased-Branch: review\n");
+        let b = repo.commit("b.txt", "b\n", "Add b\n\nRebased-Branch: review\n");
+
+        let Err(error) = sync(&repo.repo, "main") else {
+            panic!("duplicate trailers were accepted");
+        };
+        assert_eq!(
+            error.to_string(),
+            format!("review is the branch of both {:.8} and {:.8}", a, b)
+        );
+        assert!(repo
+            .repo
+            .find_branch("review", git2::BranchType::Local)
+            .is_err());
+    }
+}
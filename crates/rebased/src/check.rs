@@ -0,0 +1,407 @@
+use anyhow::Context;
+use git2::Commit;
+use git2::Config;
+use git2::Delta;
+use git2::DiffOptions;
+use git2::Repository;
+
+use crate::rewrite;
+
+// MARK: Rules
+
+/// Limits read from the `rebased.check` config subsection, such as
+/// `rebased.check.maxFiles`. Setting a limit to 0 or a flag to false turns
+/// the rule off.
+#[derive(Debug, Clone, Copy)]
+pub struct Rules {
+    pub max_summary_length: usize,
+    pub require_body: bool,
+    pub forbid_wip: bool,
+    pub max_files: usize,
+    pub trailing_whitespace: bool,
+    pub max_binary_size: usize,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            max_summary_length: 72,
+            require_body: false,
+            forbid_wip: true,
+            max_files: 20,
+            trailing_whitespace: true,
+            max_binary_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Rules {
+    pub fn from_config(repo: &Repository) -> anyhow::Result<Self> {
+        let config = repo.config().context("failed to open git config")?;
+        let defaults = Self::default();
+        let size = |config: &Config, name: &str, default: usize| {
+            config
+                .get_i64(&format!("rebased.check.{}", name))
+                .map_or(default, |value| value.max(0) as usize)
+        };
+        let flag = |config: &Config, name: &str, default: bool| {
+            config
+                .get_bool(&format!("rebased.check.{}", name))
+                .unwrap_or(default)
+        };
+        Ok(Self {
+            max_summary_length: size(&config, "maxSummaryLength", defaults.max_summary_length),
+            require_body: flag(&config, "requireBody", defaults.require_body),
+            forbid_wip: flag(&config, "forbidWip", defaults.forbid_wip),
+            max_files: size(&config, "maxFiles", defaults.max_files),
+            trailing_whitespace: flag(&config, "trailingWhitespace", defaults.trailing_whitespace),
+            max_binary_size: size(&config, "maxBinarySize", defaults.max_binary_size),
+        })
+    }
+}
+
+// MARK: Check
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Warning {
+    fn new(rule: &'static str, message: String) -> Self {
+        Self { rule, message }
+    }
+}
+
+fn check_message(commit: &Commit<'_>, rules: &Rules, warnings: &mut Vec<Warning>) {
+    let message = commit.message().unwrap_or("");
+    let summary = commit.summary().unwrap_or("");
+    if rules.max_summary_length > 0 && summary.chars().count() > rules.max_summary_length {
+        warnings.push(Warning::new(
+            "summary",
+            format!(
+                "summary is {} characters, over {}",
+                summary.chars().count(),
+                rules.max_summary_length
+            ),
+        ));
+    }
+
+    let (body, _) = rewrite::split_trailers(message);
+    let has_body = body
+        .split_once('\n')
+        .is_some_and(|(_, body)| !body.trim().is_empty());
+    if rules.require_body && !has_body {
+        warnings.push(Warning::new("body", "message has no body".to_owned()));
+    }
+
+    let lowercase = summary.to_ascii_lowercase();
+    let leftover = ["fixup!", "squash!", "amend!"]
+        .into_iter()
+        .find(|prefix| lowercase.starts_with(prefix))
+        .or_else(|| {
+            lowercase
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .any(|word| word == "wip")
+                .then_some("WIP")
+        });
+    if let Some(leftover) = leftover.filter(|_| rules.forbid_wip) {
+        warnings.push(Warning::new(
+            "wip",
+            format!("{} commit left in the stack", leftover),
+        ));
+    }
+}
+
+/// Checks the message and changes of `commit` against `rules`.
+pub fn check(
+    repo: &Repository,
+    commit: &Commit<'_>,
+    rules: &Rules,
+) -> anyhow::Result<Vec<Warning>> {
+    let mut warnings = Vec::new();
+    check_message(commit, rules, &mut warnings);
+
+    let parent_tree = match commit.parent_count() {
+        0 => None,
+        _ => Some(
+            commit
+                .parent(0)
+                .and_then(|parent| parent.tree())
+                .context("failed to retrieve parent tree")?,
+        ),
+    };
+    let tree = commit.tree().context("failed to retrieve commit tree")?;
+    let diff = repo
+        .diff_tree_to_tree(
+            parent_tree.as_ref(),
+            Some(&tree),
+            Some(DiffOptions::new().context_lines(0)),
+        )
+        .with_context(|| format!("failed to diff commit {}", commit.id()))?;
+
+    if rules.max_files > 0 && diff.deltas().len() > rules.max_files {
+        warnings.push(Warning::new(
+            "files",
+            format!(
+                "touches {} files, over {}",
+                diff.deltas().len(),
+                rules.max_files
+            ),
+        ));
+    }
+
+    if rules.max_binary_size > 0 {
+        for delta in diff.deltas() {
+            if !matches!(delta.status(), Delta::Added | Delta::Modified) {
+                continue;
+            }
+            let Ok(blob) = repo.find_blob(delta.new_file().id()) else {
+                continue;
+            };
+            if blob.is_binary() && blob.size() > rules.max_binary_size {
+                warnings.push(Warning::new(
+                    "binary",
+                    format!(
+                        "adds {} KiB binary {}",
+                        blob.size() / 1024,
+                        delta
+                            .new_file()
+                            .path()
+                            .unwrap_or_else(|| "file".as_ref())
+                            .display()
+                    ),
+                ));
+            }
+        }
+    }
+
+    if rules.trailing_whitespace {
+        let mut paths = Vec::new();
+        diff.foreach(
+            &mut |_, _| true,
+            None,
+            None,
+            Some(&mut |delta, _, line| {
+                let content = line.content();
+                let content = content.strip_suffix(b"\n").unwrap_or(content);
+                let content = content.strip_suffix(b"\r").unwrap_or(content);
+                let is_trailing = content.ends_with(b" ") || content.ends_with(b"\t");
+                if line.origin() == '+' && is_trailing {
+                    if let Some(path) = delta.new_file().path() {
+                        let path = path.display().to_string();
+                        if !paths.contains(&path) {
+                            paths.push(path);
+                        }
+                    }
+                }
+                true
+            }),
+        )
+        .context("failed to walk diff")?;
+        if !paths.is_empty() {
+            warnings.push(Warning::new(
+                "whitespace",
+                format!("adds trailing whitespace in {}", paths.join(", ")),
+            ));
+        }
+    }
+
+    Ok(warnings)
+}
+
+// MARK: Main
+
+pub struct Options {
+    pub base: String,
+}
+
+/// Prints the warnings for every commit in the stack, returning whether there
+/// were none.
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<bool> {
+    let rules = Rules::from_config(repo)?;
+    let head = repo
+        .head()
+        .context("failed to resolve HEAD")?
+        .peel_to_commit()
+        .context("HEAD is not a commit")?
+        .id();
+    let merge_base = rewrite::merge_base_with(repo, &options.base, head)?;
+
+    let mut ok = true;
+    for id in rewrite::commits_between(repo, merge_base, head)? {
+        let commit = repo
+            .find_commit(id)
+            .with_context(|| format!("failed to find commit {}", id))?;
+        let warnings = check(repo, &commit, &rules)?;
+        if warnings.is_empty() {
+            continue;
+        }
+        ok = false;
+        println!("{:.8} {}", id, commit.summary().unwrap_or(""));
+        for warning in warnings {
+            println!("  {}: {}", warning.rule, warning.message);
+        }
+    }
+    Ok(ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::testing::TempRepo;
+
+    fn rules_of(repo: &TempRepo, id: git2::Oid, rules: &Rules) -> Vec<&'static str> {
+        let commit = repo.repo.find_commit(id).unwrap();
+        check(&repo.repo, &commit, rules)
+            .unwrap()
+            .into_iter()
+            .map(|warning| warning.rule)
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_commit() {
+        let repo = TempRepo::new("check-clean");
+        let id = repo.commit("a.txt", "a\n", "Add a\n\nBecause.\n");
+        assert!(rules_of(&repo, id, &Rules::default()).is_empty());
+    }
+
+    #[test]
+    fn test_summary_length() {
+        let repo = TempRepo::new("check-summary");
+        let id = repo.commit("a.txt", "a\n", &"a".repeat(73));
+        assert_eq!(rules_of(&repo, id, &Rules::default()), ["summary"]);
+        let rules = Rules {
+            max_summary_length: 0,
+            ..Rules::default()
+        };
+        assert!(rules_of(&repo, id, &rules).is_empty());
+    }
+
+    #[test]
+    fn test_body() {
+        let repo = TempRepo::new("check-body");
+        let rules = Rules {
+            require_body: true,
+            ..Rules::default()
+        };
+        let id = repo.commit("a.txt", "a\n", "Add a\n");
+        assert_eq!(rules_of(&repo, id, &rules), ["body"]);
+        // Trailers alone are not a body.
+        let id = repo.commit("b.txt", "b\n", "Add b\n\nChange-Id: I1\n");
+        assert_eq!(rules_of(&repo, id, &rules), ["body"]);
+        let id = repo.commit("c.txt", "c\n", "Add c\n\nBecause.\n");
+        assert!(rules_of(&repo, id, &rules).is_empty());
+    }
+
+    #[test]
+    fn test_wip() {
+        let repo = TempRepo::new("check-wip");
+        for (message, rules) in [
+            ("WIP: add a", &["wip"][..]),
+            ("add a (wip)", &["wip"]),
+            ("fixup! add a", &["wip"]),
+            ("squash! add a", &["wip"]),
+            ("Wipe the cache", &[]),
+        ] {
+            let id = repo.commit("a.txt", message, message);
+            assert_eq!(rules_of(&repo, id, &Rules::default()), rules, "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        let repo = TempRepo::new("check-files");
+        let rules = Rules {
+            max_files: 2,
+            ..Rules::default()
+        };
+        let mut index = repo.repo.index().unwrap();
+        for path in ["a.txt", "b.txt"] {
+            fs::write(repo.path().join(path), path).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let id = repo.commit("c.txt", "c\n", "Add files");
+        assert_eq!(rules_of(&repo, id, &rules), ["files"]);
+        let id = repo.commit("d.txt", "d\n", "Add d");
+        assert!(rules_of(&repo, id, &rules).is_empty());
+    }
+
+    #[test]
+    fn test_trailing_whitespace() {
+        let repo = TempRepo::new("check-whitespace");
+        let id = repo.commit("a.txt", "a \nb\n", "Add a");
+        assert_eq!(rules_of(&repo, id, &Rules::default()), ["whitespace"]);
+        // Only added lines count.
+        let id = repo.commit("a.txt", "a \nb\nc\n", "Add c");
+        assert!(rules_of(&repo, id, &Rules::default()).is_empty());
+        let id = repo.commit("b.txt", "b\t\r\n", "Add b");
+        assert_eq!(rules_of(&repo, id, &Rules::default()), ["whitespace"]);
+    }
+
+    #[test]
+    fn test_binary() {
+        let repo = TempRepo::new("check-binary");
+        let rules = Rules {
+            max_binary_size: 16,
+            ..Rules::default()
+        };
+        let id = repo.commit("small.bin", "\0\0", "Add small");
+        assert!(rules_of(&repo, id, &rules).is_empty());
+        let id = repo.commit("large.bin", &"\0".repeat(32), "Add large");
+        let commit = repo.repo.find_commit(id).unwrap();
+        let warnings = check(&repo.repo, &commit, &rules).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].rule, "binary");
+        assert_eq!(warnings[0].message, "adds 0 KiB binary large.bin");
+        // Text is not binary, however large.
+        let id = repo.commit("large.txt", &"a".repeat(32), "Add text");
+        assert!(rules_of(&repo, id, &rules).is_empty());
+    }
+
+    #[test]
+    fn test_from_config() {
+        let repo = TempRepo::new("check-config");
+        let mut config = repo.repo.config().unwrap();
+        config
+            .set_i64("rebased.check.maxSummaryLength", 10)
+            .unwrap();
+        config.set_bool("rebased.check.requireBody", true).unwrap();
+        config.set_bool("rebased.check.forbidWip", false).unwrap();
+        config.set_i64("rebased.check.maxFiles", -1).unwrap();
+        let rules = Rules::from_config(&repo.repo).unwrap();
+        assert_eq!(rules.max_summary_length, 10);
+        assert!(rules.require_body);
+        assert!(!rules.forbid_wip);
+        assert_eq!(rules.max_files, 0);
+        assert!(rules.trailing_whitespace);
+        assert_eq!(rules.max_binary_size, Rules::default().max_binary_size);
+    }
+
+    #[test]
+    fn test_main() {
+        let repo = TempRepo::new("check-main");
+        repo.commit("base.txt", "base\n", "base");
+        repo.switch("stack");
+        repo.commit("a.txt", "a\n", "Add a");
+        let options = || Options {
+            base: "main".to_owned(),
+        };
+        assert!(main(&repo.repo, options()).unwrap());
+
+        repo.commit("b.txt", "b\n", "WIP");
+        assert!(!main(&repo.repo, options()).unwrap());
+        repo.repo
+            .config()
+            .unwrap()
+            .set_bool("rebased.check.forbidWip", false)
+            .unwrap();
+        assert!(main(&repo.repo, options()).unwrap());
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::Context;
+use git2::Commit;
+use git2::Oid;
+use git2::Repository;
+
+use crate::branches::Branches;
+use crate::rewrite;
+
+// MARK: Render
+
+/// The message of `commit` without its summary and trailers.
+pub fn body(commit: &Commit<'_>) -> String {
+    let message = commit.message().unwrap_or("");
+    let (body, _) = rewrite::split_trailers(message);
+    body.split_once('\n')
+        .map(|(_, body)| body.trim().to_owned())
+        .unwrap_or_default()
+}
+
+/// Escapes the characters that Markdown would take for formatting, so that a
+/// summary like `Fix *ptr handling` is shown as written. A leading `#` would
+/// make a heading, but elsewhere it is left alone so issue references still
+/// link.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for (index, c) in text.chars().enumerate() {
+        if "\\`*_[]<>~|".contains(c) || (index == 0 && c == '#') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Renders the stack as a Markdown list from the bottom up, with the branch
+/// of each commit and `current` in bold.
+pub fn render(
+    commits: &[Commit<'_>],
+    branches: &[Option<String>],
+    current: Option<Oid>,
+    bodies: bool,
+) -> String {
+    let mut markdown = String::new();
+    for (index, (commit, branch)) in commits.iter().zip(branches).enumerate() {
+        let summary = escape(commit.summary().unwrap_or(""));
+        let reference = branch
+            .clone()
+            .unwrap_or_else(|| format!("{:.8}", commit.id()));
+        let item = format!("{} (`{}`)", summary, reference);
+        if current == Some(commit.id()) {
+            markdown.push_str(&format!("{}. **{}** ← this one\n", index + 1, item));
+        } else {
+            markdown.push_str(&format!("{}. {}\n", index + 1, item));
+        }
+
+        let body = body(commit);
+        if bodies && !body.is_empty() {
+            // Continuation lines line up with the text after the marker, which
+            // is wider from the tenth item on.
+            let indent = " ".repeat((index + 1).to_string().len() + 2);
+            markdown.push('\n');
+            for line in body.lines() {
+                if line.is_empty() {
+                    markdown.push('\n');
+                } else {
+                    markdown.push_str(&format!("{}{}\n", indent, line));
+                }
+            }
+            markdown.push('\n');
+        }
+    }
+    markdown
+}
+
+// MARK: Main
+
+pub struct Options {
+    pub base: String,
+    pub current: Option<String>,
+    pub bodies: bool,
+}
+
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<()> {
+    let head = repo
+        .head()
+        .context("failed to resolve HEAD")?
+        .peel_to_commit()
+        .context("HEAD is not a commit")?
+        .id();
+    let current = match &options.current {
+        Some(current) => repo
+            .revparse_single(current)
+            .and_then(|object| object.peel_to_commit())
+            .with_context(|| format!("failed to resolve {}", current))?
+            .id(),
+        None => head,
+    };
+
+    let merge_base = rewrite::merge_base_with(repo, &options.base, head)?;
+    let commits = rewrite::commits_between(repo, merge_base, head)?
+        .into_iter()
+        .map(|id| repo.find_commit(id))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to find stack commits")?;
+    let branches = Branches::load(repo)?.branches_of(repo, &commits);
+    print!(
+        "{}",
+        render(&commits, &branches, Some(current), options.bodies)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("Fix *ptr in `foo_bar`"),
+            "Fix \\*ptr in \\`foo\\_bar\\`"
+        );
+        assert_eq!(escape("#12 and #13"), "\\#12 and #13");
+        assert_eq!(escape("Add Vec<T> [draft]"), "Add Vec\\<T\\> \\[draft\\]");
+    }
+
+    #[test]
+    fn test_render_indents_bodies_past_the_marker() {
+        let repo = TempRepo::new("describe-render");
+        let commits = (1..=10)
+            .map(|n| {
+                let id = repo.commit(
+                    "file.txt",
+                    &n.to_string(),
+                    &format!("Step {}\n\nBody {}", n, n),
+                );
+                repo.repo.find_commit(id).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut branches = vec![None; 10];
+        branches[0] = Some("review/first".to_owned());
+
+        let markdown = render(&commits, &branches, Some(commits[9].id()), true);
+        let lines = markdown.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "1. Step 1 (`review/first`)");
+        assert_eq!(lines[2], "   Body 1");
+        assert_eq!(
+            lines[lines.len() - 4],
+            format!("10. **Step 10 (`{:.8}`)** ← this one", commits[9].id())
+        );
+        assert_eq!(lines[lines.len() - 2], "    Body 10");
+    }
+}
@@ -0,0 +1,360 @@
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use git2::Repository;
+
+use crate::branches;
+use crate::describe;
+use crate::rewrite;
+use crate::rewrite::shared_state_dir;
+
+// MARK: Review
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReviewStatus {
+    Open,
+    Approved,
+    Merged,
+    Closed,
+}
+
+impl ReviewStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            ReviewStatus::Open => "open",
+            ReviewStatus::Approved => "approved",
+            ReviewStatus::Merged => "merged",
+            ReviewStatus::Closed => "closed",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [
+            ReviewStatus::Open,
+            ReviewStatus::Approved,
+            ReviewStatus::Merged,
+            ReviewStatus::Closed,
+        ]
+        .into_iter()
+        .find(|status| status.name() == name)
+    }
+}
+
+impl fmt::Display for ReviewStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A review of one branch, such as a pull or merge request.
+#[derive(Debug, Clone)]
+pub struct Review {
+    pub id: u64,
+    pub branch: String,
+    pub base: String,
+    pub title: String,
+    pub body: String,
+    pub status: ReviewStatus,
+}
+
+// MARK: Forge
+
+/// Where reviews live. Implementations map branches to reviews, so the core
+/// only ever deals in branch names.
+pub trait Forge {
+    /// Opens a review of `branch` against `base`, or updates the existing one.
+    fn submit(
+        &mut self,
+        branch: &str,
+        base: &str,
+        title: &str,
+        body: &str,
+    ) -> anyhow::Result<Review>;
+
+    fn status(&self, branch: &str) -> anyhow::Result<Option<ReviewStatus>>;
+
+    /// The status of the review of each of `branches`, fetched together where
+    /// the forge allows it.
+    fn statuses(&self, branches: &[&str]) -> anyhow::Result<Vec<Option<ReviewStatus>>> {
+        branches.iter().map(|branch| self.status(branch)).collect()
+    }
+
+    /// Attaches the Markdown summary of the stack to the review of `branch`.
+    fn post_summary(&mut self, branch: &str, summary: &str) -> anyhow::Result<()>;
+}
+
+/// Returns the forge selected by `rebased.forge`, if any.
+pub fn from_config(repo: &Repository) -> anyhow::Result<Option<Box<dyn Forge>>> {
+    let config = repo.config().context("failed to open git config")?;
+    match config.get_string("rebased.forge").ok().as_deref() {
+        None => Ok(None),
+        Some("file") => {
+            let path = config
+                .get_path("rebased.forgePath")
+                .unwrap_or_else(|_| shared_state_dir(repo).join("forge"));
+            Ok(Some(Box::new(FileForge::new(path))))
+        }
+        Some(other) => anyhow::bail!("unsupported rebased.forge {}", other),
+    }
+}
+
+// MARK: File
+
+/// A fake forge keeping each review in a file of its own, named after its id.
+/// A review file holds `key: value` headers followed by a blank line and the
+/// body; edit its `status` header to approve, merge or close it. Summaries are
+/// written next to it with a `.summary` extension.
+pub struct FileForge {
+    path: PathBuf,
+}
+
+impl FileForge {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn reviews(&self) -> anyhow::Result<Vec<Review>> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error).context("failed to read forge directory"),
+        };
+
+        let mut reviews = Vec::new();
+        for entry in entries {
+            let entry = entry.context("failed to read forge directory")?;
+            let Some(id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            else {
+                continue;
+            };
+            let text = fs::read_to_string(entry.path())
+                .with_context(|| format!("failed to read review {}", id))?;
+            reviews.push(Self::parse(id, &text).with_context(|| format!("invalid review {}", id))?);
+        }
+        reviews.sort_by_key(|review| review.id);
+        Ok(reviews)
+    }
+
+    fn parse(id: u64, text: &str) -> anyhow::Result<Review> {
+        let (headers, body) = text.split_once("\n\n").unwrap_or((text, ""));
+        let header = |key: &str| {
+            headers
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+                .map(|value| value.trim().to_owned())
+                .ok_or_else(|| anyhow::format_err!("missing {}", key))
+        };
+        let status = header("status")?;
+        Ok(Review {
+            id,
+            branch: header("branch")?,
+            base: header("base")?,
+            title: header("title")?,
+            body: body.to_owned(),
+            status: ReviewStatus::parse(&status)
+                .ok_or_else(|| anyhow::format_err!("unknown status {}", status))?,
+        })
+    }
+
+    fn find(&self, branch: &str) -> anyhow::Result<Option<Review>> {
+        Ok(self
+            .reviews()?
+            .into_iter()
+            .rev()
+            .find(|review| review.branch == branch))
+    }
+
+    fn save(&self, review: &Review) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.path).context("failed to create forge directory")?;
+        let text = format!(
+            "branch: {}\nbase: {}\nstatus: {}\ntitle: {}\n\n{}",
+            review.branch, review.base, review.status, review.title, review.body
+        );
+        fs::write(self.path.join(review.id.to_string()), text)
+            .with_context(|| format!("failed to write review {}", review.id))
+    }
+}
+
+impl Forge for FileForge {
+    fn submit(
+        &mut self,
+        branch: &str,
+        base: &str,
+        title: &str,
+        body: &str,
+    ) -> anyhow::Result<Review> {
+        let review = match self.find(branch)? {
+            // Merged and closed reviews are done with; the branch gets a new one.
+            Some(review)
+                if matches!(review.status, ReviewStatus::Open | ReviewStatus::Approved) =>
+            {
+                Review {
+                    base: base.to_owned(),
+                    title: title.to_owned(),
+                    body: body.to_owned(),
+                    ..review
+                }
+            }
+            _ => Review {
+                id: self.reviews()?.last().map_or(1, |review| review.id + 1),
+                branch: branch.to_owned(),
+                base: base.to_owned(),
+                title: title.to_owned(),
+                body: body.to_owned(),
+                status: ReviewStatus::Open,
+            },
+        };
+        self.save(&review)?;
+        Ok(review)
+    }
+
+    fn status(&self, branch: &str) -> anyhow::Result<Option<ReviewStatus>> {
+        Ok(self.find(branch)?.map(|review| review.status))
+    }
+
+    fn statuses(&self, branches: &[&str]) -> anyhow::Result<Vec<Option<ReviewStatus>>> {
+        let reviews = self.reviews()?;
+        Ok(branches
+            .iter()
+            .map(|branch| {
+                reviews
+                    .iter()
+                    .rev()
+                    .find(|review| review.branch == *branch)
+                    .map(|review| review.status)
+            })
+            .collect())
+    }
+
+    fn post_summary(&mut self, branch: &str, summary: &str) -> anyhow::Result<()> {
+        let review = self
+            .find(branch)?
+            .ok_or_else(|| anyhow::format_err!("no review for {}", branch))?;
+        fs::write(self.path.join(format!("{}.summary", review.id)), summary)
+            .with_context(|| format!("failed to write summary of review {}", review.id))
+    }
+}
+
+// MARK: Main
+
+pub struct Options {
+    pub base: String,
+}
+
+/// Opens or updates a review for every commit with a branch, each against the
+/// branch of the commit below it, and posts the stack summary to all of them.
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<()> {
+    let Some(mut forge) = from_config(repo)? else {
+        anyhow::bail!("no forge configured, set rebased.forge");
+    };
+
+    let head = repo
+        .head()
+        .context("failed to resolve HEAD")?
+        .peel_to_commit()
+        .context("HEAD is not a commit")?
+        .id();
+    let merge_base = rewrite::merge_base_with(repo, &options.base, head)?;
+    let commits = rewrite::commits_between(repo, merge_base, head)?
+        .into_iter()
+        .map(|id| repo.find_commit(id))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to find stack commits")?;
+
+    // Reviews target branches on the remote, so drop the remote from the base.
+    let mut base = match repo.find_branch(&options.base, git2::BranchType::Remote) {
+        Ok(_) => options
+            .base
+            .split_once('/')
+            .map_or(options.base.clone(), |(_, name)| name.to_owned()),
+        Err(_) => options.base.clone(),
+    };
+    let names = branches::Branches::load(repo)?.branches_of(repo, &commits);
+    let mut submitted = Vec::new();
+    for (commit, branch) in commits.iter().zip(&names) {
+        let Some(branch) = branch.clone() else {
+            println!("{:.8}: no branch, run rebased branches first", commit.id());
+            continue;
+        };
+        let body = describe::body(commit);
+        let review = forge.submit(&branch, &base, commit.summary().unwrap_or(""), &body)?;
+        println!("{}: review {} ({})", branch, review.id, review.status);
+        submitted.push((commit.id(), branch.clone()));
+        base = branch;
+    }
+
+    for (id, branch) in submitted {
+        forge.post_summary(
+            &branch,
+            &describe::render(&commits, &names, Some(id), false),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_file_forge_round_trip() {
+        let dir = TempDir::new("forge");
+        let mut forge = FileForge::new(dir.path().join("forge"));
+        assert_eq!(forge.status("one").unwrap(), None);
+
+        let review = forge.submit("one", "main", "First", "Body\n").unwrap();
+        assert_eq!((review.id, review.status), (1, ReviewStatus::Open));
+        let review = forge.submit("two", "one", "Second", "").unwrap();
+        assert_eq!(review.id, 2);
+
+        // Submitting again updates the open review.
+        let review = forge
+            .submit("one", "main", "First, again", "New body\n")
+            .unwrap();
+        assert_eq!(review.id, 1);
+        let text = fs::read_to_string(dir.path().join("forge/1")).unwrap();
+        assert_eq!(
+            text,
+            "branch: one\nbase: main\nstatus: open\ntitle: First, again\n\nNew body\n"
+        );
+
+        // A merged review is done with, so the branch gets a new one.
+        fs::write(
+            dir.path().join("forge/1"),
+            text.replace("status: open", "status: merged"),
+        )
+        .unwrap();
+        assert_eq!(forge.status("one").unwrap(), Some(ReviewStatus::Merged));
+        let review = forge.submit("one", "main", "First", "").unwrap();
+        assert_eq!((review.id, review.status), (3, ReviewStatus::Open));
+        assert_eq!(
+            forge.statuses(&["one", "two", "three"]).unwrap(),
+            vec![Some(ReviewStatus::Open), Some(ReviewStatus::Open), None]
+        );
+
+        forge.post_summary("one", "1. First\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("forge/3.summary")).unwrap(),
+            "1. First\n"
+        );
+        assert!(forge.post_summary("three", "").is_err());
+    }
+
+    #[test]
+    fn test_file_forge_rejects_invalid_reviews() {
+        let dir = TempDir::new("forge-invalid");
+        fs::write(dir.path().join("1"), "branch: one\nstatus: open\n\n").unwrap();
+        // Files that are not named after an id are ignored.
+        fs::write(dir.path().join("notes"), "").unwrap();
+        let forge = FileForge::new(dir.path().to_owned());
+        let error = forge.status("one").unwrap_err();
+        assert_eq!(format!("{:#}", error), "invalid review 1: missing base");
+    }
+}
@@ -0,0 +1,806 @@
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use crossterm::event::KeyCode;
+use crossterm::event::KeyEvent;
+use crossterm::event::KeyModifiers;
+use git2::Repository;
+
+// MARK: Actions
+
+/// Everything a key can be bound to, named as in the config.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    SelectUp,
+    SelectDown,
+    SelectFirst,
+    SelectLast,
+    Toggle,
+    ShowDelta,
+    PageUp,
+    PageDown,
+    NextHunk,
+    PreviousHunk,
+    NextFile,
+    PreviousFile,
+    Focus,
+    Edit,
+    Insert,
+    MoveUp,
+    MoveDown,
+    Squash,
+    Drop,
+    Move,
+    Describe,
+    RangeDiff,
+    SwitchWorktree,
+    Worktrees,
+    Search,
+    SearchNext,
+    SearchPrevious,
+    Filter,
+    Push,
+    PushBranches,
+    Confirm,
+    Cancel,
+    Resume,
+    Abort,
+    Help,
+    Quit,
+}
+
+/// Where an action applies, to group actions in the help.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Group {
+    Stack,
+    Preview,
+    Editing,
+    General,
+}
+
+impl Group {
+    pub const ALL: [Group; 4] = [Group::Stack, Group::Preview, Group::Editing, Group::General];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Group::Stack => "Stack",
+            Group::Preview => "Preview",
+            Group::Editing => "While editing a commit",
+            Group::General => "General",
+        }
+    }
+}
+
+impl Action {
+    pub const ALL: [Action; 36] = [
+        Action::SelectUp,
+        Action::SelectDown,
+        Action::SelectFirst,
+        Action::SelectLast,
+        Action::Toggle,
+        Action::ShowDelta,
+        Action::PageUp,
+        Action::PageDown,
+        Action::NextHunk,
+        Action::PreviousHunk,
+        Action::NextFile,
+        Action::PreviousFile,
+        Action::Focus,
+        Action::Edit,
+        Action::Insert,
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::Squash,
+        Action::Drop,
+        Action::Move,
+        Action::Describe,
+        Action::RangeDiff,
+        Action::SwitchWorktree,
+        Action::Worktrees,
+        Action::Search,
+        Action::SearchNext,
+        Action::SearchPrevious,
+        Action::Filter,
+        Action::Push,
+        Action::PushBranches,
+        Action::Confirm,
+        Action::Cancel,
+        Action::Resume,
+        Action::Abort,
+        Action::Help,
+        Action::Quit,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::SelectUp => "select-up",
+            Action::SelectDown => "select-down",
+            Action::SelectFirst => "select-first",
+            Action::SelectLast => "select-last",
+            Action::Toggle => "toggle",
+            Action::ShowDelta => "show-delta",
+            Action::PageUp => "page-up",
+            Action::PageDown => "page-down",
+            Action::NextHunk => "next-hunk",
+            Action::PreviousHunk => "previous-hunk",
+            Action::NextFile => "next-file",
+            Action::PreviousFile => "previous-file",
+            Action::Focus => "focus",
+            Action::Edit => "edit",
+            Action::Insert => "insert",
+            Action::MoveUp => "move-up",
+            Action::MoveDown => "move-down",
+            Action::Squash => "squash",
+            Action::Drop => "drop",
+            Action::Move => "move",
+            Action::Describe => "describe",
+            Action::RangeDiff => "range-diff",
+            Action::SwitchWorktree => "switch-worktree",
+            Action::Worktrees => "worktrees",
+            Action::Search => "search",
+            Action::SearchNext => "search-next",
+            Action::SearchPrevious => "search-previous",
+            Action::Filter => "filter",
+            Action::Push => "push",
+            Action::PushBranches => "push-branches",
+            Action::Confirm => "confirm",
+            Action::Cancel => "cancel",
+            Action::Resume => "resume",
+            Action::Abort => "abort",
+            Action::Help => "help",
+            Action::Quit => "quit",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::SelectUp => "Select the previous commit or file, or scroll up",
+            Action::SelectDown => "Select the next commit or file, or scroll down",
+            Action::SelectFirst => "Select the first commit, or scroll to the top",
+            Action::SelectLast => "Select the last commit, or scroll to the bottom",
+            Action::Toggle => "Expand or collapse a commit, or show the selected file",
+            Action::ShowDelta => "Show the selected file",
+            Action::PageUp => "Scroll the preview up a page",
+            Action::PageDown => "Scroll the preview down a page",
+            Action::NextHunk => "Scroll the preview to the next hunk",
+            Action::PreviousHunk => "Scroll the preview to the previous hunk",
+            Action::NextFile => "Scroll the preview to the next file",
+            Action::PreviousFile => "Scroll the preview to the previous file",
+            Action::Focus => "Switch focus between the commits and the preview",
+            Action::Edit => "Stop at the commit to amend it in a shell",
+            Action::Insert => "Insert a commit with a message after the selected one",
+            Action::MoveUp => "Plan to move the commit up",
+            Action::MoveDown => "Plan to move the commit down",
+            Action::Squash => "Plan to squash the commit into the one before it",
+            Action::Drop => "Plan to drop the commit",
+            Action::Move => "Mark the commit, or move the marked commit after it",
+            Action::Describe => "Show the stack as Markdown for a review",
+            Action::RangeDiff => "Compare the stack with its previous version",
+            Action::SwitchWorktree => "Switch to the next worktree",
+            Action::Worktrees => "Show the worktrees and their branches",
+            Action::Search => "Search messages and paths, or with Tab also diffs",
+            Action::SearchNext => "Select the next match",
+            Action::SearchPrevious => "Select the previous match",
+            Action::Filter => "Show only commits matching path:, author: or a message regex",
+            Action::Push => "Push the stack branch",
+            Action::PushBranches => "Name and push a branch for every commit",
+            Action::Confirm => "Apply the planned rewrite",
+            Action::Cancel => "Discard the planned rewrite, or clear the search or filter",
+            Action::Resume => "Amend the commit and continue",
+            Action::Abort => "Leave the commit as it was and stop editing",
+            Action::Help => "Show this help",
+            Action::Quit => "Quit",
+        }
+    }
+
+    pub fn group(self) -> Group {
+        match self {
+            Action::ShowDelta
+            | Action::PageUp
+            | Action::PageDown
+            | Action::NextHunk
+            | Action::PreviousHunk
+            | Action::NextFile
+            | Action::PreviousFile
+            | Action::Describe
+            | Action::RangeDiff
+            | Action::Worktrees => Group::Preview,
+            Action::Resume | Action::Abort => Group::Editing,
+            Action::Focus | Action::SwitchWorktree | Action::Help | Action::Quit => Group::General,
+            _ => Group::Stack,
+        }
+    }
+
+    /// A word or two for the footer.
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::SelectUp => "up",
+            Action::SelectDown => "down",
+            Action::SelectFirst => "first",
+            Action::SelectLast => "last",
+            Action::Toggle => "expand",
+            Action::ShowDelta => "show",
+            Action::PageUp => "page up",
+            Action::PageDown => "page down",
+            Action::NextHunk => "next hunk",
+            Action::PreviousHunk => "previous hunk",
+            Action::NextFile => "next file",
+            Action::PreviousFile => "previous file",
+            Action::MoveUp => "move up",
+            Action::MoveDown => "move down",
+            Action::RangeDiff => "range-diff",
+            Action::SwitchWorktree => "switch",
+            Action::PushBranches => "branches",
+            Action::SearchNext => "next match",
+            Action::SearchPrevious => "previous match",
+            Action::Confirm => "apply",
+            Action::Cancel => "discard",
+            action => action.name(),
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Action::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// MARK: Chords
+
+/// One key press with its modifiers, written like `q`, `C-c`, `M-<` or
+/// `space`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Chord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+const NAMES: [(&str, KeyCode); 15] = [
+    ("space", KeyCode::Char(' ')),
+    ("enter", KeyCode::Enter),
+    ("esc", KeyCode::Esc),
+    ("tab", KeyCode::Tab),
+    ("backtab", KeyCode::BackTab),
+    ("backspace", KeyCode::Backspace),
+    ("delete", KeyCode::Delete),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+];
+
+impl Chord {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        // Shift is already in the character, and terminals disagree on
+        // whether to report it as well.
+        let modifiers = match code {
+            KeyCode::Char(_) => modifiers - KeyModifiers::SHIFT,
+            _ => modifiers,
+        };
+        Self { code, modifiers }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = text;
+        loop {
+            let modifier = match rest.get(..2) {
+                Some("C-") => KeyModifiers::CONTROL,
+                Some("M-") => KeyModifiers::ALT,
+                Some("S-") => KeyModifiers::SHIFT,
+                _ => break,
+            };
+            if rest.len() == 2 {
+                break;
+            }
+            modifiers |= modifier;
+            rest = &rest[2..];
+        }
+
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => NAMES
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(rest))
+                .map(|&(_, code)| code)?,
+        };
+        Some(Self::new(code, modifiers))
+    }
+}
+
+impl From<&KeyEvent> for Chord {
+    fn from(key: &KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, prefix) in [
+            (KeyModifiers::CONTROL, "C-"),
+            (KeyModifiers::ALT, "M-"),
+            (KeyModifiers::SHIFT, "S-"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(prefix)?;
+            }
+        }
+        match NAMES.iter().find(|(_, code)| *code == self.code) {
+            Some((name, _)) => f.write_str(name),
+            None => match self.code {
+                KeyCode::Char(c) => write!(f, "{}", c),
+                code => write!(f, "{}", code),
+            },
+        }
+    }
+}
+
+/// Parses a key sequence, with chords separated by spaces like `C-x C-c`.
+/// Plain characters may also be run together, so `gg` is `g g`.
+fn parse_sequence(text: &str) -> anyhow::Result<Vec<Chord>> {
+    let mut sequence = Vec::new();
+    for token in text.split_whitespace() {
+        match Chord::parse(token) {
+            Some(chord) => sequence.push(chord),
+            None if !token.contains('-') => sequence.extend(
+                token
+                    .chars()
+                    .map(|c| Chord::new(KeyCode::Char(c), KeyModifiers::NONE)),
+            ),
+            None => anyhow::bail!("invalid key {}", token),
+        }
+    }
+    if sequence.is_empty() {
+        anyhow::bail!("empty key sequence");
+    }
+    Ok(sequence)
+}
+
+fn format_sequence(sequence: &[Chord]) -> String {
+    sequence
+        .iter()
+        .map(Chord::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// MARK: Keymap
+
+/// Maps key sequences to actions. Keys are fed one at a time; a key that
+/// starts a longer sequence is held until the sequence is complete or broken,
+/// or until the next key comes after `TIMEOUT`.
+pub struct Keymap {
+    bindings: Vec<(Vec<Chord>, Action)>,
+    pending: Vec<Chord>,
+    last_key: Option<Instant>,
+}
+
+/// How long a sequence waits for its next key, as `timeoutlen` in vim.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+const DEFAULT: &[(&str, Action)] = &[
+    ("up", Action::SelectUp),
+    ("down", Action::SelectDown),
+    ("home", Action::SelectFirst),
+    ("end", Action::SelectLast),
+    ("space", Action::Toggle),
+    ("right", Action::ShowDelta),
+    ("pageup", Action::PageUp),
+    ("pagedown", Action::PageDown),
+    ("]", Action::NextHunk),
+    ("[", Action::PreviousHunk),
+    ("}", Action::NextFile),
+    ("{", Action::PreviousFile),
+    ("tab", Action::Focus),
+    ("e", Action::Edit),
+    ("i", Action::Insert),
+    ("K", Action::MoveUp),
+    ("J", Action::MoveDown),
+    ("s", Action::Squash),
+    ("d", Action::Drop),
+    ("m", Action::Move),
+    ("D", Action::Describe),
+    ("R", Action::RangeDiff),
+    ("w", Action::SwitchWorktree),
+    ("W", Action::Worktrees),
+    ("/", Action::Search),
+    ("n", Action::SearchNext),
+    ("N", Action::SearchPrevious),
+    ("f", Action::Filter),
+    ("P", Action::Push),
+    ("B", Action::PushBranches),
+    ("enter", Action::Confirm),
+    ("esc", Action::Cancel),
+    ("c", Action::Resume),
+    ("a", Action::Abort),
+    ("?", Action::Help),
+    ("q", Action::Quit),
+    ("C-c", Action::Quit),
+    ("C-q", Action::Quit),
+    ("C-x", Action::Quit),
+];
+
+const VIM: &[(&str, Action)] = &[
+    ("k", Action::SelectUp),
+    ("j", Action::SelectDown),
+    ("gg", Action::SelectFirst),
+    ("G", Action::SelectLast),
+    ("l", Action::ShowDelta),
+    ("C-f", Action::PageDown),
+    ("C-b", Action::PageUp),
+];
+
+const EMACS: &[(&str, Action)] = &[
+    ("C-p", Action::SelectUp),
+    ("C-n", Action::SelectDown),
+    ("M-<", Action::SelectFirst),
+    ("M->", Action::SelectLast),
+    ("C-f", Action::ShowDelta),
+    ("C-v", Action::PageDown),
+    ("M-v", Action::PageUp),
+    ("C-g", Action::Cancel),
+    ("C-s", Action::Search),
+    ("C-r", Action::SearchPrevious),
+    ("C-x C-c", Action::Quit),
+];
+
+impl Keymap {
+    /// The default bindings, plus those of the `vim` or `emacs` preset.
+    pub fn preset(name: &str) -> anyhow::Result<Self> {
+        let extra = match name {
+            "default" => &[][..],
+            "vim" => VIM,
+            "emacs" => EMACS,
+            other => anyhow::bail!("unknown keymap preset {}", other),
+        };
+        let mut keymap = Self {
+            bindings: Vec::new(),
+            pending: Vec::new(),
+            last_key: None,
+        };
+        for &(keys, action) in DEFAULT.iter().chain(extra) {
+            keymap.bind(parse_sequence(keys)?, action);
+        }
+        Ok(keymap)
+    }
+
+    /// Adds a binding, replacing whatever the sequence was bound to before
+    /// along with bindings that it would shadow or be shadowed by.
+    fn bind(&mut self, sequence: Vec<Chord>, action: Action) {
+        self.bindings
+            .retain(|(keys, _)| !keys.starts_with(&sequence) && !sequence.starts_with(keys));
+        self.bindings.push((sequence, action));
+    }
+
+    /// Replaces all the bindings of `action`.
+    fn rebind(&mut self, action: Action, sequences: &[String]) -> anyhow::Result<()> {
+        self.bindings.retain(|(_, bound)| *bound != action);
+        for sequence in sequences
+            .iter()
+            .filter(|sequence| !sequence.trim().is_empty())
+        {
+            let sequence = parse_sequence(sequence)
+                .with_context(|| format!("invalid binding for {}", action))?;
+            self.bind(sequence, action);
+        }
+        Ok(())
+    }
+
+    /// Loads the keymap from `~/.config/rebased/config.toml` and then git
+    /// config, which takes precedence. Both pick a preset and rebind actions,
+    /// either in a `[keys]` table:
+    ///
+    /// ```toml
+    /// [keys]
+    /// preset = "vim"
+    /// select-first = ["gg", "home"]
+    /// ```
+    ///
+    /// or with `rebased.keymap` and `rebased.keys.<action>`, which may be
+    /// given more than once. An empty binding unbinds the action.
+    pub fn from_config(repo: &Repository) -> anyhow::Result<Self> {
+        let mut settings = Settings {
+            preset: "default".to_owned(),
+            overrides: Vec::new(),
+        };
+        if let Some(path) = config_path() {
+            match fs::read_to_string(&path) {
+                Ok(text) => settings
+                    .read_toml(&text)
+                    .with_context(|| format!("invalid keymap in {}", path.display()))?,
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                Err(error) => {
+                    return Err(error).with_context(|| format!("failed to read {}", path.display()))
+                }
+            }
+        }
+        settings
+            .read_git_config(repo)
+            .context("invalid keymap in git config")?;
+
+        let mut keymap = Self::preset(&settings.preset)?;
+        for (action, sequences) in settings.overrides {
+            keymap.rebind(action, &sequences)?;
+        }
+        Ok(keymap)
+    }
+
+    /// Feeds a key press, returning the action once a whole sequence is
+    /// pressed. A key that breaks a sequence starts over on its own.
+    pub fn feed(&mut self, key: &KeyEvent) -> Option<Action> {
+        self.feed_at(key, Instant::now())
+    }
+
+    fn feed_at(&mut self, key: &KeyEvent, now: Instant) -> Option<Action> {
+        if self
+            .last_key
+            .is_some_and(|last_key| now.duration_since(last_key) > TIMEOUT)
+        {
+            self.pending.clear();
+        }
+        self.last_key = Some(now);
+        self.pending.push(Chord::from(key));
+        loop {
+            if let Some((_, action)) = self.bindings.iter().find(|(keys, _)| *keys == self.pending)
+            {
+                self.pending.clear();
+                return Some(*action);
+            }
+            if self
+                .bindings
+                .iter()
+                .any(|(keys, _)| keys.starts_with(&self.pending))
+            {
+                return None;
+            }
+            match self.pending.len() {
+                1 => {
+                    self.pending.clear();
+                    return None;
+                }
+                _ => {
+                    self.pending.drain(..self.pending.len() - 1);
+                }
+            }
+        }
+    }
+
+    /// The keys of a sequence pressed so far, if any.
+    pub fn pending(&self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| format_sequence(&self.pending))
+    }
+
+    /// The first sequence bound to `action`.
+    pub fn keys(&self, action: Action) -> Option<String> {
+        self.bindings(action).into_iter().next()
+    }
+
+    /// Every sequence bound to `action`.
+    pub fn bindings(&self, action: Action) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter(|(_, bound)| *bound == action)
+            .map(|(keys, _)| format_sequence(keys))
+            .collect()
+    }
+
+    /// `key label` pairs for the footer, skipping unbound actions.
+    pub fn tooltips(&self, actions: &[Action]) -> Vec<(String, &'static str)> {
+        actions
+            .iter()
+            .filter_map(|&action| Some((self.keys(action)?, action.label())))
+            .collect()
+    }
+}
+
+// MARK: Config
+
+/// The preset and the bindings that replace its own, in the order read.
+struct Settings {
+    preset: String,
+    overrides: Vec<(Action, Vec<String>)>,
+}
+
+impl Settings {
+    fn read_toml(&mut self, text: &str) -> anyhow::Result<()> {
+        let table = text.parse::<toml::Table>().context("failed to parse")?;
+        let Some(keys) = table.get("keys") else {
+            return Ok(());
+        };
+        let keys = keys
+            .as_table()
+            .ok_or_else(|| anyhow::format_err!("keys is not a table"))?;
+        for (name, value) in keys {
+            if name == "preset" {
+                self.preset = value
+                    .as_str()
+                    .ok_or_else(|| anyhow::format_err!("keys.preset is not a string"))?
+                    .to_owned();
+                continue;
+            }
+            let action = Action::parse(name)
+                .ok_or_else(|| anyhow::format_err!("unknown action {}", name))?;
+            let sequences = match value {
+                toml::Value::String(sequence) => vec![sequence.clone()],
+                toml::Value::Array(values) => values
+                    .iter()
+                    .map(|value| value.as_str().map(str::to_owned))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow::format_err!("keys.{} is not a list of strings", name))?,
+                _ => anyhow::bail!("keys.{} is not a string or list", name),
+            };
+            self.overrides.push((action, sequences));
+        }
+        Ok(())
+    }
+
+    fn read_git_config(&mut self, repo: &Repository) -> anyhow::Result<()> {
+        let config = repo.config().context("failed to open git config")?;
+        if let Ok(preset) = config.get_string("rebased.keymap") {
+            self.preset = preset;
+        }
+
+        // Values of the same key add up to one override.
+        let mut overrides: Vec<(Action, Vec<String>)> = Vec::new();
+        let mut entries = config
+            .entries(Some("rebased\\.keys\\..*"))
+            .context("failed to read git config")?;
+        while let Some(entry) = entries.next() {
+            let entry = entry.context("failed to read git config")?;
+            let (Some(name), Some(value)) = (entry.name(), entry.value()) else {
+                continue;
+            };
+            let name = name.trim_start_matches("rebased.keys.");
+            let action = Action::parse(name)
+                .ok_or_else(|| anyhow::format_err!("unknown action {}", name))?;
+            let index = match overrides.iter().position(|(bound, _)| *bound == action) {
+                Some(index) => index,
+                None => {
+                    overrides.push((action, Vec::new()));
+                    overrides.len() - 1
+                }
+            };
+            overrides[index].1.push(value.to_owned());
+        }
+        self.overrides.extend(overrides);
+        Ok(())
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("rebased").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    fn char(c: char) -> KeyEvent {
+        key(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        key(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    fn feed_all(keymap: &mut Keymap, keys: &[KeyEvent]) -> Vec<Option<Action>> {
+        let now = Instant::now();
+        keys.iter().map(|key| keymap.feed_at(key, now)).collect()
+    }
+
+    #[test]
+    fn test_parse_sequence() {
+        let sequence = |text| format_sequence(&parse_sequence(text).unwrap());
+        assert_eq!(sequence("gg"), "g g");
+        assert_eq!(sequence("C-x C-c"), "C-x C-c");
+        assert_eq!(sequence("M-< S-tab"), "M-< S-tab");
+        assert_eq!(sequence("Enter"), "enter");
+        // A lone `-` or `C-` is the key itself, not a modifier.
+        assert_eq!(sequence("- C--"), "- C--");
+        assert_eq!(parse_sequence("G").unwrap(), parse_sequence("S-G").unwrap());
+        assert!(parse_sequence("C-foo").is_err());
+        assert!(parse_sequence(" ").is_err());
+    }
+
+    #[test]
+    fn test_feed_sequences() {
+        let mut keymap = Keymap::preset("vim").unwrap();
+        assert_eq!(
+            feed_all(&mut keymap, &[char('g'), char('g')]),
+            vec![None, Some(Action::SelectFirst)]
+        );
+        assert_eq!(
+            feed_all(&mut keymap, &[char('G')]),
+            vec![Some(Action::SelectLast)]
+        );
+
+        let mut keymap = Keymap::preset("emacs").unwrap();
+        assert_eq!(
+            feed_all(&mut keymap, &[ctrl('x'), ctrl('c')]),
+            vec![None, Some(Action::Quit)]
+        );
+    }
+
+    #[test]
+    fn test_feed_broken_prefix() {
+        let mut keymap = Keymap::preset("vim").unwrap();
+        // `g` is held, then `j` breaks the sequence and counts on its own.
+        assert_eq!(
+            feed_all(&mut keymap, &[char('g'), char('j')]),
+            vec![None, Some(Action::SelectDown)]
+        );
+        assert_eq!(keymap.pending(), None);
+        // A key bound to nothing is dropped, along with the held prefix.
+        assert_eq!(
+            feed_all(&mut keymap, &[char('g'), char('z'), char('k')]),
+            vec![None, None, Some(Action::SelectUp)]
+        );
+        assert_eq!(feed_all(&mut keymap, &[char('g')]), vec![None]);
+        assert_eq!(keymap.pending().as_deref(), Some("g"));
+    }
+
+    #[test]
+    fn test_feed_timeout() {
+        let mut keymap = Keymap::preset("vim").unwrap();
+        let start = Instant::now();
+        assert_eq!(keymap.feed_at(&char('g'), start), None);
+        assert_eq!(
+            keymap.feed_at(&char('g'), start + TIMEOUT + Duration::from_millis(1)),
+            None
+        );
+        assert_eq!(keymap.pending().as_deref(), Some("g"));
+        assert_eq!(
+            keymap.feed_at(&char('g'), start + TIMEOUT * 2),
+            Some(Action::SelectFirst)
+        );
+    }
+
+    #[test]
+    fn test_bind_replaces_conflicts() {
+        let mut keymap = Keymap::preset("vim").unwrap();
+        // `g` would shadow `gg`, so binding it drops `gg`.
+        keymap.rebind(Action::Quit, &["g".to_owned()]).unwrap();
+        assert_eq!(keymap.bindings(Action::SelectFirst), vec!["home"]);
+        assert_eq!(
+            feed_all(&mut keymap, &[char('g')]),
+            vec![Some(Action::Quit)]
+        );
+        // `q` no longer quits, since rebinding replaced all of quit's keys.
+        assert_eq!(feed_all(&mut keymap, &[char('q')]), vec![None]);
+
+        // A sequence bound twice keeps the last action.
+        keymap.rebind(Action::Help, &["j".to_owned()]).unwrap();
+        assert_eq!(
+            feed_all(&mut keymap, &[char('j')]),
+            vec![Some(Action::Help)]
+        );
+        assert_eq!(keymap.bindings(Action::SelectDown), vec!["down"]);
+
+        // An empty binding unbinds the action.
+        keymap.rebind(Action::Help, &[String::new()]).unwrap();
+        assert!(keymap.bindings(Action::Help).is_empty());
+    }
+}
@@ -4,8 +4,26 @@ use clap::Parser;
 use clap::Subcommand;
 use git2::Repository;
 
-mod events;
+use crate::rewrite::AuthorDate;
+use crate::rewrite::AuthorIdentity;
+use crate::rewrite::CommitterDate;
+use crate::rewrite::Metadata;
+
+mod branches;
+mod check;
+mod describe;
+mod forge;
+mod keymap;
+mod push;
+mod rangediff;
+mod rewrite;
+mod sign;
 mod stack;
+mod stacks;
+#[cfg(test)]
+mod testing;
+mod theme;
+mod worktree;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -17,11 +35,109 @@ struct Args {
     command: Command,
 }
 
+/// Overrides for the `rebased.committerDate`, `rebased.authorDate` and
+/// `rebased.author` config options.
+#[derive(clap::Args, Debug)]
+struct MetadataArgs {
+    /// Committer date of rewritten commits.
+    #[clap(long, value_enum)]
+    committer_date: Option<CommitterDate>,
+
+    /// Same as `--committer-date author`.
+    #[clap(long, conflicts_with = "committer_date")]
+    committer_date_is_author_date: bool,
+
+    /// Author date of rewritten commits.
+    #[clap(long, value_enum)]
+    author_date: Option<AuthorDate>,
+
+    /// Author of rewritten commits.
+    #[clap(long, value_enum)]
+    author: Option<AuthorIdentity>,
+}
+
+impl MetadataArgs {
+    fn resolve(self, repository: &Repository) -> anyhow::Result<Metadata> {
+        let metadata = Metadata::from_config(repository)?;
+        Ok(Metadata {
+            committer_date: if self.committer_date_is_author_date {
+                CommitterDate::Author
+            } else {
+                self.committer_date.unwrap_or(metadata.committer_date)
+            },
+            author_date: self.author_date.unwrap_or(metadata.author_date),
+            author: self.author.unwrap_or(metadata.author),
+        })
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
     Stack {
         #[clap(short, long, default_value = "origin/master")]
         base: String,
+
+        #[clap(flatten)]
+        metadata: MetadataArgs,
+    },
+    /// List every local branch with commits not on the base, grouped by the
+    /// branches they are stacked on, and open the stack view for one.
+    Stacks {
+        #[clap(short, long, default_value = "origin/master")]
+        base: String,
+
+        #[clap(flatten)]
+        metadata: MetadataArgs,
+    },
+    /// Create or update a branch for every commit in the stack, named after
+    /// `rebased.branchTemplate`, and push them.
+    Branches {
+        #[clap(short, long, default_value = "origin/master")]
+        base: String,
+
+        /// Only update the local branches.
+        #[clap(long)]
+        no_push: bool,
+    },
+    /// Check every commit in the stack against the `rebased.check` rules,
+    /// failing if any do not pass.
+    Check {
+        #[clap(short, long, default_value = "origin/master")]
+        base: String,
+    },
+    /// Print the stack as a Markdown list for pull request descriptions.
+    Describe {
+        #[clap(short, long, default_value = "origin/master")]
+        base: String,
+
+        /// Commit to highlight, HEAD by default.
+        #[clap(short, long)]
+        current: Option<String>,
+
+        /// Include the body of each commit message.
+        #[clap(long)]
+        bodies: bool,
+    },
+    /// Open or update a review on the `rebased.forge` for every commit with a
+    /// branch, and post the stack summary to each.
+    Submit {
+        #[clap(short, long, default_value = "origin/master")]
+        base: String,
+    },
+    /// Compare each commit of the stack with its previous version, by default
+    /// the upstream of the branch or where it was before it last moved.
+    RangeDiff {
+        #[clap(short, long, default_value = "origin/master")]
+        base: String,
+
+        /// Previous version of the stack.
+        old: Option<String>,
+    },
+    /// Force-push every branch in the stack to its upstream, with a lease on
+    /// the remote-tracking branch.
+    Push {
+        #[clap(short, long, default_value = "origin/master")]
+        base: String,
     },
 }
 
@@ -36,6 +152,112 @@ fn main() -> anyhow::Result<()> {
     };
 
     match args.command {
-        Command::Stack { base } => stack::main(&repository, stack::Options { base }),
+        Command::Stack { base, metadata } => {
+            let metadata = metadata.resolve(&repository)?;
+            stack::main(&repository, stack::Options { base, metadata })
+        }
+        Command::Stacks { base, metadata } => {
+            let metadata = metadata.resolve(&repository)?;
+            stacks::main(&repository, stacks::Options { base, metadata })
+        }
+        Command::Branches { base, no_push } => {
+            let options = branches::Options {
+                base,
+                push: !no_push,
+            };
+            if !branches::main(&repository, options)? {
+                exit(1);
+            }
+            Ok(())
+        }
+        Command::Check { base } => {
+            if !check::main(&repository, check::Options { base })? {
+                exit(1);
+            }
+            Ok(())
+        }
+        Command::Describe {
+            base,
+            current,
+            bodies,
+        } => describe::main(
+            &repository,
+            describe::Options {
+                base,
+                current,
+                bodies,
+            },
+        ),
+        Command::Submit { base } => forge::main(&repository, forge::Options { base }),
+        Command::RangeDiff { base, old } => {
+            rangediff::main(&repository, rangediff::Options { base, old })
+        }
+        Command::Push { base } => {
+            if !push::main(&repository, push::Options { base })? {
+                exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    fn resolve(repo: &TempRepo, args: &[&str]) -> Metadata {
+        let args = Args::try_parse_from(["rebased", "stack"].iter().chain(args).copied()).unwrap();
+        let Command::Stack { metadata, .. } = args.command else {
+            unreachable!();
+        };
+        metadata.resolve(&repo.repo).unwrap()
+    }
+
+    #[test]
+    fn test_metadata_args_override_config() {
+        let repo = TempRepo::new("metadata-args");
+        assert_eq!(resolve(&repo, &[]), Metadata::default());
+
+        let mut config = repo.repo.config().unwrap();
+        config.set_str("rebased.committerDate", "original").unwrap();
+        config.set_str("rebased.authorDate", "reset").unwrap();
+        config.set_str("rebased.author", "reset").unwrap();
+        let configured = Metadata {
+            committer_date: CommitterDate::Original,
+            author_date: AuthorDate::Reset,
+            author: AuthorIdentity::Reset,
+        };
+        assert_eq!(resolve(&repo, &[]), configured);
+
+        assert_eq!(
+            resolve(
+                &repo,
+                &[
+                    "--committer-date",
+                    "now",
+                    "--author-date",
+                    "keep",
+                    "--author",
+                    "keep"
+                ]
+            ),
+            Metadata::default()
+        );
+        assert_eq!(
+            resolve(&repo, &["--committer-date-is-author-date"]),
+            Metadata {
+                committer_date: CommitterDate::Author,
+                ..configured
+            }
+        );
+        assert!(Args::try_parse_from([
+            "rebased",
+            "stack",
+            "--committer-date",
+            "now",
+            "--committer-date-is-author-date"
+        ])
+        .is_err());
     }
 }
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Context;
+use git2::BranchType;
+use git2::Cred;
+use git2::CredentialType;
+use git2::Oid;
+use git2::PushOptions;
+use git2::RemoteCallbacks;
+use git2::Repository;
+
+use crate::rewrite;
+use crate::rewrite::Stop;
+
+// MARK: Branches
+
+/// A local branch whose tip is one of the commits in the stack.
+pub struct StackBranch {
+    pub refname: String,
+    pub target: Oid,
+}
+
+impl StackBranch {
+    pub fn name(&self) -> &str {
+        self.refname
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&self.refname)
+    }
+}
+
+/// Lists the local branches pointing into the stack between `base` and HEAD,
+/// from the bottom of the stack up.
+pub fn stack_branches(repo: &Repository, base: &str) -> anyhow::Result<Vec<StackBranch>> {
+    if Stop::load(repo)?.is_some() {
+        anyhow::bail!("cannot push while an edit is in progress");
+    }
+
+    let head = repo
+        .head()
+        .context("failed to resolve HEAD")?
+        .peel_to_commit()
+        .context("HEAD is not a commit")?
+        .id();
+    let merge_base = rewrite::merge_base_with(repo, base, head)?;
+    let positions = rewrite::commits_between(repo, merge_base, head)?
+        .into_iter()
+        .enumerate()
+        .map(|(position, id)| (id, position))
+        .collect::<HashMap<_, _>>();
+
+    let mut branches = Vec::new();
+    for result in repo
+        .branches(Some(BranchType::Local))
+        .context("failed to list branches")?
+    {
+        let (branch, _) = result.context("failed to read branch")?;
+        let reference = branch.into_reference();
+        if let (Some(refname), Some(target)) = (reference.name(), reference.target()) {
+            if positions.contains_key(&target) {
+                branches.push(StackBranch {
+                    refname: refname.to_owned(),
+                    target,
+                });
+            }
+        }
+    }
+
+    branches.sort_by_key(|branch| (positions[&branch.target], branch.refname.clone()));
+    Ok(branches)
+}
+
+// MARK: Push
+
+pub enum Outcome {
+    UpToDate,
+    Pushed { from: Oid, to: Oid },
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::UpToDate => write!(f, "up to date"),
+            Outcome::Pushed { from, to } if from.is_zero() => write!(f, "created at {:.8}", to),
+            Outcome::Pushed { from, to } => write!(f, "{:.8}..{:.8}", from, to),
+        }
+    }
+}
+
+fn credentials<'a>(
+    repo: &Repository,
+) -> anyhow::Result<impl FnMut(&str, Option<&str>, CredentialType) -> Result<Cred, git2::Error> + 'a>
+{
+    let config = repo.config().context("failed to open git config")?;
+    let mut attempts = 0;
+    Ok(
+        move |url: &str, username: Option<&str>, allowed: CredentialType| {
+            attempts += 1;
+            if attempts > 3 {
+                return Err(git2::Error::from_str("authentication failed"));
+            }
+            if allowed.contains(CredentialType::SSH_KEY) {
+                Cred::ssh_key_from_agent(username.unwrap_or("git"))
+            } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+                Cred::credential_helper(&config, url, username)
+            } else {
+                Cred::default()
+            }
+        },
+    )
+}
+
+/// Force-pushes a branch to its upstream, but only if the upstream is still
+/// where the remote-tracking branch says it was when it was last fetched.
+pub fn push_branch(repo: &Repository, branch: &StackBranch) -> anyhow::Result<Outcome> {
+    let upstream = |buf: Result<git2::Buf, git2::Error>| {
+        buf.ok()
+            .and_then(|buf| buf.as_str().map(str::to_owned))
+            .ok_or_else(|| anyhow::format_err!("no upstream configured"))
+    };
+    let remote_name = upstream(repo.branch_upstream_remote(&branch.refname))?;
+    let merge = upstream(repo.branch_upstream_merge(&branch.refname))?;
+    let tracking = upstream(repo.branch_upstream_name(&branch.refname))?;
+    let expected = repo.refname_to_id(&tracking).unwrap_or(Oid::zero());
+    if expected == branch.target {
+        return Ok(Outcome::UpToDate);
+    }
+
+    let mut remote = repo
+        .find_remote(&remote_name)
+        .with_context(|| format!("failed to find remote {}", remote_name))?;
+    let mut stale = None;
+    let mut rejected = None;
+    let result = {
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(credentials(repo)?);
+        callbacks.push_negotiation(|updates| {
+            for update in updates {
+                if update.dst_refname() == Some(merge.as_str()) && update.src() != expected {
+                    stale = Some(update.src());
+                    return Err(git2::Error::from_str("stale info"));
+                }
+            }
+            Ok(())
+        });
+        callbacks.push_update_reference(|_, status| {
+            rejected = status.map(str::to_owned);
+            Ok(())
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+        let refspec = format!("+{}:{}", branch.refname, merge);
+        remote.push(&[refspec.as_str()], Some(&mut options))
+    };
+
+    if let Some(actual) = stale {
+        anyhow::bail!(
+            "stale info, {} is at {:.8} but {} expects {:.8}",
+            merge,
+            actual,
+            tracking,
+            expected
+        );
+    }
+    result.with_context(|| format!("failed to push to {}", remote_name))?;
+    if let Some(status) = rejected {
+        anyhow::bail!("rejected by {}: {}", remote_name, status);
+    }
+    Ok(Outcome::Pushed {
+        from: expected,
+        to: branch.target,
+    })
+}
+
+/// How pushing a branch went, as shown per branch.
+pub fn result_line(branch: &StackBranch, outcome: &anyhow::Result<Outcome>) -> String {
+    match outcome {
+        Ok(outcome) => format!("{}: {}", branch.name(), outcome),
+        Err(error) => format!("{}: {:#}", branch.name(), error),
+    }
+}
+
+pub fn push_stack(
+    repo: &Repository,
+    base: &str,
+) -> anyhow::Result<Vec<(StackBranch, anyhow::Result<Outcome>)>> {
+    Ok(stack_branches(repo, base)?
+        .into_iter()
+        .map(|branch| {
+            let outcome = push_branch(repo, &branch);
+            (branch, outcome)
+        })
+        .collect())
+}
+
+// MARK: Main
+
+pub struct Options {
+    pub base: String,
+}
+
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<bool> {
+    let results = push_stack(repo, &options.base)?;
+    if results.is_empty() {
+        println!("No branches in the stack");
+    }
+
+    let mut ok = true;
+    for (branch, outcome) in &results {
+        ok &= outcome.is_ok();
+        println!("{}", result_line(branch, outcome));
+    }
+    Ok(ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Repository;
+
+    use super::*;
+    use crate::testing::TempRepo;
+
+    /// A clone with `main` and `feature` pushed to a local bare remote and
+    /// `feature` tracking it.
+    fn setup() -> (TempRepo, TempRepo) {
+        let remote = TempRepo::bare("push-remote");
+        let local = TempRepo::new("push-local");
+        local
+            .repo
+            .remote("origin", remote.path().to_str().unwrap())
+            .unwrap();
+        local.commit("base.txt", "base\n", "base");
+        local
+            .repo
+            .find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+
+        local.switch("feature");
+        local.commit("a.txt", "a\n", "a");
+        let mut config = local.repo.config().unwrap();
+        config.set_str("branch.feature.remote", "origin").unwrap();
+        config
+            .set_str("branch.feature.merge", "refs/heads/feature")
+            .unwrap();
+        (remote, local)
+    }
+
+    fn lines(repo: &Repository) -> Vec<String> {
+        push_stack(repo, "main")
+            .unwrap()
+            .iter()
+            .map(|(branch, outcome)| result_line(branch, outcome))
+            .collect()
+    }
+
+    #[test]
+    fn test_push_creates_then_fast_forwards() {
+        let (remote, local) = setup();
+        let a = local.repo.head().unwrap().target().unwrap();
+        assert_eq!(
+            lines(&local.repo),
+            vec![format!("feature: created at {:.8}", a)]
+        );
+        assert_eq!(lines(&local.repo), vec!["feature: up to date".to_owned()]);
+
+        let b = local.commit("b.txt", "b\n", "b");
+        assert_eq!(
+            lines(&local.repo),
+            vec![format!("feature: {:.8}..{:.8}", a, b)]
+        );
+        assert_eq!(remote.repo.refname_to_id("refs/heads/feature").unwrap(), b);
+    }
+
+    #[test]
+    fn test_push_rejected_by_lease() {
+        let (remote, local) = setup();
+        push_stack(&local.repo, "main").unwrap();
+
+        // Someone else pushes to the branch, and nothing is fetched since.
+        let a = remote.repo.refname_to_id("refs/heads/feature").unwrap();
+        let a = remote.repo.find_commit(a).unwrap();
+        let signature = TempRepo::signature();
+        let theirs = remote
+            .repo
+            .commit(
+                Some("refs/heads/feature"),
+                &signature,
+                &signature,
+                "theirs",
+                &a.tree().unwrap(),
+                &[&a],
+            )
+            .unwrap();
+
+        local.commit("b.txt", "b\n", "b");
+        assert_eq!(
+            lines(&local.repo),
+            vec![format!(
+                "feature: stale info, refs/heads/feature is at {:.8} but \
+                 refs/remotes/origin/feature expects {:.8}",
+                theirs,
+                a.id()
+            )]
+        );
+        assert_eq!(
+            remote.repo.refname_to_id("refs/heads/feature").unwrap(),
+            theirs
+        );
+    }
+
+    #[test]
+    fn test_push_each_branch() {
+        let (_remote, local) = setup();
+        let a = local.repo.head().unwrap().target().unwrap();
+        local.switch("other");
+        local.commit("c.txt", "c\n", "c");
+        assert_eq!(
+            lines(&local.repo),
+            vec![
+                format!("feature: created at {:.8}", a),
+                "other: no upstream configured".to_owned(),
+            ]
+        );
+    }
+}
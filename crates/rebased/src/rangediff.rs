@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use git2::Commit;
+use git2::DiffFormat;
+use git2::DiffOptions;
+use git2::Oid;
+use git2::Patch;
+use git2::Repository;
+
+use crate::branches;
+use crate::rewrite;
+
+// MARK: Patches
+
+fn commit_diff<'repo>(
+    repo: &'repo Repository,
+    commit: &Commit<'_>,
+) -> anyhow::Result<git2::Diff<'repo>> {
+    let parent_tree = match commit.parent_count() {
+        0 => None,
+        _ => Some(
+            commit
+                .parent(0)
+                .and_then(|parent| parent.tree())
+                .context("failed to retrieve parent tree")?,
+        ),
+    };
+    let tree = commit.tree().context("failed to retrieve commit tree")?;
+    repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+        .with_context(|| format!("failed to diff commit {}", commit.id()))
+}
+
+pub fn patch_id(repo: &Repository, commit: &Commit<'_>) -> anyhow::Result<Oid> {
+    commit_diff(repo, commit)?
+        .patchid(None)
+        .with_context(|| format!("failed to compute patch id of {}", commit.id()))
+}
+
+/// Renders the message and patch of a commit without anything that changes
+/// when it is merely moved, like blob ids and line numbers.
+fn patch_text(repo: &Repository, commit: &Commit<'_>) -> anyhow::Result<String> {
+    let mut text = String::new();
+    for line in commit.message().unwrap_or("").trim_end().lines() {
+        text.push_str("    ");
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push('\n');
+
+    commit_diff(repo, commit)?
+        .print(DiffFormat::Patch, |_, _, line| {
+            let content = String::from_utf8_lossy(line.content());
+            match line.origin() {
+                '+' | '-' | ' ' => {
+                    text.push(line.origin());
+                    text.push_str(&content);
+                }
+                'H' => {
+                    // Keep the function context but not the line numbers.
+                    let context = content.splitn(3, "@@").nth(2).unwrap_or("\n");
+                    text.push_str("@@");
+                    text.push_str(context);
+                }
+                'F' => {
+                    for header in content.lines().filter(|line| !line.starts_with("index ")) {
+                        text.push_str(header);
+                        text.push('\n');
+                    }
+                }
+                _ => text.push_str(&content),
+            }
+            true
+        })
+        .with_context(|| format!("failed to print patch of {}", commit.id()))?;
+    Ok(text)
+}
+
+// MARK: Pairing
+
+/// One row of a range diff: a commit of the new stack paired with its
+/// previous version, a new commit, or an old commit that is gone.
+pub struct Entry {
+    pub old: Option<(usize, Oid)>,
+    pub new: Option<(usize, Oid)>,
+    pub summary: String,
+    /// The diff between the old and new patch, with the outer origin of each
+    /// line. Empty when the patches are the same.
+    pub lines: Vec<(char, String)>,
+}
+
+impl Entry {
+    pub fn marker(&self) -> char {
+        match (self.old, self.new) {
+            (Some(_), None) => '<',
+            (None, _) => '>',
+            _ if self.lines.is_empty() => '=',
+            _ => '!',
+        }
+    }
+
+    /// A `git range-diff`-like header, such as `1: 1234abcd ! 1: 5678cdef`.
+    pub fn header(&self) -> String {
+        let side = |side: Option<(usize, Oid)>| match side {
+            Some((index, id)) => format!("{}: {:.8}", index + 1, id),
+            None => "-: --------".to_owned(),
+        };
+        format!(
+            "{} {} {} {}",
+            side(self.old),
+            self.marker(),
+            side(self.new),
+            self.summary
+        )
+    }
+}
+
+type PairKey<'a> = dyn Fn(&Commit<'_>) -> Option<String> + 'a;
+
+/// Pairs the new commits that are not paired yet with old commits that are
+/// not taken yet and have the same key.
+fn link(
+    old: &[Commit<'_>],
+    new: &[Commit<'_>],
+    pairs: &mut [Option<usize>],
+    taken: &mut [bool],
+    key: &PairKey,
+) {
+    let mut keys = HashMap::new();
+    for (index, commit) in old.iter().enumerate() {
+        if taken[index] {
+            continue;
+        }
+        if let Some(key) = key(commit) {
+            keys.entry(key).or_insert(index);
+        }
+    }
+    for (index, commit) in new.iter().enumerate() {
+        if pairs[index].is_some() {
+            continue;
+        }
+        if let Some(&old_index) = key(commit).and_then(|key| keys.get(&key)) {
+            if !taken[old_index] {
+                taken[old_index] = true;
+                pairs[index] = Some(old_index);
+            }
+        }
+    }
+}
+
+/// Pairs each new commit with an old one, first by identity, then by what the
+/// old one was rewritten into, by patch id, by review branch and finally by
+/// summary, when no other commit on either side has the same one.
+fn pair(
+    repo: &Repository,
+    old: &[Commit<'_>],
+    new: &[Commit<'_>],
+) -> anyhow::Result<Vec<Option<usize>>> {
+    let patch_ids = old
+        .iter()
+        .chain(new)
+        .map(|commit| Ok((commit.id(), patch_id(repo, commit)?)))
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+    let rewritten = rewrite::load_rewritten(repo)?;
+    let branches = branches::Branches::load(repo)?;
+    let mut summaries = HashMap::<&str, (usize, usize)>::new();
+    for commit in old {
+        summaries
+            .entry(commit.summary().unwrap_or(""))
+            .or_default()
+            .0 += 1;
+    }
+    for commit in new {
+        summaries
+            .entry(commit.summary().unwrap_or(""))
+            .or_default()
+            .1 += 1;
+    }
+    let keys: [&PairKey; 5] = [
+        &|commit| Some(commit.id().to_string()),
+        &|commit| {
+            let id = commit.id();
+            Some(rewritten.get(&id).unwrap_or(&id).to_string())
+        },
+        &|commit| patch_ids.get(&commit.id()).map(Oid::to_string),
+        &|commit| branches.branch_of(repo, commit),
+        &|commit| {
+            let summary = commit.summary()?;
+            (summaries.get(summary) == Some(&(1, 1))).then(|| summary.to_owned())
+        },
+    ];
+
+    let mut pairs = vec![None; new.len()];
+    let mut taken = vec![false; old.len()];
+    for key in keys {
+        link(old, new, &mut pairs, &mut taken, key);
+    }
+    Ok(pairs)
+}
+
+fn diff_of_diffs(old: &str, new: &str) -> anyhow::Result<Vec<(char, String)>> {
+    let patch = Patch::from_buffers(
+        old.as_bytes(),
+        None,
+        new.as_bytes(),
+        None,
+        Some(DiffOptions::new().context_lines(3)),
+    )
+    .context("failed to diff patches")?;
+
+    let mut lines = Vec::new();
+    for hunk in 0..patch.num_hunks() {
+        let count = patch.num_lines_in_hunk(hunk)?;
+        lines.push(('@', "@@".to_owned()));
+        for index in 0..count {
+            let line = patch.line_in_hunk(hunk, index)?;
+            let content = String::from_utf8_lossy(line.content());
+            lines.push((line.origin(), content.trim_end_matches('\n').to_owned()));
+        }
+    }
+    Ok(lines)
+}
+
+/// Compares two versions of a stack commit by commit.
+pub fn range_diff(repo: &Repository, old: &[Oid], new: &[Oid]) -> anyhow::Result<Vec<Entry>> {
+    let find = |ids: &[Oid]| {
+        ids.iter()
+            .map(|&id| {
+                repo.find_commit(id)
+                    .with_context(|| format!("failed to find commit {}", id))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    let (old, new) = (find(old)?, find(new)?);
+    let pairs = pair(repo, &old, &new)?;
+
+    let mut entries = Vec::new();
+    let mut shown = vec![false; old.len()];
+    let removed = |entries: &mut Vec<Entry>, shown: &mut Vec<bool>, until: usize| {
+        for index in 0..until {
+            if !shown[index] && !pairs.contains(&Some(index)) {
+                shown[index] = true;
+                entries.push(Entry {
+                    old: Some((index, old[index].id())),
+                    new: None,
+                    summary: old[index].summary().unwrap_or("").to_owned(),
+                    lines: Vec::new(),
+                });
+            }
+        }
+    };
+
+    for (index, commit) in new.iter().enumerate() {
+        let lines = match pairs[index] {
+            Some(old_index) => {
+                removed(&mut entries, &mut shown, old_index);
+                shown[old_index] = true;
+                if old[old_index].id() == commit.id() {
+                    Vec::new()
+                } else {
+                    diff_of_diffs(
+                        &patch_text(repo, &old[old_index])?,
+                        &patch_text(repo, commit)?,
+                    )?
+                }
+            }
+            None => Vec::new(),
+        };
+        entries.push(Entry {
+            old: pairs[index].map(|old_index| (old_index, old[old_index].id())),
+            new: Some((index, commit.id())),
+            summary: commit.summary().unwrap_or("").to_owned(),
+            lines,
+        });
+    }
+    removed(&mut entries, &mut shown, old.len());
+    Ok(entries)
+}
+
+// MARK: Versions
+
+/// The previous version of the stack on HEAD's branch: the tip the tool last
+/// rewrote into HEAD, or else the upstream if it has commits of the stack,
+/// since that is what reviewers last saw, or else where the branch was before
+/// it last moved.
+pub fn previous_version(repo: &Repository, base: &str) -> anyhow::Result<Oid> {
+    let head = repo.head().context("failed to resolve HEAD")?;
+    let Some(refname) = head.name().filter(|_| head.is_branch()) else {
+        anyhow::bail!("HEAD is detached, pass the previous version explicitly");
+    };
+    let head_id = head.peel_to_commit().context("HEAD is not a commit")?.id();
+    if let Some(old) = rewrite::rewritten_from(repo, head_id)? {
+        return Ok(old);
+    }
+
+    // An upstream like origin/main is behind the stack, not a version of it.
+    let merge_base = rewrite::merge_base_with(repo, base, head_id)?;
+    if let Some(upstream) = repo
+        .branch_upstream_name(refname)
+        .ok()
+        .and_then(|name| name.as_str().map(str::to_owned))
+        .and_then(|name| repo.refname_to_id(&name).ok())
+    {
+        let behind = upstream == merge_base
+            || repo
+                .graph_descendant_of(merge_base, upstream)
+                .context("failed to compare the upstream with the merge base")?;
+        if !behind {
+            return Ok(upstream);
+        }
+    }
+
+    let reflog = repo
+        .reflog(refname)
+        .with_context(|| format!("failed to read reflog of {}", refname))?;
+    reflog
+        .get(1)
+        .map(|entry| entry.id_new())
+        .ok_or_else(|| anyhow::format_err!("{} has no previous version", refname))
+}
+
+/// Compares the stack between `base` and `old` with the stack between `base`
+/// and HEAD.
+pub fn compare(repo: &Repository, base: &str, old: Oid) -> anyhow::Result<Vec<Entry>> {
+    let head = repo
+        .head()
+        .context("failed to resolve HEAD")?
+        .peel_to_commit()
+        .context("HEAD is not a commit")?
+        .id();
+    let old_commits =
+        rewrite::commits_between(repo, rewrite::merge_base_with(repo, base, old)?, old)?;
+    let new_commits =
+        rewrite::commits_between(repo, rewrite::merge_base_with(repo, base, head)?, head)?;
+    range_diff(repo, &old_commits, &new_commits)
+}
+
+// MARK: Main
+
+pub struct Options {
+    pub base: String,
+    pub old: Option<String>,
+}
+
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<()> {
+    let old = match &options.old {
+        Some(old) => repo
+            .revparse_single(old)
+            .and_then(|object| object.peel_to_commit())
+            .with_context(|| format!("failed to resolve {}", old))?
+            .id(),
+        None => previous_version(repo, &options.base)?,
+    };
+
+    for entry in compare(repo, &options.base, old)? {
+        println!("{}", entry.header());
+        for (origin, line) in &entry.lines {
+            match origin {
+                '@' => println!("    @@"),
+                origin => println!("    {}{}", origin, line),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::TempRepo;
+
+    /// Commits `path` with `content` on top of `parent` without moving HEAD.
+    fn commit_on(repo: &Repository, parent: Oid, path: &str, content: &str, message: &str) -> Oid {
+        let parent = repo.find_commit(parent).unwrap();
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let mut builder = repo.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+        builder.insert(path, blob, 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let signature = TempRepo::signature();
+        repo.commit(None, &signature, &signature, message, &tree, &[&parent])
+            .unwrap()
+    }
+
+    fn headers(repo: &Repository, old: &[Oid], new: &[Oid]) -> Vec<String> {
+        range_diff(repo, old, new)
+            .unwrap()
+            .iter()
+            .map(|entry| format!("{} {}", entry.marker(), entry.summary))
+            .collect()
+    }
+
+    #[test]
+    fn test_pair_by_patch_id() {
+        let repo = TempRepo::new("rangediff-patch-id");
+        let base = repo.commit("base.txt", "base\n", "base");
+        let a = commit_on(&repo.repo, base, "a.txt", "a\n", "Add a");
+        let b = commit_on(&repo.repo, a, "b.txt", "b\n", "Add b");
+
+        // Reordered and reworded, so only the patches match.
+        let new_b = commit_on(&repo.repo, base, "b.txt", "b\n", "Create b");
+        let new_a = commit_on(&repo.repo, new_b, "a.txt", "a\n", "Create a");
+        let c = commit_on(&repo.repo, new_a, "c.txt", "c\n", "Add c");
+
+        let entries = range_diff(&repo.repo, &[a, b], &[new_b, new_a, c]).unwrap();
+        let pairs = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.old.map(|(index, _)| index),
+                    entry.new.map(|(index, _)| index),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pairs,
+            vec![(Some(1), Some(0)), (Some(0), Some(1)), (None, Some(2))]
+        );
+        assert_eq!(entries[2].marker(), '>');
+    }
+
+    #[test]
+    fn test_pair_by_recorded_rewrite() {
+        let repo = TempRepo::new("rangediff-rewritten");
+        let base = repo.commit("base.txt", "base\n", "base");
+        let a = commit_on(&repo.repo, base, "a.txt", "a\n", "Add a");
+        let b = commit_on(&repo.repo, a, "b.txt", "b\n", "Add b");
+
+        // Both patches and summaries change, but the rewrite was recorded.
+        let new_a = commit_on(&repo.repo, base, "a.txt", "a2\n", "Add a file");
+        let new_b = commit_on(&repo.repo, new_a, "b.txt", "b2\n", "Add b file");
+        let state = rewrite::shared_state_dir(&repo.repo);
+        fs::create_dir_all(&state).unwrap();
+        fs::write(
+            state.join("rewritten"),
+            format!("{} {} edit\n{} {} edit\n", a, new_a, b, new_b),
+        )
+        .unwrap();
+
+        assert_eq!(
+            headers(&repo.repo, &[a, b], &[new_a, new_b]),
+            vec!["! Add a file", "! Add b file"]
+        );
+        // Without the record nothing pairs them.
+        fs::remove_file(state.join("rewritten")).unwrap();
+        assert_eq!(
+            headers(&repo.repo, &[a, b], &[new_a, new_b]),
+            vec!["> Add a file", "> Add b file", "< Add a", "< Add b"]
+        );
+    }
+
+    #[test]
+    fn test_pair_by_unique_summary() {
+        let repo = TempRepo::new("rangediff-summary");
+        let base = repo.commit("base.txt", "base\n", "base");
+        let a = commit_on(&repo.repo, base, "a.txt", "a\n", "WIP");
+        let b = commit_on(&repo.repo, a, "b.txt", "b\n", "WIP");
+        let c = commit_on(&repo.repo, b, "c.txt", "c\n", "Add c");
+
+        let new_a = commit_on(&repo.repo, base, "a.txt", "a2\n", "WIP");
+        let new_b = commit_on(&repo.repo, new_a, "b.txt", "b2\n", "WIP");
+        let new_c = commit_on(&repo.repo, new_b, "c.txt", "c2\n", "Add c");
+
+        // The unrelated WIP commits are not paired just because they share a summary.
+        assert_eq!(
+            headers(&repo.repo, &[a, b, c], &[new_a, new_b, new_c]),
+            vec!["> WIP", "> WIP", "< WIP", "< WIP", "! Add c"]
+        );
+    }
+
+    #[test]
+    fn test_previous_version_skips_an_upstream_behind_the_stack() {
+        let repo = TempRepo::new("rangediff-previous");
+        repo.commit("base.txt", "base\n", "base");
+        repo.repo
+            .reference(
+                "refs/remotes/origin/main",
+                repo.repo.head().unwrap().target().unwrap(),
+                true,
+                "test",
+            )
+            .unwrap();
+        repo.switch("stack");
+        let mut config = repo.repo.config().unwrap();
+        config.set_str("branch.stack.remote", "origin").unwrap();
+        config
+            .set_str("branch.stack.merge", "refs/heads/main")
+            .unwrap();
+        let a = repo.commit("a.txt", "a\n", "Add a");
+        let b = repo.commit("b.txt", "b\n", "Add b");
+
+        // The reflog, since origin/main has none of the stack.
+        assert_eq!(previous_version(&repo.repo, "main").unwrap(), a);
+
+        // The recorded rewrite wins over the reflog.
+        let state = rewrite::shared_state_dir(&repo.repo);
+        fs::create_dir_all(&state).unwrap();
+        let old = commit_on(&repo.repo, a, "b.txt", "old\n", "Add b");
+        fs::write(state.join("rewritten"), format!("{} {} edit\n", old, b)).unwrap();
+        assert_eq!(previous_version(&repo.repo, "main").unwrap(), old);
+    }
+}
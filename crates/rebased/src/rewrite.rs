@@ -0,0 +1,1820 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::Context;
+use git2::build::CheckoutBuilder;
+use git2::Commit;
+use git2::Oid;
+use git2::Reference;
+use git2::Repository;
+use git2::Signature;
+use git2::Sort;
+use git2::Status;
+use git2::StatusOptions;
+use git2::Tree;
+
+use crate::sign;
+use crate::sign::Signer;
+use crate::worktree;
+
+// MARK: State
+
+/// The state of this worktree, like the edit in progress.
+pub fn state_dir(repo: &Repository) -> PathBuf {
+    repo.path().join("rebased")
+}
+
+/// The state shared by all the worktrees of the repository, like what the
+/// tool rewrote and the branches of commits.
+pub fn shared_state_dir(repo: &Repository) -> PathBuf {
+    repo.commondir().join("rebased")
+}
+
+// MARK: Stop
+
+/// An edit of a commit in the stack that is in progress. It is persisted in
+/// `.git/rebased/stop` so that it outlives the TUI.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Stop {
+    pub branch: Option<String>,
+    pub head: Oid,
+    pub commit: Oid,
+}
+
+impl Stop {
+    fn path(repo: &Repository) -> PathBuf {
+        state_dir(repo).join("stop")
+    }
+
+    pub fn load(repo: &Repository) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(repo);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+
+        let mut branch = None;
+        let mut head = None;
+        let mut commit = None;
+        for line in text.lines() {
+            match line.split_once(' ') {
+                Some(("branch", value)) => branch = Some(value.to_owned()),
+                Some(("head", value)) => head = Some(Oid::from_str(value)?),
+                Some(("commit", value)) => commit = Some(Oid::from_str(value)?),
+                _ => anyhow::bail!("malformed line in {}: {}", path.display(), line),
+            }
+        }
+
+        match (head, commit) {
+            (Some(head), Some(commit)) => Ok(Some(Self {
+                branch,
+                head,
+                commit,
+            })),
+            _ => anyhow::bail!("incomplete stop in {}", path.display()),
+        }
+    }
+
+    pub fn save(&self, repo: &Repository) -> anyhow::Result<()> {
+        let mut text = format!("head {}\ncommit {}\n", self.head, self.commit);
+        if let Some(branch) = &self.branch {
+            text.push_str(&format!("branch {}\n", branch));
+        }
+
+        let path = Self::path(repo);
+        fs::create_dir_all(state_dir(repo)).context("failed to create state directory")?;
+        fs::write(&path, text).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn clear(repo: &Repository) -> anyhow::Result<()> {
+        let path = Self::path(repo);
+        match fs::remove_file(&path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                Err(error).with_context(|| format!("failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// MARK: Commits
+
+/// Writes a commit without updating any reference, signing it when
+/// `commit.gpgsign` is set.
+pub fn write_commit(
+    repo: &Repository,
+    author: &Signature<'_>,
+    committer: &Signature<'_>,
+    message: &str,
+    tree: &Tree<'_>,
+    parents: &[&Commit<'_>],
+) -> anyhow::Result<Oid> {
+    let Some(signer) = Signer::from_config(repo)? else {
+        return repo
+            .commit(None, author, committer, message, tree, parents)
+            .context("failed to write commit");
+    };
+
+    let buffer = repo
+        .commit_create_buffer(author, committer, message, tree, parents)
+        .context("failed to create commit buffer")?;
+    let buffer = buffer
+        .as_str()
+        .ok_or_else(|| anyhow::format_err!("commit buffer is not valid UTF-8"))?;
+    let identity = format!(
+        "{} <{}>",
+        committer.name().unwrap_or(""),
+        committer.email().unwrap_or("")
+    );
+    let signature = signer.sign(buffer, &identity)?;
+    repo.commit_signed(buffer, &signature, None)
+        .context("failed to write signed commit")
+}
+
+/// Refuses to rewrite a signed commit when the rewritten commit would not be
+/// signed, rather than silently dropping the signature.
+fn check_signature(repo: &Repository, commit: &Commit<'_>) -> anyhow::Result<()> {
+    if sign::is_signed(repo, commit.id()) && Signer::from_config(repo)?.is_none() {
+        anyhow::bail!(
+            "{} is signed but commit.gpgsign is not set, so rewriting it would drop the signature",
+            describe(commit)
+        );
+    }
+    Ok(())
+}
+
+/// Finds where the stack at `head` forks from `base`.
+pub fn merge_base_with(repo: &Repository, base: &str, head: Oid) -> anyhow::Result<Oid> {
+    let base_id = repo
+        .resolve_reference_from_short_name(base)
+        .with_context(|| format!("failed to resolve base commit {}", base))?
+        .target()
+        .ok_or_else(|| anyhow::format_err!("no target OID for reference {}", base))?;
+    repo.merge_base(head, base_id)
+        .with_context(|| format!("failed to resolve merge base between {} and HEAD", base))
+}
+
+pub fn commits_between(repo: &Repository, base: Oid, head: Oid) -> anyhow::Result<Vec<Oid>> {
+    let mut revwalk = repo
+        .revwalk()
+        .context("failed to construct a revision walk")?;
+    revwalk.push(head)?;
+    revwalk.hide(base)?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    revwalk
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to walk commits")
+}
+
+fn describe(commit: &Commit<'_>) -> String {
+    format!("{:.8} {}", commit.id(), commit.summary().unwrap_or(""))
+}
+
+// MARK: Messages
+
+fn is_trailer(line: &str) -> bool {
+    line.split_once(':').is_some_and(|(token, value)| {
+        !token.is_empty()
+            && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && value.starts_with(' ')
+            && !value.trim().is_empty()
+    })
+}
+
+/// Splits a commit message into its body and its trailing block of
+/// `Token: value` trailers, such as `Signed-off-by` or `Change-Id`.
+pub fn split_trailers(message: &str) -> (&str, Vec<&str>) {
+    let message = message.trim_end();
+    let (body, last) = match message.rfind("\n\n") {
+        Some(i) => (&message[..i], &message[i + 2..]),
+        None => ("", message),
+    };
+    let trailers = last.lines().collect::<Vec<_>>();
+    if !body.is_empty() && trailers.iter().all(|line| is_trailer(line)) {
+        (body.trim_end(), trailers)
+    } else {
+        (message, Vec::new())
+    }
+}
+
+/// Trailers that identify a review, of which a commit should only have one.
+const UNIQUE_TRAILERS: &[&str] = &["Change-Id", "Rebased-Branch"];
+
+fn trailer_token(trailer: &str) -> Option<&str> {
+    trailer.split_once(':').map(|(token, _)| token)
+}
+
+/// Returns the value of the first `token` trailer in `message`.
+pub fn find_trailer<'a>(message: &'a str, token: &str) -> Option<&'a str> {
+    split_trailers(message)
+        .1
+        .into_iter()
+        .find(|line| trailer_token(line).is_some_and(|t| t.eq_ignore_ascii_case(token)))
+        .and_then(|line| line.split_once(':'))
+        .map(|(_, value)| value.trim())
+}
+
+/// Joins the messages of two squashed commits, keeping a single trailer block
+/// at the end. Duplicate trailers are dropped, and only the first `Change-Id`
+/// or `Rebased-Branch` is kept since it identifies the review the commits are
+/// squashed into.
+pub fn squash_message(first: &str, second: &str) -> String {
+    let (first_body, mut trailers) = split_trailers(first);
+    let (second_body, second_trailers) = split_trailers(second);
+    let is_unique = |trailer: &str| {
+        trailer_token(trailer).and_then(|token| {
+            UNIQUE_TRAILERS
+                .iter()
+                .find(|unique| unique.eq_ignore_ascii_case(token))
+        })
+    };
+    for trailer in second_trailers {
+        let is_duplicate = trailers.contains(&trailer)
+            || is_unique(trailer)
+                .is_some_and(|unique| trailers.iter().any(|line| is_unique(line) == Some(unique)));
+        if !is_duplicate {
+            trailers.push(trailer);
+        }
+    }
+
+    let mut message = format!("{}\n\n{}\n", first_body, second_body);
+    if !trailers.is_empty() {
+        message.push('\n');
+        for trailer in trailers {
+            message.push_str(trailer);
+            message.push('\n');
+        }
+    }
+    message
+}
+
+// MARK: Errors
+
+#[derive(Debug, thiserror::Error)]
+pub enum RewriteError {
+    #[error("conflict while replaying {name}")]
+    Conflict { position: usize, name: String },
+}
+
+// MARK: Metadata
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum CommitterDate {
+    #[default]
+    Now,
+    Original,
+    Author,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum AuthorDate {
+    #[default]
+    Keep,
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum AuthorIdentity {
+    #[default]
+    Keep,
+    Reset,
+}
+
+/// How rewritten commits get their author and committer. The committer is
+/// always the current user; only its date is configurable.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Metadata {
+    pub committer_date: CommitterDate,
+    pub author_date: AuthorDate,
+    pub author: AuthorIdentity,
+}
+
+fn get_value<T: clap::ValueEnum>(config: &git2::Config, name: &str) -> anyhow::Result<Option<T>> {
+    match config.get_string(name) {
+        Ok(value) => T::from_str(&value, true)
+            .map(Some)
+            .map_err(|_| anyhow::format_err!("invalid value {:?} for {}", value, name)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn same_signature(a: &Signature<'_>, b: &Signature<'_>) -> bool {
+    a.name_bytes() == b.name_bytes() && a.email_bytes() == b.email_bytes() && a.when() == b.when()
+}
+
+impl Metadata {
+    /// Reads `rebased.committerDate`, `rebased.authorDate` and
+    /// `rebased.author` from git config.
+    pub fn from_config(repo: &Repository) -> anyhow::Result<Self> {
+        let config = repo.config().context("failed to open git config")?;
+        Ok(Self {
+            committer_date: get_value(&config, "rebased.committerDate")?.unwrap_or_default(),
+            author_date: get_value(&config, "rebased.authorDate")?.unwrap_or_default(),
+            author: get_value(&config, "rebased.author")?.unwrap_or_default(),
+        })
+    }
+
+    /// Returns the author and committer for a rewrite of a commit with the
+    /// given original signatures.
+    pub fn signatures(
+        &self,
+        repo: &Repository,
+        author: &Signature<'_>,
+        committer: Option<&Signature<'_>>,
+    ) -> anyhow::Result<(Signature<'static>, Signature<'static>)> {
+        let now = repo.signature().context("failed to determine committer")?;
+        let identity = match self.author {
+            AuthorIdentity::Keep => author,
+            AuthorIdentity::Reset => &now,
+        };
+        let author_time = match self.author_date {
+            AuthorDate::Keep => author.when(),
+            AuthorDate::Reset => now.when(),
+        };
+        let author = Signature::new(
+            identity.name().unwrap_or(""),
+            identity.email().unwrap_or(""),
+            &author_time,
+        )?;
+
+        let committer_time = match (self.committer_date, committer) {
+            (CommitterDate::Original, Some(committer)) => committer.when(),
+            (CommitterDate::Author, _) => author_time,
+            _ => now.when(),
+        };
+        let committer = Signature::new(
+            now.name().unwrap_or(""),
+            now.email().unwrap_or(""),
+            &committer_time,
+        )?;
+        Ok((author, committer))
+    }
+
+    /// Whether an unchanged commit can be kept as is instead of being
+    /// rewritten with new metadata.
+    fn keeps(&self, repo: &Repository, commit: &Commit<'_>) -> anyhow::Result<bool> {
+        let (author, committer) =
+            self.signatures(repo, &commit.author(), Some(&commit.committer()))?;
+        Ok(same_signature(&author, &commit.author())
+            && (self.committer_date == CommitterDate::Now
+                || same_signature(&committer, &commit.committer())))
+    }
+}
+
+// MARK: Todo
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    Pick,
+    Squash,
+    Drop,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Pick => "pick",
+            Action::Squash => "squash",
+            Action::Drop => "drop",
+        }
+    }
+}
+
+/// One line of an interactive rebase todo list.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Todo {
+    pub commit: Oid,
+    pub action: Action,
+}
+
+// MARK: Plan
+
+/// A change to replay: the difference between `ancestor` and `tree`, which
+/// for an existing commit is its parent's tree and its own tree. A squashed
+/// pick is folded into the commit produced by the pick before it.
+pub struct Pick<'repo> {
+    pub original: Option<Commit<'repo>>,
+    pub ancestor: Tree<'repo>,
+    pub tree: Tree<'repo>,
+    pub author: Signature<'static>,
+    pub committer: Option<Signature<'static>>,
+    pub message: String,
+    pub squash: bool,
+}
+
+impl<'repo> Pick<'repo> {
+    pub fn from_commit(commit: Commit<'repo>) -> anyhow::Result<Self> {
+        if commit.parent_count() != 1 {
+            anyhow::bail!(
+                "cannot replay commit {} with {} parents",
+                commit.id(),
+                commit.parent_count()
+            );
+        }
+
+        let ancestor = commit
+            .parent(0)
+            .context("failed to retrieve commit parent")?
+            .tree()
+            .context("failed to retrieve commit tree")?;
+        let tree = commit.tree().context("failed to retrieve commit tree")?;
+        let author = commit.author().to_owned();
+        let committer = Some(commit.committer().to_owned());
+        let message = commit.message_raw().unwrap_or("").to_owned();
+        Ok(Self {
+            original: Some(commit),
+            ancestor,
+            tree,
+            author,
+            committer,
+            message,
+            squash: false,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        match &self.original {
+            Some(commit) => describe(commit),
+            None => format!("new commit {:?}", self.message.lines().next().unwrap_or("")),
+        }
+    }
+}
+
+/// The trees produced by merging each pick of a plan in order. Merging stops
+/// at the first conflict, so `trees` may be shorter than the plan.
+pub struct Simulation {
+    pub trees: Vec<Oid>,
+    pub conflict: Option<(usize, Vec<PathBuf>)>,
+}
+
+impl Simulation {
+    /// The lines added and removed by each predicted tree, compared to the one
+    /// before it or to `onto` for the first.
+    pub fn stats(&self, repo: &Repository, onto: Oid) -> anyhow::Result<Vec<(usize, usize)>> {
+        let mut parent = repo.find_tree(onto)?;
+        let mut stats = Vec::with_capacity(self.trees.len());
+        for &tree_id in &self.trees {
+            let tree = repo.find_tree(tree_id)?;
+            let diff = repo
+                .diff_tree_to_tree(Some(&parent), Some(&tree), None)
+                .context("failed to diff predicted trees")?
+                .stats()?;
+            stats.push((diff.insertions(), diff.deletions()));
+            parent = tree;
+        }
+        Ok(stats)
+    }
+}
+
+/// An ordered list of changes to replay onto a base commit. Replaying happens
+/// entirely in memory, so a plan can be checked for conflicts before any
+/// reference is moved.
+pub struct Plan<'repo> {
+    pub onto: Commit<'repo>,
+    pub picks: Vec<Pick<'repo>>,
+}
+
+impl<'repo> Plan<'repo> {
+    pub fn from_commits(
+        repo: &'repo Repository,
+        onto: Commit<'repo>,
+        commits: &[Oid],
+    ) -> anyhow::Result<Self> {
+        let picks = commits
+            .iter()
+            .map(|&id| {
+                repo.find_commit(id)
+                    .with_context(|| format!("failed to find commit {}", id))
+                    .and_then(Pick::from_commit)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { onto, picks })
+    }
+
+    pub fn from_todos(
+        repo: &'repo Repository,
+        onto: Commit<'repo>,
+        todos: &[Todo],
+    ) -> anyhow::Result<Self> {
+        let mut picks = Vec::new();
+        for todo in todos {
+            if todo.action == Action::Drop {
+                continue;
+            }
+
+            let commit = repo
+                .find_commit(todo.commit)
+                .with_context(|| format!("failed to find commit {}", todo.commit))?;
+            let mut pick = Pick::from_commit(commit)?;
+            pick.squash = todo.action == Action::Squash;
+            if pick.squash && picks.is_empty() {
+                anyhow::bail!("cannot squash {} without a previous commit", pick.name());
+            }
+            picks.push(pick);
+        }
+        Ok(Self { onto, picks })
+    }
+
+    pub fn simulate(&self, repo: &'repo Repository) -> anyhow::Result<Simulation> {
+        let mut trees = Vec::with_capacity(self.picks.len());
+        let mut tip = self.onto.tree().context("failed to retrieve commit tree")?;
+        for (position, pick) in self.picks.iter().enumerate() {
+            let mut index = repo
+                .merge_trees(&pick.ancestor, &tip, &pick.tree, None)
+                .with_context(|| format!("failed to merge {}", pick.name()))?;
+            if index.has_conflicts() {
+                let paths = index
+                    .conflicts()?
+                    .filter_map(Result::ok)
+                    .filter_map(|conflict| conflict.our.or(conflict.their).or(conflict.ancestor))
+                    .map(|entry| PathBuf::from(String::from_utf8_lossy(&entry.path).as_ref()))
+                    .collect();
+                return Ok(Simulation {
+                    trees,
+                    conflict: Some((position, paths)),
+                });
+            }
+
+            let tree_id = index.write_tree_to(repo)?;
+            tip = repo.find_tree(tree_id)?;
+            trees.push(tree_id);
+        }
+
+        Ok(Simulation {
+            trees,
+            conflict: None,
+        })
+    }
+
+    pub fn replay(
+        &self,
+        repo: &'repo Repository,
+        metadata: &Metadata,
+    ) -> anyhow::Result<Replayed<'repo>> {
+        let simulation = self.simulate(repo)?;
+        if let Some((position, _)) = simulation.conflict {
+            return Err(RewriteError::Conflict {
+                position,
+                name: self.picks[position].name(),
+            }
+            .into());
+        }
+
+        let mut tip = self.onto.clone();
+        // The originals that went into each commit written, squashes included.
+        let mut outputs: Vec<(Vec<Oid>, Oid)> = Vec::new();
+        for (pick, &tree_id) in self.picks.iter().zip(&simulation.trees) {
+            // Keep commits that would come out identical, signatures and all.
+            if let Some(original) = &pick.original {
+                if !pick.squash
+                    && original.tree_id() == tree_id
+                    && original.parent_id(0).ok() == Some(tip.id())
+                    && original.message_raw() == Some(pick.message.as_str())
+                    && same_signature(&original.author(), &pick.author)
+                    && metadata.keeps(repo, original)?
+                {
+                    tip = original.clone();
+                    outputs.push((vec![original.id()], original.id()));
+                    continue;
+                }
+                check_signature(repo, original)?;
+            }
+
+            let tree = repo.find_tree(tree_id)?;
+            let new_id = if pick.squash {
+                check_signature(repo, &tip)?;
+                let message = squash_message(tip.message_raw().unwrap_or(""), &pick.message);
+                let parents = tip.parents().collect::<Vec<_>>();
+                let (author, committer) =
+                    metadata.signatures(repo, &tip.author(), Some(&tip.committer()))?;
+                write_commit(
+                    repo,
+                    &author,
+                    &committer,
+                    &message,
+                    &tree,
+                    &parents.iter().collect::<Vec<_>>(),
+                )?
+            } else {
+                let (author, committer) =
+                    metadata.signatures(repo, &pick.author, pick.committer.as_ref())?;
+                write_commit(repo, &author, &committer, &pick.message, &tree, &[&tip])?
+            };
+            let original = pick.original.as_ref().map(Commit::id);
+            match (pick.squash, outputs.last_mut()) {
+                (true, Some((originals, id))) => {
+                    originals.extend(original);
+                    *id = new_id;
+                }
+                _ => outputs.push((original.into_iter().collect(), new_id)),
+            }
+            tip = repo.find_commit(new_id)?;
+        }
+
+        let rewritten = outputs
+            .into_iter()
+            .flat_map(|(originals, new)| originals.into_iter().map(move |old| (old, new)))
+            .filter(|(old, new)| old != new)
+            .collect();
+        Ok(Replayed { tip, rewritten })
+    }
+}
+
+/// The result of replaying a plan.
+pub struct Replayed<'repo> {
+    pub tip: Commit<'repo>,
+    /// Each original commit that was rewritten and the commit it became.
+    pub rewritten: Vec<(Oid, Oid)>,
+}
+
+// MARK: Checkout
+
+fn checkout(repo: &Repository, commit: &Commit<'_>) -> anyhow::Result<()> {
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))
+        .with_context(|| format!("failed to check out {}", commit.id()))
+}
+
+fn head_branch(head: &Reference<'_>) -> Option<String> {
+    head.is_branch()
+        .then(|| head.name().map(str::to_owned))
+        .flatten()
+}
+
+fn check_branch(repo: &Repository, branch: Option<&str>) -> anyhow::Result<()> {
+    match branch {
+        Some(branch) => worktree::check_not_checked_out(repo, branch),
+        None => Ok(()),
+    }
+}
+
+fn point_head(repo: &Repository, branch: Option<&str>, tip: &Commit<'_>) -> anyhow::Result<()> {
+    match branch {
+        Some(branch) => {
+            repo.reference(branch, tip.id(), true, "rebased: update stack")
+                .with_context(|| format!("failed to update {}", branch))?;
+            repo.set_head(branch)
+        }
+        None => repo.set_head_detached(tip.id()),
+    }
+    .context("failed to update HEAD")
+}
+
+fn move_head(repo: &Repository, branch: Option<&str>, tip: &Commit<'_>) -> anyhow::Result<()> {
+    check_branch(repo, branch)?;
+    point_head(repo, branch, tip)
+}
+
+fn update_head(repo: &Repository, branch: Option<&str>, tip: &Commit<'_>) -> anyhow::Result<()> {
+    check_branch(repo, branch)?;
+    checkout(repo, tip)?;
+    point_head(repo, branch, tip)
+}
+
+/// Checks out the branch `refname`, keeping local changes that do not
+/// conflict with it.
+pub fn switch_branch(repo: &Repository, refname: &str) -> anyhow::Result<()> {
+    if Stop::load(repo)?.is_some() {
+        anyhow::bail!("cannot switch branches while an edit is in progress");
+    }
+    check_branch(repo, Some(refname))?;
+    let commit = repo
+        .find_reference(refname)
+        .and_then(|reference| reference.peel_to_commit())
+        .with_context(|| format!("failed to resolve {}", refname))?;
+    checkout(repo, &commit)?;
+    repo.set_head(refname).context("failed to update HEAD")
+}
+
+// MARK: Provenance
+
+fn rewritten_path(repo: &Repository) -> PathBuf {
+    shared_state_dir(repo).join("rewritten")
+}
+
+/// Records a rewrite that already moved HEAD, so a failure is only a warning,
+/// which the public rewrites return.
+fn record(repo: &Repository, operation: &str, rewritten: &[(Oid, Oid)]) -> Option<String> {
+    try_record(repo, operation, rewritten).err().map(|error| {
+        format!(
+            "the rewrite was applied, but recording it failed: {:#}",
+            error
+        )
+    })
+}
+
+/// Appends `<old> <new> <operation>` lines to `.git/rebased/rewritten` and
+/// runs the `post-rewrite` hook with the pairs on stdin, like git, which
+/// passes `amend` for amends and `rebase` for everything else.
+fn try_record(repo: &Repository, operation: &str, rewritten: &[(Oid, Oid)]) -> anyhow::Result<()> {
+    if rewritten.is_empty() {
+        return Ok(());
+    }
+
+    let mut log = String::new();
+    let mut input = String::new();
+    for (old, new) in rewritten {
+        log.push_str(&format!("{} {} {}\n", old, new, operation));
+        input.push_str(&format!("{} {}\n", old, new));
+    }
+    fs::create_dir_all(shared_state_dir(repo)).context("failed to create state directory")?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(rewritten_path(repo))
+        .and_then(|mut file| file.write_all(log.as_bytes()))
+        .context("failed to record rewritten commits")?;
+
+    let command = match operation {
+        "amend" => "amend",
+        _ => "rebase",
+    };
+    run_hook(repo, "post-rewrite", &[command], &input)
+}
+
+fn run_hook(repo: &Repository, name: &str, args: &[&str], input: &str) -> anyhow::Result<()> {
+    let config = repo.config().context("failed to open git config")?;
+    let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+    let hooks = match config.get_path("core.hooksPath") {
+        Ok(path) => workdir.join(path),
+        Err(_) => repo.commondir().join("hooks"),
+    };
+    let hook = hooks.join(name);
+    if !hook.is_file() {
+        return Ok(());
+    }
+
+    // Like other post hooks, its result is ignored; its output would only
+    // garble the interface.
+    let child = Command::new(&hook)
+        .args(args)
+        .current_dir(workdir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Ok(mut child) = child {
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+        let _ = child.wait();
+    }
+    Ok(())
+}
+
+/// The `(old, new)` pairs recorded by the tool, oldest first.
+fn read_rewritten(repo: &Repository) -> anyhow::Result<Vec<(Oid, Oid)>> {
+    let text = match fs::read_to_string(rewritten_path(repo)) {
+        Ok(text) => text,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error).context("failed to read rewritten commits"),
+    };
+
+    let mut pairs = Vec::new();
+    for line in text.lines() {
+        let mut fields = line.split(' ');
+        if let (Some(Ok(old)), Some(Ok(new))) = (
+            fields.next().map(Oid::from_str),
+            fields.next().map(Oid::from_str),
+        ) {
+            pairs.push((old, new));
+        }
+    }
+    Ok(pairs)
+}
+
+/// The commit the tool last rewrote into `id`, if any.
+pub fn rewritten_from(repo: &Repository, id: Oid) -> anyhow::Result<Option<Oid>> {
+    Ok(read_rewritten(repo)?
+        .into_iter()
+        .rev()
+        .find(|&(_, new)| new == id)
+        .map(|(old, _)| old))
+}
+
+/// Maps every commit ever rewritten by the tool to what it last became,
+/// following chains of rewrites.
+pub fn load_rewritten(repo: &Repository) -> anyhow::Result<HashMap<Oid, Oid>> {
+    let mapping = read_rewritten(repo)?.into_iter().collect::<HashMap<_, _>>();
+
+    let mut latest = HashMap::new();
+    for &old in mapping.keys() {
+        let mut new = mapping[&old];
+        // Bounded in case a commit was rewritten back into an earlier one.
+        for _ in 0..mapping.len() {
+            match mapping.get(&new) {
+                Some(&next) if next != old => new = next,
+                _ => break,
+            }
+        }
+        latest.insert(old, new);
+    }
+    Ok(latest)
+}
+
+// MARK: Local changes
+
+fn local_changes(repo: &Repository) -> anyhow::Result<Vec<String>> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let statuses = repo
+        .statuses(Some(&mut options))
+        .context("failed to read working tree status")?;
+    Ok(statuses
+        .iter()
+        .filter(|entry| entry.status() != Status::CURRENT)
+        .filter_map(|entry| entry.path().map(str::to_owned))
+        .collect())
+}
+
+/// Fails with the list of files if there are uncommitted changes to tracked
+/// files.
+fn require_clean(repo: &Repository) -> anyhow::Result<()> {
+    let changes = local_changes(repo)?;
+    if !changes.is_empty() {
+        anyhow::bail!(
+            "uncommitted changes would be overwritten, commit or stash them first: {}",
+            changes.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Runs `f`, which moves HEAD and the working tree, with uncommitted changes
+/// put aside in a stash if `rebased.autoStash` or `rebase.autoStash` is set,
+/// and refuses to otherwise. Returns a warning with the result of `f` if the
+/// changes could not be put back.
+fn with_autostash<T>(
+    repo: &Repository,
+    f: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<(T, Option<String>)> {
+    let config = repo.config().context("failed to open git config")?;
+    let autostash = config
+        .get_bool("rebased.autoStash")
+        .or_else(|_| config.get_bool("rebase.autoStash"))
+        .unwrap_or(false);
+    if !autostash || local_changes(repo)?.is_empty() {
+        require_clean(repo)?;
+        return Ok((f()?, None));
+    }
+
+    // Stashing needs a mutable handle, so take one of our own.
+    let mut stash = Repository::open(repo.path()).context("failed to reopen repository")?;
+    let signature = repo
+        .signature()
+        .context("failed to get default signature")?;
+    stash
+        .stash_save(&signature, "rebased: autostash", None)
+        .context("failed to stash local changes")?;
+    let result = f();
+    match (pop_autostash(&mut stash), result) {
+        (Ok(()), result) => Ok((result?, None)),
+        (Err(error), Ok(value)) => Ok((
+            value,
+            Some(format!(
+                "the stashed changes do not apply after the rewrite ({}), they are kept in stash@{{0}}",
+                error
+            )),
+        )),
+        (Err(error), Err(rewrite)) => Err(rewrite.context(format!(
+            "the stashed changes could not be restored ({}), they are kept in stash@{{0}}",
+            error
+        ))),
+    }
+}
+
+/// Applies the latest stash and drops it, unless that leaves conflicts. Like
+/// `git stash pop`, the conflicts are then left in the working tree.
+fn pop_autostash(stash: &mut Repository) -> Result<(), String> {
+    let message = |error: git2::Error| error.message().to_owned();
+    // The handle still has the index from before the rewrite moved HEAD,
+    // which applying would take for staged changes.
+    let mut index = stash.index().map_err(message)?;
+    index.read(true).map_err(message)?;
+    stash.stash_apply(0, None).map_err(message)?;
+    index.read(true).map_err(message)?;
+    if index.has_conflicts() {
+        let paths = index
+            .conflicts()
+            .map_err(message)?
+            .filter_map(Result::ok)
+            .filter_map(|conflict| conflict.our.or(conflict.their))
+            .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+            .collect::<Vec<_>>();
+        return Err(format!("conflicts in {}", paths.join(", ")));
+    }
+    stash.stash_drop(0).map_err(message)
+}
+
+// MARK: Edit
+
+pub fn stop_at(repo: &Repository, commit: Oid) -> anyhow::Result<Stop> {
+    // Changes stashed here would end up amended into the edited commit.
+    require_clean(repo)?;
+    let head = repo.head().context("failed to resolve HEAD")?;
+    let stop = Stop {
+        branch: head_branch(&head),
+        head: head.peel_to_commit().context("HEAD is not a commit")?.id(),
+        commit,
+    };
+
+    let target = repo
+        .find_commit(commit)
+        .with_context(|| format!("failed to find commit {}", commit))?;
+    checkout(repo, &target)?;
+    repo.set_head_detached(commit)
+        .context("failed to detach HEAD")?;
+    stop.save(repo)?;
+    Ok(stop)
+}
+
+/// Amends the checked out commit with the changes to tracked files and what is
+/// staged, then replays the rest of the stack on top of it and moves the
+/// branch. Returns a warning if the rewrite could not be recorded.
+pub fn resume(
+    repo: &Repository,
+    stop: &Stop,
+    metadata: &Metadata,
+) -> anyhow::Result<Option<String>> {
+    let mut base = repo
+        .head()
+        .context("failed to resolve HEAD")?
+        .peel_to_commit()
+        .context("HEAD is not a commit")?;
+
+    let mut index = repo.index().context("failed to open index")?;
+    // Like `git commit -a`, untracked files are only included when staged.
+    index.update_all(["*"], None)?;
+    index.write().context("failed to write index")?;
+    let tree_id = index.write_tree().context("failed to write tree")?;
+
+    let mut amended = None;
+    if tree_id != base.tree_id() {
+        check_signature(repo, &base)?;
+        let tree = repo.find_tree(tree_id)?;
+        let (author, committer) =
+            metadata.signatures(repo, &base.author(), Some(&base.committer()))?;
+        let parents = base.parents().collect::<Vec<_>>();
+        let id = write_commit(
+            repo,
+            &author,
+            &committer,
+            base.message_raw().unwrap_or(""),
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )?;
+        amended = Some((base.id(), id));
+        base = repo.find_commit(id)?;
+        repo.set_head_detached(id)
+            .context("failed to move HEAD to amended commit")?;
+    }
+
+    let rest = commits_between(repo, stop.commit, stop.head)?;
+    let replayed = Plan::from_commits(repo, base, &rest)?.replay(repo, metadata)?;
+    update_head(repo, stop.branch.as_deref(), &replayed.tip)?;
+    Stop::clear(repo)?;
+    let amend_warning = record(repo, "amend", amended.as_slice());
+    let warning = record(repo, "edit", &replayed.rewritten).or(amend_warning);
+    Ok(warning)
+}
+
+pub fn abort(repo: &Repository, stop: &Stop) -> anyhow::Result<()> {
+    let head = repo
+        .find_commit(stop.head)
+        .with_context(|| format!("failed to find commit {}", stop.head))?;
+    repo.checkout_tree(head.as_object(), Some(CheckoutBuilder::new().force()))
+        .with_context(|| format!("failed to check out {}", head.id()))?;
+    match stop.branch.as_deref() {
+        Some(branch) => repo.set_head(branch),
+        None => repo.set_head_detached(head.id()),
+    }
+    .context("failed to restore HEAD")?;
+    Stop::clear(repo)
+}
+
+// MARK: Insert
+
+/// Inserts a commit holding whatever is staged in the index right after
+/// `after`, then replays the rest of the stack on top of it. The working tree
+/// is left alone, since the staged changes end up in the stack either way.
+/// Returns a warning if the rewrite could not be recorded.
+pub fn insert_after(
+    repo: &Repository,
+    after: Oid,
+    message: &str,
+    metadata: &Metadata,
+) -> anyhow::Result<Option<String>> {
+    let head = repo.head().context("failed to resolve HEAD")?;
+    let branch = head_branch(&head);
+    let head = head.peel_to_commit().context("HEAD is not a commit")?;
+
+    let mut index = repo.index().context("failed to open index")?;
+    if index.has_conflicts() {
+        anyhow::bail!("the index has unresolved conflicts");
+    }
+    let staged = repo.find_tree(index.write_tree().context("failed to write tree")?)?;
+
+    let after = repo
+        .find_commit(after)
+        .with_context(|| format!("failed to find commit {}", after))?;
+    let after_name = describe(&after);
+    let rest = commits_between(repo, after.id(), head.id())?;
+    let mut plan = Plan::from_commits(repo, after, &rest)?;
+
+    let mut message = message.trim_end().to_owned();
+    message.push('\n');
+    plan.picks.insert(
+        0,
+        Pick {
+            original: None,
+            ancestor: head.tree().context("failed to retrieve HEAD tree")?,
+            tree: staged,
+            author: repo
+                .signature()
+                .context("failed to determine author")?
+                .to_owned(),
+            committer: None,
+            message,
+            squash: false,
+        },
+    );
+
+    let replayed = plan.replay(repo, metadata).map_err(|error| {
+        match error.downcast_ref::<RewriteError>() {
+            Some(RewriteError::Conflict { position: 0, .. }) => {
+                anyhow::format_err!("staged changes do not apply on top of {}", after_name)
+            }
+            Some(RewriteError::Conflict { name, .. }) => {
+                anyhow::format_err!("staged changes conflict with {}", name)
+            }
+            None => error,
+        }
+    })?;
+
+    move_head(repo, branch.as_deref(), &replayed.tip)?;
+    index
+        .read_tree(
+            &replayed
+                .tip
+                .tree()
+                .context("failed to retrieve commit tree")?,
+        )
+        .context("failed to reset index")?;
+    index.write().context("failed to write index")?;
+    Ok(record(repo, "insert", &replayed.rewritten))
+}
+
+// MARK: Apply
+
+/// Replays the stack from `onto` according to `todos` and moves the branch to
+/// the result. Returns a warning if the rewrite could not be recorded.
+pub fn apply(
+    repo: &Repository,
+    onto: Oid,
+    todos: &[Todo],
+    metadata: &Metadata,
+) -> anyhow::Result<Option<String>> {
+    let head = repo.head().context("failed to resolve HEAD")?;
+    let branch = head_branch(&head);
+    let onto = repo
+        .find_commit(onto)
+        .with_context(|| format!("failed to find commit {}", onto))?;
+    let replayed = Plan::from_todos(repo, onto, todos)?.replay(repo, metadata)?;
+    let ((), stash_warning) =
+        with_autostash(repo, || update_head(repo, branch.as_deref(), &replayed.tip))?;
+    let warnings = [stash_warning, record(repo, "apply", &replayed.rewritten)]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    Ok((!warnings.is_empty()).then(|| warnings.join("; ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn test_split_trailers() {
+        let message = "Fix the parser\n\nIt missed a case.\n\nChange-Id: I123\nSigned-off-by: A <a@example.com>\n";
+        assert_eq!(
+            split_trailers(message),
+            (
+                "Fix the parser\n\nIt missed a case.",
+                vec!["Change-Id: I123", "Signed-off-by: A <a@example.com>"]
+            )
+        );
+        // A summary on its own is never a trailer block.
+        assert_eq!(
+            split_trailers("Fixes: the parser\n"),
+            ("Fixes: the parser", Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_split_trailers_ignores_paragraphs_that_only_look_like_trailers() {
+        for message in [
+            // Only some of the lines are trailers.
+            "Fix the parser\n\nNote: the lexer is fine\nso only the parser changes\n",
+            // The token has a space in it.
+            "Fix the parser\n\nSee also: the lexer\n",
+            // A link, not a trailer.
+            "Fix the parser\n\nhttps://example.com/issues/1\n",
+            // No value.
+            "Fix the parser\n\nTODO:\n",
+        ] {
+            assert_eq!(
+                split_trailers(message),
+                (message.trim_end(), Vec::new()),
+                "{:?}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_trailer() {
+        let message = "Fix the parser\n\nchange-id: I123\nChange-Id: I456\n";
+        assert_eq!(find_trailer(message, "Change-Id"), Some("I123"));
+        assert_eq!(find_trailer(message, "Signed-off-by"), None);
+        assert_eq!(find_trailer("Change-Id: I123\n", "Change-Id"), None);
+    }
+
+    #[test]
+    fn test_squash_message_dedups_trailers() {
+        let first = "Fix the parser\n\nChange-Id: I1\nSigned-off-by: A <a@example.com>\n";
+        let second = "fixup! Fix the parser\n\nChange-Id: I2\nSigned-off-by: A <a@example.com>\nSigned-off-by: B <b@example.com>\n";
+        assert_eq!(
+            squash_message(first, second),
+            "Fix the parser\n\nfixup! Fix the parser\n\n\
+             Change-Id: I1\n\
+             Signed-off-by: A <a@example.com>\n\
+             Signed-off-by: B <b@example.com>\n"
+        );
+        assert_eq!(squash_message("a\n", "b\n"), "a\n\nb\n");
+    }
+
+    /// Commits `message` on HEAD with a made up signature attached.
+    fn commit_signed(repo: &TempRepo, message: &str) -> Oid {
+        let head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        let signature = TempRepo::signature();
+        let buffer = repo
+            .repo
+            .commit_create_buffer(
+                &signature,
+                &signature,
+                message,
+                &head.tree().unwrap(),
+                &[&head],
+            )
+            .unwrap();
+        let id = repo
+            .repo
+            .commit_signed(
+                buffer.as_str().unwrap(),
+                "-----BEGIN PGP SIGNATURE-----\n\nfake\n-----END PGP SIGNATURE-----",
+                None,
+            )
+            .unwrap();
+        repo.repo
+            .reference("refs/heads/main", id, true, "signed")
+            .unwrap();
+        id
+    }
+
+    #[test]
+    fn test_check_signature_needs_a_signer() {
+        let repo = TempRepo::new("check-signature");
+        repo.commit("base.txt", "base\n", "base");
+        let signed = commit_signed(&repo, "signed");
+        let commit = repo.repo.find_commit(signed).unwrap();
+        assert!(sign::is_signed(&repo.repo, signed));
+
+        let error = check_signature(&repo.repo, &commit).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("rewriting it would drop the signature"),
+            "{}",
+            error
+        );
+
+        repo.repo
+            .config()
+            .unwrap()
+            .set_bool("commit.gpgsign", true)
+            .unwrap();
+        check_signature(&repo.repo, &commit).unwrap();
+    }
+
+    #[test]
+    fn test_signed_commits_are_not_rewritten_without_a_signer() {
+        let repo = TempRepo::new("signed-rewrite");
+        let base = repo.commit("base.txt", "base\n", "base");
+        let signed = commit_signed(&repo, "signed");
+        let b = repo.commit("b.txt", "b\n", "b");
+
+        let todos = [
+            Todo {
+                commit: b,
+                action: Action::Pick,
+            },
+            Todo {
+                commit: signed,
+                action: Action::Pick,
+            },
+        ];
+        assert!(apply(&repo.repo, base, &todos, &Metadata::default()).is_err());
+        assert_eq!(repo.repo.head().unwrap().target(), Some(b));
+    }
+
+    /// Commits `path` on HEAD as someone else, at times before the tests run.
+    fn commit_as_other(repo: &TempRepo, path: &str) -> Oid {
+        let author = Signature::new(
+            "Other",
+            "other@example.com",
+            &git2::Time::new(1_600_000_000, 60),
+        )
+        .unwrap();
+        let committer = Signature::new(
+            "Committer",
+            "committer@example.com",
+            &git2::Time::new(1_650_000_000, 0),
+        )
+        .unwrap();
+        fs::write(repo.path().join(path), path).unwrap();
+        let mut index = repo.repo.index().unwrap();
+        index.add_path(std::path::Path::new(path)).unwrap();
+        index.write().unwrap();
+        let tree = repo.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        repo.repo
+            .commit(Some("HEAD"), &author, &committer, path, &tree, &[&head])
+            .unwrap()
+    }
+
+    /// Swaps the last two commits, which are by someone else, with `metadata`
+    /// and returns the rewrite of the first.
+    fn rewrite_with(name: &str, metadata: Metadata) -> (TempRepo, Oid) {
+        let repo = TempRepo::new(name);
+        let base = repo.commit("base.txt", "base\n", "base");
+        let a = commit_as_other(&repo, "a.txt");
+        let b = commit_as_other(&repo, "b.txt");
+        let todos = todos(&[(b, Action::Pick), (a, Action::Pick)]);
+        apply(&repo.repo, base, &todos, &metadata).unwrap();
+        let head = repo.repo.head().unwrap().target().unwrap();
+        (repo, head)
+    }
+
+    #[test]
+    fn test_metadata_from_config() {
+        let repo = TempRepo::new("metadata-config");
+        assert_eq!(
+            Metadata::from_config(&repo.repo).unwrap(),
+            Metadata::default()
+        );
+
+        let mut config = repo.repo.config().unwrap();
+        config.set_str("rebased.committerDate", "Author").unwrap();
+        config.set_str("rebased.authorDate", "reset").unwrap();
+        config.set_str("rebased.author", "reset").unwrap();
+        assert_eq!(
+            Metadata::from_config(&repo.repo).unwrap(),
+            Metadata {
+                committer_date: CommitterDate::Author,
+                author_date: AuthorDate::Reset,
+                author: AuthorIdentity::Reset,
+            }
+        );
+
+        config.set_str("rebased.author", "someone").unwrap();
+        let error = Metadata::from_config(&repo.repo).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid value \"someone\" for rebased.author"
+        );
+    }
+
+    #[test]
+    fn test_metadata_defaults() {
+        let (repo, id) = rewrite_with("metadata-defaults", Metadata::default());
+        let commit = repo.repo.find_commit(id).unwrap();
+        assert_eq!(commit.author().name(), Some("Other"));
+        assert_eq!(commit.author().when(), git2::Time::new(1_600_000_000, 60));
+        // The committer is always whoever rewrites the commit.
+        assert_eq!(commit.committer().name(), Some("Tester"));
+        assert!(commit.committer().when().seconds() > 1_700_000_000);
+    }
+
+    #[test]
+    fn test_metadata_committer_date() {
+        let metadata = Metadata {
+            committer_date: CommitterDate::Original,
+            ..Metadata::default()
+        };
+        let (repo, id) = rewrite_with("metadata-committer-original", metadata);
+        let commit = repo.repo.find_commit(id).unwrap();
+        assert_eq!(commit.committer().name(), Some("Tester"));
+        assert_eq!(commit.committer().when(), git2::Time::new(1_650_000_000, 0));
+
+        let metadata = Metadata {
+            committer_date: CommitterDate::Author,
+            ..Metadata::default()
+        };
+        let (repo, id) = rewrite_with("metadata-committer-author", metadata);
+        let commit = repo.repo.find_commit(id).unwrap();
+        assert_eq!(
+            commit.committer().when(),
+            git2::Time::new(1_600_000_000, 60)
+        );
+    }
+
+    #[test]
+    fn test_metadata_author_date_and_identity() {
+        let metadata = Metadata {
+            author_date: AuthorDate::Reset,
+            ..Metadata::default()
+        };
+        let (repo, id) = rewrite_with("metadata-author-date", metadata);
+        let commit = repo.repo.find_commit(id).unwrap();
+        assert_eq!(commit.author().name(), Some("Other"));
+        assert!(commit.author().when().seconds() > 1_700_000_000);
+
+        let metadata = Metadata {
+            author: AuthorIdentity::Reset,
+            committer_date: CommitterDate::Author,
+            ..Metadata::default()
+        };
+        let (repo, id) = rewrite_with("metadata-author-identity", metadata);
+        let commit = repo.repo.find_commit(id).unwrap();
+        assert_eq!(commit.author().name(), Some("Tester"));
+        assert_eq!(commit.author().email(), Some("tester@example.com"));
+        assert_eq!(commit.author().when(), git2::Time::new(1_600_000_000, 60));
+        assert_eq!(commit.committer().when(), commit.author().when());
+    }
+
+    /// A stack of `a`, adding one line, and `b`, adding two, on `base`.
+    fn plan_repo(name: &str) -> (TempRepo, Oid, Oid, Oid) {
+        let repo = TempRepo::new(name);
+        let base = repo.commit("base.txt", "base\n", "base");
+        let a = repo.commit("a.txt", "a\n", "a");
+        let b = repo.commit("b.txt", "b\nb\n", "b");
+        (repo, base, a, b)
+    }
+
+    fn todos(todos: &[(Oid, Action)]) -> Vec<Todo> {
+        todos
+            .iter()
+            .map(|&(commit, action)| Todo { commit, action })
+            .collect()
+    }
+
+    fn simulate(repo: &TempRepo, onto: Oid, todos: &[Todo]) -> Simulation {
+        let onto = repo.repo.find_commit(onto).unwrap();
+        Plan::from_todos(&repo.repo, onto, todos)
+            .unwrap()
+            .simulate(&repo.repo)
+            .unwrap()
+    }
+
+    fn paths(repo: &TempRepo, tree: Oid) -> Vec<String> {
+        let tree = repo.repo.find_tree(tree).unwrap();
+        tree.iter()
+            .map(|entry| entry.name().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_simulate_reorder() {
+        let (repo, base, a, b) = plan_repo("simulate-reorder");
+        let todos = todos(&[(b, Action::Pick), (a, Action::Pick)]);
+        let simulation = simulate(&repo, base, &todos);
+        assert!(simulation.conflict.is_none());
+        assert_eq!(simulation.trees.len(), 2);
+        assert_eq!(paths(&repo, simulation.trees[0]), ["b.txt", "base.txt"]);
+        assert_eq!(
+            simulation.trees[1],
+            repo.repo.find_commit(b).unwrap().tree_id()
+        );
+        let onto = repo.repo.find_commit(base).unwrap().tree_id();
+        assert_eq!(
+            simulation.stats(&repo.repo, onto).unwrap(),
+            [(2, 0), (1, 0)]
+        );
+    }
+
+    #[test]
+    fn test_simulate_squash() {
+        let (repo, base, a, b) = plan_repo("simulate-squash");
+        let todos = todos(&[(a, Action::Pick), (b, Action::Squash)]);
+        let simulation = simulate(&repo, base, &todos);
+        assert!(simulation.conflict.is_none());
+        assert_eq!(
+            simulation.trees,
+            [
+                repo.repo.find_commit(a).unwrap().tree_id(),
+                repo.repo.find_commit(b).unwrap().tree_id()
+            ]
+        );
+        let onto = repo.repo.find_commit(base).unwrap().tree_id();
+        assert_eq!(
+            simulation.stats(&repo.repo, onto).unwrap(),
+            [(1, 0), (2, 0)]
+        );
+    }
+
+    #[test]
+    fn test_simulate_drop() {
+        let (repo, base, a, b) = plan_repo("simulate-drop");
+        let todos = todos(&[(a, Action::Drop), (b, Action::Pick)]);
+        let simulation = simulate(&repo, base, &todos);
+        assert!(simulation.conflict.is_none());
+        assert_eq!(simulation.trees.len(), 1);
+        assert_eq!(paths(&repo, simulation.trees[0]), ["b.txt", "base.txt"]);
+        let onto = repo.repo.find_commit(base).unwrap().tree_id();
+        assert_eq!(simulation.stats(&repo.repo, onto).unwrap(), [(2, 0)]);
+    }
+
+    #[test]
+    fn test_simulated_conflict_leaves_refs_alone() {
+        let repo = TempRepo::new("simulate-conflict");
+        let base = repo.commit("x.txt", "1\n", "base");
+        let a = repo.commit("x.txt", "2\n", "a");
+        let b = repo.commit("x.txt", "3\n", "b");
+        let todos = todos(&[(b, Action::Pick), (a, Action::Pick)]);
+
+        let simulation = simulate(&repo, base, &todos);
+        assert!(simulation.trees.is_empty());
+        assert_eq!(simulation.conflict, Some((0, vec![PathBuf::from("x.txt")])));
+
+        let error = apply(&repo.repo, base, &todos, &Metadata::default()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RewriteError>(),
+            Some(RewriteError::Conflict { position: 0, .. })
+        ));
+        assert_eq!(repo.repo.head().unwrap().target(), Some(b));
+        assert!(!rewritten_path(&repo.repo).exists());
+    }
+
+    #[test]
+    fn test_apply_matches_the_simulation() {
+        let (repo, base, a, b) = plan_repo("simulate-apply");
+        let c = repo.commit("c.txt", "c\n", "c");
+        let todos = todos(&[(c, Action::Pick), (b, Action::Squash), (a, Action::Drop)]);
+        let simulation = simulate(&repo, base, &todos);
+
+        apply(&repo.repo, base, &todos, &Metadata::default()).unwrap();
+        let head = repo.repo.head().unwrap().target().unwrap();
+        let applied = commits_between(&repo.repo, base, head)
+            .unwrap()
+            .into_iter()
+            .map(|id| repo.repo.find_commit(id).unwrap().tree_id())
+            .collect::<Vec<_>>();
+        assert_eq!(applied, simulation.trees[1..]);
+    }
+
+    /// A repository on `main` with two commits and an uncommitted change to
+    /// `notes.txt`, which only the first commit touches.
+    fn dirty_repo(name: &str) -> (TempRepo, Oid) {
+        let repo = TempRepo::new(name);
+        repo.commit("notes.txt", "notes\n", "notes");
+        let first = repo.commit("a.txt", "a\n", "a");
+        repo.commit("a.txt", "a\nb\n", "b");
+        fs::write(repo.path().join("notes.txt"), "notes\nmore\n").unwrap();
+        (repo, first)
+    }
+
+    fn reset_to(repo: &Repository, id: Oid) -> anyhow::Result<()> {
+        let commit = repo.find_commit(id)?;
+        update_head(repo, Some("refs/heads/main"), &commit)
+    }
+
+    #[test]
+    fn test_autostash_round_trip() {
+        let (repo, first) = dirty_repo("autostash");
+        repo.repo
+            .config()
+            .unwrap()
+            .set_bool("rebased.autoStash", true)
+            .unwrap();
+
+        let ((), warning) = with_autostash(&repo.repo, || reset_to(&repo.repo, first)).unwrap();
+        assert_eq!(warning, None);
+        assert_eq!(repo.repo.head().unwrap().target(), Some(first));
+        assert_eq!(
+            fs::read_to_string(repo.path().join("a.txt")).unwrap(),
+            "a\n"
+        );
+        assert_eq!(
+            fs::read_to_string(repo.path().join("notes.txt")).unwrap(),
+            "notes\nmore\n"
+        );
+        assert!(repo.repo.revparse_single("refs/stash").is_err());
+    }
+
+    #[test]
+    fn test_dirty_tree_is_refused() {
+        let (repo, first) = dirty_repo("autostash-refused");
+        let head = repo.repo.head().unwrap().target();
+
+        let error = with_autostash(&repo.repo, || reset_to(&repo.repo, first)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "uncommitted changes would be overwritten, commit or stash them first: notes.txt"
+        );
+        assert_eq!(repo.repo.head().unwrap().target(), head);
+    }
+
+    #[test]
+    fn test_autostash_keeps_the_stash_when_both_fail() {
+        let (repo, _) = dirty_repo("autostash-both");
+        repo.repo
+            .config()
+            .unwrap()
+            .set_bool("rebased.autoStash", true)
+            .unwrap();
+
+        // The rewrite fails after leaving a change the stash conflicts with.
+        let error = with_autostash::<()>(&repo.repo, || {
+            fs::write(repo.path().join("notes.txt"), "other\n").unwrap();
+            anyhow::bail!("rewrite failed")
+        })
+        .unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(
+            message.contains("they are kept in stash@{0}"),
+            "{}",
+            message
+        );
+        assert!(message.ends_with(": rewrite failed"), "{}", message);
+        assert!(repo.repo.revparse_single("refs/stash").is_ok());
+    }
+
+    #[test]
+    fn test_rewrite_is_recorded_when_the_stash_does_not_apply() {
+        let repo = TempRepo::new("autostash-pop");
+        let base = repo.commit("base.txt", "base\n", "base");
+        let notes = repo.commit("notes.txt", "notes\n", "notes");
+        let a = repo.commit("a.txt", "a\n", "a");
+        let edit = repo.commit("notes.txt", "edited\n", "edit");
+        fs::write(repo.path().join("notes.txt"), "dirty\n").unwrap();
+        repo.repo
+            .config()
+            .unwrap()
+            .set_bool("rebased.autoStash", true)
+            .unwrap();
+
+        // Dropping the edit the stashed change was made on makes the pop fail.
+        let todos = todos(&[
+            (a, Action::Pick),
+            (notes, Action::Pick),
+            (edit, Action::Drop),
+        ]);
+        let warning = apply(&repo.repo, base, &todos, &Metadata::default()).unwrap();
+        assert!(
+            warning
+                .as_deref()
+                .is_some_and(|warning| warning.contains("(conflicts in notes.txt)")),
+            "{:?}",
+            warning
+        );
+        let head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("notes"));
+        assert_eq!(
+            load_rewritten(&repo.repo).unwrap().get(&notes).copied(),
+            Some(head.id())
+        );
+        assert!(fs::read_to_string(repo.path().join("notes.txt"))
+            .unwrap()
+            .contains("dirty\n"));
+        assert!(repo.repo.revparse_single("refs/stash").is_ok());
+    }
+
+    #[test]
+    fn test_post_rewrite_hook_is_told_about_amends() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repo = TempRepo::new("post-rewrite");
+        repo.commit("base.txt", "base\n", "base");
+        let a = repo.commit("a.txt", "a\n", "a");
+        repo.commit("b.txt", "b\n", "b");
+        let hook = repo.repo.path().join("hooks/post-rewrite");
+        fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        // The hook runs in the working tree, so the log ends up there.
+        fs::write(&hook, "#!/bin/sh\necho \"$1\" >> hook.log\n").unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let stop = stop_at(&repo.repo, a).unwrap();
+        fs::write(repo.path().join("a.txt"), "a\namended\n").unwrap();
+        let warning = resume(&repo.repo, &stop, &Metadata::default()).unwrap();
+        assert_eq!(warning, None);
+        assert_eq!(
+            fs::read_to_string(repo.path().join("hook.log")).unwrap(),
+            "amend\nrebase\n"
+        );
+    }
+
+    #[test]
+    fn test_resume_leaves_untracked_files_alone() {
+        let repo = TempRepo::new("resume-untracked");
+        repo.commit("base.txt", "base\n", "base");
+        let a = repo.commit("a.txt", "a\n", "a");
+        repo.commit("b.txt", "b\n", "b");
+
+        let stop = stop_at(&repo.repo, a).unwrap();
+        fs::write(repo.path().join("a.txt"), "a\namended\n").unwrap();
+        fs::write(repo.path().join("staged.txt"), "staged\n").unwrap();
+        fs::write(repo.path().join("scratch.txt"), "scratch\n").unwrap();
+        let mut index = repo.repo.index().unwrap();
+        index.add_path(std::path::Path::new("staged.txt")).unwrap();
+        index.write().unwrap();
+        resume(&repo.repo, &stop, &Metadata::default()).unwrap();
+
+        let head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        let amended = head.parent(0).unwrap();
+        assert_eq!(amended.summary(), Some("a"));
+        let tree = amended.tree().unwrap();
+        let blob = tree
+            .get_name("a.txt")
+            .unwrap()
+            .to_object(&repo.repo)
+            .unwrap();
+        assert_eq!(blob.as_blob().unwrap().content(), b"a\namended\n");
+        assert!(tree.get_name("staged.txt").is_some());
+        assert!(head.tree().unwrap().get_name("scratch.txt").is_none());
+        assert!(repo.path().join("scratch.txt").exists());
+    }
+
+    /// Stages `content` as `path` without touching the working tree.
+    fn stage(repo: &TempRepo, path: &str, content: &str) {
+        let mut index = repo.repo.index().unwrap();
+        let blob = repo.repo.blob(content.as_bytes()).unwrap();
+        let mut entry = index.get_path(std::path::Path::new(path), 0).unwrap();
+        entry.id = blob;
+        entry.file_size = content.len() as u32;
+        index.add(&entry).unwrap();
+        index.write().unwrap();
+    }
+
+    fn index_tree(repo: &TempRepo) -> Oid {
+        repo.repo.index().unwrap().write_tree().unwrap()
+    }
+
+    #[test]
+    fn test_insert_after_with_nothing_staged() {
+        let repo = TempRepo::new("insert-empty");
+        repo.commit("base.txt", "base\n", "base");
+        let a = repo.commit("a.txt", "a\n", "a");
+        let b = repo.commit("b.txt", "b\n", "b");
+
+        let warning = insert_after(&repo.repo, a, "empty\n\n", &Metadata::default()).unwrap();
+        assert_eq!(warning, None);
+        let head = repo.repo.head().unwrap();
+        assert_eq!(head.name(), Some("refs/heads/main"));
+        let tip = head.peel_to_commit().unwrap();
+        let inserted = tip.parent(0).unwrap();
+        assert_eq!(tip.summary(), Some("b"));
+        assert_eq!(tip.tree_id(), repo.repo.find_commit(b).unwrap().tree_id());
+        assert_eq!(inserted.message(), Some("empty\n"));
+        assert_eq!(inserted.parent_id(0).unwrap(), a);
+        assert_eq!(
+            inserted.tree_id(),
+            repo.repo.find_commit(a).unwrap().tree_id()
+        );
+        assert_eq!(index_tree(&repo), tip.tree_id());
+    }
+
+    #[test]
+    fn test_insert_after_resets_the_index_to_the_new_tip() {
+        let repo = TempRepo::new("insert-staged");
+        repo.commit("base.txt", "base\n", "base");
+        let a = repo.commit("a.txt", "a\n", "a");
+        repo.commit("b.txt", "b\n", "b");
+        stage(&repo, "a.txt", "a\nstaged\n");
+        fs::write(repo.path().join("a.txt"), "a\nstaged\nunstaged\n").unwrap();
+
+        insert_after(&repo.repo, a, "staged", &Metadata::default()).unwrap();
+        let tip = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        let inserted = tip.parent(0).unwrap();
+        assert_eq!(inserted.summary(), Some("staged"));
+        let blob = inserted
+            .tree()
+            .unwrap()
+            .get_name("a.txt")
+            .unwrap()
+            .to_object(&repo.repo)
+            .unwrap();
+        assert_eq!(blob.as_blob().unwrap().content(), b"a\nstaged\n");
+        assert_eq!(index_tree(&repo), tip.tree_id());
+        // What was not staged stays in the working tree.
+        assert_eq!(
+            fs::read_to_string(repo.path().join("a.txt")).unwrap(),
+            "a\nstaged\nunstaged\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_after_conflicts() {
+        let repo = TempRepo::new("insert-conflict");
+        repo.commit("base.txt", "base\n", "base");
+        let a = repo.commit("x.txt", "1\n", "a");
+        let b = repo.commit("x.txt", "2\n", "b");
+        let c = repo.commit("x.txt", "1\n", "c");
+        stage(&repo, "x.txt", "3\n");
+        let staged = index_tree(&repo);
+
+        let error = insert_after(&repo.repo, b, "staged", &Metadata::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("staged changes do not apply on top of {:.8} b", b)
+        );
+        // After `a`, the staged change applies but `b` no longer does.
+        let error = insert_after(&repo.repo, a, "staged", &Metadata::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("staged changes conflict with {:.8} b", b)
+        );
+        assert_eq!(repo.repo.head().unwrap().target(), Some(c));
+        assert_eq!(index_tree(&repo), staged);
+    }
+
+    #[test]
+    fn test_failing_to_record_is_a_warning() {
+        let repo = TempRepo::new("record-warning");
+        let base = repo.commit("base.txt", "base\n", "base");
+        let a = repo.commit("a.txt", "a\n", "a");
+        let b = repo.commit("b.txt", "b\n", "b");
+        // A directory where the log should be makes appending to it fail.
+        fs::create_dir_all(rewritten_path(&repo.repo)).unwrap();
+
+        let todos = [
+            Todo {
+                commit: b,
+                action: Action::Pick,
+            },
+            Todo {
+                commit: a,
+                action: Action::Pick,
+            },
+        ];
+        let warning = apply(&repo.repo, base, &todos, &Metadata::default()).unwrap();
+        assert!(
+            warning
+                .as_deref()
+                .is_some_and(|warning| warning
+                    .starts_with("the rewrite was applied, but recording it failed")),
+            "{:?}",
+            warning
+        );
+        let head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("a"));
+        assert_eq!(head.parent(0).unwrap().summary(), Some("b"));
+    }
+
+    #[test]
+    fn test_rewrites_are_shared_between_worktrees() {
+        let repo = TempRepo::new("shared-state");
+        let base = repo.commit("base.txt", "base\n", "base");
+        repo.switch("stack");
+        let a = repo.commit("a.txt", "a\n", "a");
+        let b = repo.commit("b.txt", "b\n", "b");
+        repo.repo.set_head("refs/heads/main").unwrap();
+
+        let dir = crate::testing::TempDir::new("shared-state-worktree");
+        let stack = repo.repo.find_reference("refs/heads/stack").unwrap();
+        repo.repo
+            .worktree(
+                "stack",
+                &dir.path().join("stack"),
+                Some(git2::WorktreeAddOptions::new().reference(Some(&stack))),
+            )
+            .unwrap();
+        let linked = Repository::open(dir.path().join("stack")).unwrap();
+
+        let todos = [
+            Todo {
+                commit: b,
+                action: Action::Pick,
+            },
+            Todo {
+                commit: a,
+                action: Action::Pick,
+            },
+        ];
+        assert_eq!(
+            apply(&linked, base, &todos, &Metadata::default()).unwrap(),
+            None
+        );
+        let rewritten = load_rewritten(&repo.repo).unwrap();
+        assert_eq!(rewritten.len(), 2);
+        assert_eq!(rewritten, load_rewritten(&linked).unwrap());
+        assert_eq!(rewritten.get(&a).copied(), linked.head().unwrap().target());
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::Context;
+use git2::Config;
+use git2::Oid;
+use git2::Repository;
+
+use crate::rewrite::state_dir;
+
+// MARK: Format
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+// MARK: Signer
+
+/// Signs commit buffers the way git does when `commit.gpgsign` is set,
+/// honouring `gpg.format`, `gpg.program`, `gpg.<format>.program` and
+/// `user.signingkey`.
+pub struct Signer {
+    format: Format,
+    program: String,
+    key: Option<String>,
+    state: PathBuf,
+}
+
+impl Signer {
+    pub fn from_config(repo: &Repository) -> anyhow::Result<Option<Self>> {
+        let config = repo.config().context("failed to open git config")?;
+        if !config.get_bool("commit.gpgsign").unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let format = match config.get_string("gpg.format").ok().as_deref() {
+            None | Some("openpgp") => Format::OpenPgp,
+            Some("x509") => Format::X509,
+            Some("ssh") => Format::Ssh,
+            Some(other) => anyhow::bail!("unsupported gpg.format {}", other),
+        };
+        let program = match format {
+            Format::OpenPgp => get(&config, &["gpg.openpgp.program", "gpg.program"]),
+            Format::X509 => get(&config, &["gpg.x509.program"]),
+            Format::Ssh => get(&config, &["gpg.ssh.program"]),
+        }
+        .unwrap_or_else(|| {
+            match format {
+                Format::OpenPgp => "gpg",
+                Format::X509 => "gpgsm",
+                Format::Ssh => "ssh-keygen",
+            }
+            .to_owned()
+        });
+        let key = get(&config, &["user.signingkey"]);
+        if format == Format::Ssh && key.is_none() {
+            anyhow::bail!("gpg.format is ssh but user.signingkey is not set");
+        }
+
+        Ok(Some(Self {
+            format,
+            program,
+            key,
+            state: state_dir(repo),
+        }))
+    }
+
+    /// Produces a detached signature of `buffer`. `identity` is used as the
+    /// key for OpenPGP and X.509 when no signing key is configured, like git
+    /// does with the committer identity.
+    pub fn sign(&self, buffer: &str, identity: &str) -> anyhow::Result<String> {
+        match self.format {
+            Format::OpenPgp | Format::X509 => {
+                let key = self.key.as_deref().unwrap_or(identity);
+                self.run(
+                    Command::new(&self.program).args(["--status-fd=2", "-bsau", key]),
+                    buffer,
+                )
+            }
+            Format::Ssh => self.sign_ssh(buffer),
+        }
+    }
+
+    fn sign_ssh(&self, buffer: &str) -> anyhow::Result<String> {
+        fs::create_dir_all(&self.state).context("failed to create state directory")?;
+        let key = self.key.as_deref().unwrap_or_default();
+        let literal = key
+            .strip_prefix("key::")
+            .or_else(|| key.starts_with("ssh-").then_some(key));
+
+        let mut command = Command::new(&self.program);
+        command.args(["-Y", "sign", "-n", "git", "-f"]);
+        let key_path = match literal {
+            Some(literal) => {
+                let path = self.state.join("signing-key.pub");
+                fs::write(&path, literal).context("failed to write signing key")?;
+                command.arg(&path).arg("-U");
+                Some(path)
+            }
+            None => {
+                command.arg(expand_home(key));
+                None
+            }
+        };
+
+        let buffer_path = self.state.join("signing-buffer");
+        fs::write(&buffer_path, buffer).context("failed to write signing buffer")?;
+        let result = self.run(command.arg(&buffer_path), "").and_then(|_| {
+            let signature_path = buffer_path.with_extension("sig");
+            let signature =
+                fs::read_to_string(&signature_path).context("failed to read ssh signature");
+            let _ = fs::remove_file(&signature_path);
+            signature
+        });
+
+        let _ = fs::remove_file(&buffer_path);
+        if let Some(key_path) = key_path {
+            let _ = fs::remove_file(key_path);
+        }
+        result
+    }
+
+    fn run(&self, command: &mut Command, input: &str) -> anyhow::Result<String> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run signing program {}", self.program))?;
+        child
+            .stdin
+            .take()
+            .context("failed to open signing program stdin")?
+            .write_all(input.as_bytes())
+            .context("failed to write to signing program")?;
+
+        let output = child
+            .wait_with_output()
+            .context("failed to wait for signing program")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "{} failed to sign the commit: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        String::from_utf8(output.stdout).context("signature is not valid UTF-8")
+    }
+}
+
+fn get(config: &Config, names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| config.get_string(name).ok())
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+pub fn is_signed(repo: &Repository, commit: Oid) -> bool {
+    repo.extract_signature(&commit, None).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TempRepo;
+
+    fn signer(repo: &TempRepo, settings: &[(&str, &str)]) -> anyhow::Result<Option<Signer>> {
+        let mut config = repo.repo.config().unwrap();
+        config.set_bool("commit.gpgsign", true).unwrap();
+        for (name, value) in settings {
+            config.set_str(name, value).unwrap();
+        }
+        Signer::from_config(&repo.repo)
+    }
+
+    #[test]
+    fn test_from_config_needs_gpgsign() {
+        let repo = TempRepo::new("signer-off");
+        assert!(Signer::from_config(&repo.repo).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_from_config_resolves_the_format_and_program() {
+        for (settings, format, program) in [
+            (&[][..], Format::OpenPgp, "gpg"),
+            (&[("gpg.program", "gpg2")][..], Format::OpenPgp, "gpg2"),
+            (
+                &[("gpg.program", "gpg2"), ("gpg.openpgp.program", "gpg3")][..],
+                Format::OpenPgp,
+                "gpg3",
+            ),
+            (&[("gpg.format", "x509")][..], Format::X509, "gpgsm"),
+            (
+                &[("gpg.format", "x509"), ("gpg.program", "gpg2")][..],
+                Format::X509,
+                "gpgsm",
+            ),
+            (
+                &[("gpg.format", "ssh"), ("user.signingkey", "~/.ssh/id.pub")][..],
+                Format::Ssh,
+                "ssh-keygen",
+            ),
+            (
+                &[
+                    ("gpg.format", "ssh"),
+                    ("user.signingkey", "~/.ssh/id.pub"),
+                    ("gpg.ssh.program", "my-keygen"),
+                ][..],
+                Format::Ssh,
+                "my-keygen",
+            ),
+        ] {
+            let repo = TempRepo::new("signer-config");
+            let signer = signer(&repo, settings).unwrap().unwrap();
+            assert_eq!(signer.format, format, "{:?}", settings);
+            assert_eq!(signer.program, program, "{:?}", settings);
+        }
+    }
+
+    #[test]
+    fn test_from_config_refuses_bad_settings() {
+        let repo = TempRepo::new("signer-unsupported");
+        assert!(signer(&repo, &[("gpg.format", "pgp")]).is_err());
+        let repo = TempRepo::new("signer-ssh-without-key");
+        assert!(signer(&repo, &[("gpg.format", "ssh")]).is_err());
+    }
+
+    #[test]
+    fn test_sign_ssh_with_a_literal_key() {
+        let repo = TempRepo::new("signer-ssh");
+        // Stands in for ssh-keygen, signing with the key and the buffer.
+        let program = repo.path().join("keygen.sh");
+        fs::write(
+            &program,
+            "#!/bin/sh\n\
+             while [ $# -gt 1 ]; do [ \"$1\" = -f ] && key=$2; shift; done\n\
+             buffer=$1\n\
+             cat \"$key\" \"$buffer\" > \"$buffer.sig\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        let signer = signer(
+            &repo,
+            &[
+                ("gpg.format", "ssh"),
+                ("user.signingkey", "ssh-ed25519 AAAA tester\n"),
+                ("gpg.ssh.program", program.to_str().unwrap()),
+            ],
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            signer.sign("buffer\n", "Tester").unwrap(),
+            "ssh-ed25519 AAAA tester\nbuffer\n"
+        );
+        // The key and buffer are only written for the duration of the call.
+        assert_eq!(fs::read_dir(&signer.state).unwrap().count(), 0);
+    }
+}
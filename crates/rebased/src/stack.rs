@@ -1,31 +1,47 @@
 use anyhow::Context;
-use crossterm::event::{Event, KeyCode, KeyModifiers};
-use git2::{Commit, Delta, Deltas, Diff, DiffDelta, DiffFlags, FileMode, Oid};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::event::{MouseButton, MouseEvent, MouseEventKind};
+use git2::{Commit, Delta, Diff, DiffDelta, Oid};
 use git2::{DiffFile, Repository};
 use ratatui::buffer::Buffer;
-use ratatui::layout::{Alignment, Constraint, Layout, Rect};
+use ratatui::layout::{Alignment, Constraint, Flex, Layout, Position, Rect};
 use ratatui::prelude::{StatefulWidget, Style, Stylize, Widget};
-use ratatui::text::{Line, Text, ToLine, ToSpan};
-use ratatui::widgets::{Block, BorderType, Paragraph, StatefulWidgetRef, WidgetRef};
+use ratatui::text::{Line, Span, Text, ToSpan};
+use ratatui::widgets::{Block, BorderType, Clear, Padding, Paragraph, StatefulWidgetRef};
 use ratatui::{DefaultTerminal, Frame};
-use ratatui_tree::{tree_index, Tree, TreeIndex, TreeItem, TreeState, TreeView};
-use std::collections::VecDeque;
-use std::ops::{AddAssign, Deref, Index, SubAssign};
+use ratatui_diff::{DiffLine, DiffState, LineKind};
+use ratatui_tree::{Tree, TreeIndex, TreeItem, TreeState, TreeView};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Index;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
-use std::sync::RwLock;
+use std::process::Command;
+
+use crate::branches;
+use crate::branches::Branches;
+use crate::check;
+use crate::check::{Rules, Warning};
+use crate::describe;
+use crate::forge;
+use crate::forge::{Forge, ReviewStatus};
+use crate::keymap;
+use crate::keymap::Keymap;
+use crate::push;
+use crate::rangediff;
+use crate::rewrite;
+use crate::rewrite::{Action, Metadata, Plan, Stop, Todo};
+use crate::theme::Theme;
+use crate::worktree;
+use crate::worktree::Worktree;
 
 // MARK: Extra
 
 struct StackCommitDeltaFile {
-    mode: FileMode,
     path: Option<PathBuf>,
 }
 
 impl<'a> From<DiffFile<'a>> for StackCommitDeltaFile {
     fn from(file: DiffFile<'a>) -> Self {
         Self {
-            mode: file.mode(),
             path: file.path().map(PathBuf::from),
         }
     }
@@ -34,32 +50,37 @@ impl<'a> From<DiffFile<'a>> for StackCommitDeltaFile {
 // MARK: Delta
 
 struct DeltaNode {
-    status: Delta,
     new_file: StackCommitDeltaFile,
     old_file: StackCommitDeltaFile,
-    flags: DiffFlags,
+    is_match: bool,
+    is_hidden: bool,
 }
 
 impl From<DiffDelta<'_>> for DeltaNode {
     fn from(delta: DiffDelta<'_>) -> Self {
         Self {
-            status: delta.status(),
             new_file: delta.new_file().into(),
             old_file: delta.old_file().into(),
-            flags: delta.flags(),
+            is_match: false,
+            is_hidden: false,
         }
     }
 }
 
-impl<'a> Into<TreeItem<'a>> for &'a DeltaNode {
-    fn into(self) -> TreeItem<'a> {
+impl DeltaNode {
+    fn item(&self, theme: &Theme) -> TreeItem<'_> {
         let path = self
             .new_file
             .path
             .as_deref()
             .and_then(Path::to_str)
             .unwrap_or("?");
-        TreeItem::new_empty(Line::from(path))
+        let label = if self.is_match {
+            Line::from(Span::styled(path, theme.search))
+        } else {
+            Line::from(path)
+        };
+        TreeItem::new_empty(label).hidden(self.is_hidden)
     }
 }
 
@@ -70,6 +91,12 @@ struct CommitNode<'repo> {
     diff: Option<Diff<'repo>>,
     deltas: Vec<Node<'repo>>,
     is_collapsed: bool,
+    is_stopped: bool,
+    branch: Option<String>,
+    review: Option<ReviewStatus>,
+    warnings: Vec<Warning>,
+    is_match: bool,
+    is_hidden: bool,
 }
 
 impl<'repo> CommitNode<'repo> {
@@ -80,22 +107,6 @@ impl<'repo> CommitNode<'repo> {
     pub fn get_mut(&mut self, index: usize) -> Option<&mut DeltaNode> {
         self.deltas.get_mut(index).map(Node::unwrap_delta_mut)
     }
-
-    pub fn push<T: Into<DeltaNode>>(&mut self, delta: T) {
-        self.deltas.push(Node::Delta(delta.into()));
-    }
-
-    pub fn clear(&mut self) {
-        self.deltas.clear();
-    }
-
-    pub fn len(&self) -> usize {
-        self.deltas.len()
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.deltas.is_empty()
-    }
 }
 
 impl<'repo> From<Commit<'repo>> for CommitNode<'repo> {
@@ -105,12 +116,18 @@ impl<'repo> From<Commit<'repo>> for CommitNode<'repo> {
             diff: None,
             deltas: Vec::new(),
             is_collapsed: true,
+            is_stopped: false,
+            branch: None,
+            review: None,
+            warnings: Vec::new(),
+            is_match: false,
+            is_hidden: false,
         }
     }
 }
 
-impl<'a, 'repo> Into<TreeItem<'a>> for &'a CommitNode<'repo> {
-    fn into(self) -> TreeItem<'a> {
+impl<'repo> CommitNode<'repo> {
+    fn item(&self, theme: &Theme) -> TreeItem<'_> {
         let icon: &'static str = if self.is_collapsed { " + " } else { " - " };
         let mut hash = self.commit.id().to_string();
         hash.truncate(8);
@@ -119,11 +136,38 @@ impl<'a, 'repo> Into<TreeItem<'a>> for &'a CommitNode<'repo> {
             .message()
             .and_then(|message| message.lines().next())
             .unwrap_or("");
-        let label = Line::from(vec![icon.into(), hash.into(), " ".into(), message.into()]);
+        let mut label = Line::from(vec![
+            icon.into(),
+            Span::styled(hash, theme.hash),
+            " ".into(),
+        ]);
+        if let Some(review) = self.review {
+            label.push_span(match review {
+                ReviewStatus::Open => "○ ".into(),
+                ReviewStatus::Approved => Span::styled("✔ ", theme.added),
+                ReviewStatus::Merged => Span::styled("● ", theme.decoration),
+                ReviewStatus::Closed => Span::styled("✘ ", theme.removed),
+            });
+        }
+        if !self.warnings.is_empty() {
+            label.push_span(Span::styled("⚠ ", theme.warning));
+        }
+        if self.is_match {
+            label.push_span(Span::styled(message, theme.search));
+        } else {
+            label.push_span(message);
+        }
+        if let Some(branch) = &self.branch {
+            label.push_span(Span::styled(format!(" {}", branch), theme.decoration));
+        }
+        if self.is_stopped {
+            label.push_span(Span::styled(" (editing)", theme.warning));
+        }
+        let deltas = self.deltas.iter().map(|delta| delta.item(theme));
         if self.is_collapsed {
-            TreeItem::new_empty(label)
+            TreeItem::new_empty(label).hidden(self.is_hidden)
         } else {
-            TreeItem::new(label, self.deltas.iter())
+            TreeItem::new(label, deltas).hidden(self.is_hidden)
         }
     }
 }
@@ -185,11 +229,11 @@ impl<'repo> From<Commit<'repo>> for Node<'repo> {
     }
 }
 
-impl<'a, 'repo> Into<TreeItem<'a>> for &'a Node<'repo> {
-    fn into(self) -> TreeItem<'a> {
+impl<'repo> Node<'repo> {
+    fn item(&self, theme: &Theme) -> TreeItem<'_> {
         match self {
-            Node::Delta(delta) => delta.into(),
-            Node::Commit(commit) => commit.into(),
+            Node::Delta(delta) => delta.item(theme),
+            Node::Commit(commit) => commit.item(theme),
         }
     }
 }
@@ -203,24 +247,47 @@ impl<'repo> TreeView<Node<'repo>> for Node<'repo> {
     fn iter_children(&self) -> Self::ChildIter<'_> {
         match self {
             Node::Delta(_) => std::slice::Iter::default(),
+            // Keep the selection out of the files of a collapsed commit.
+            Node::Commit(commit) if commit.is_collapsed => std::slice::Iter::default(),
             Node::Commit(commit) => commit.deltas.iter(),
         }
     }
+
+    fn is_hidden(&self) -> bool {
+        match self {
+            Node::Delta(delta) => delta.is_hidden,
+            Node::Commit(commit) => commit.is_hidden,
+        }
+    }
 }
 
 // MARK: Stack
 
 struct StackTree<'repo> {
     commits: Vec<Node<'repo>>,
+    merge_base: Option<Oid>,
+    stop: Option<Stop>,
 }
 
 impl<'repo> StackTree<'repo> {
     pub fn new() -> Self {
         Self {
             commits: Vec::new(),
+            merge_base: None,
+            stop: None,
         }
     }
 
+    pub fn todos(&self) -> Vec<Todo> {
+        self.commits
+            .iter()
+            .map(|node| Todo {
+                commit: node.unwrap_commit_ref().commit.id(),
+                action: Action::Pick,
+            })
+            .collect()
+    }
+
     pub fn get(&self, index: usize) -> Option<&CommitNode<'repo>> {
         self.commits.get(index).map(Node::unwrap_commit_ref)
     }
@@ -244,6 +311,13 @@ impl<'repo> StackTree<'repo> {
     pub fn is_empty(&self) -> bool {
         self.commits.is_empty()
     }
+
+    /// The commits as a tree widget, without the block around it.
+    pub fn tree(&self, theme: &Theme) -> Tree<'_> {
+        Tree::new(self.commits.iter().map(|node| node.item(theme)))
+            .indent_symbol("    ")
+            .highlight_style(theme.selection)
+    }
 }
 
 impl<'repo> Index<usize> for StackTree<'repo> {
@@ -265,88 +339,403 @@ impl<'repo> TreeView<Node<'repo>> for StackTree<'repo> {
     }
 }
 
+// MARK: Plan
+
+/// Rewrites staged against the stack but not yet applied.
+struct PendingPlan {
+    todos: Vec<Todo>,
+    marked: Option<Oid>,
+}
+
+impl PendingPlan {
+    fn position(&self, commit: Oid) -> Option<usize> {
+        self.todos.iter().position(|todo| todo.commit == commit)
+    }
+}
+
 // MARK: Model
 
 struct Model<'repo> {
     repo: &'repo Repository,
+    worktrees: &'repo [Worktree],
+    metadata: Metadata,
+    forge: Option<Box<dyn Forge>>,
+    rules: Rules,
+    base: String,
     stack: StackTree<'repo>,
+    plan: Option<PendingPlan>,
     tree: TreeState,
-    preview: Paragraph<'static>,
+    preview: ratatui_diff::Diff<'static>,
+    preview_state: DiffState,
+    /// The commit and file last shown by following the selection.
+    previewed: Option<(Oid, Option<usize>)>,
+    preview_cache: HashMap<(Oid, Option<usize>), ratatui_diff::Diff<'static>>,
+    /// The added and removed lines of each file of a commit, so searching the
+    /// diffs again as the query is typed does not diff every file again.
+    changed_lines: HashMap<Oid, Vec<Vec<String>>>,
+    search: Option<Search>,
+    filter: Option<Filter>,
+    theme: Theme,
 }
 
 impl<'repo> Model<'repo> {
-    pub fn new(repo: &'repo Repository) -> Self {
+    pub fn new(
+        repo: &'repo Repository,
+        worktrees: &'repo [Worktree],
+        metadata: Metadata,
+        forge: Option<Box<dyn Forge>>,
+        rules: Rules,
+        theme: Theme,
+    ) -> Self {
         Self {
             repo,
+            worktrees,
+            metadata,
+            forge,
+            rules,
+            base: String::new(),
             stack: StackTree::new(),
+            plan: None,
             tree: TreeState::new(),
-            preview: Paragraph::new(""),
+            preview: ratatui_diff::Diff::default(),
+            preview_state: DiffState::new(),
+            previewed: None,
+            preview_cache: HashMap::new(),
+            changed_lines: HashMap::new(),
+            search: None,
+            filter: None,
+            theme,
         }
     }
 
     fn load_commits_since_merge_base_with(&mut self, base: &str) -> anyhow::Result<()> {
-        let base_id = self
-            .repo
-            .resolve_reference_from_short_name(base)
-            .with_context(|| format!("failed to resolve base commit {}", base))?
-            .target()
-            .ok_or_else(|| anyhow::format_err!("no target OID for reference {}", base))?;
-        let head_id = self
-            .repo
-            .head()
-            .context("failed to resolve HEAD")?
-            .target()
-            .ok_or_else(|| anyhow::format_err!("no target OID for HEAD"))?;
-        let merge_base_id = self
-            .repo
-            .merge_base(head_id, base_id)
-            .with_context(|| format!("failed to resolve merge base between {} and HEAD", base))?;
+        let stop = Stop::load(self.repo)?;
+        let head_id = match &stop {
+            Some(stop) => stop.head,
+            None => self
+                .repo
+                .head()
+                .context("failed to resolve HEAD")?
+                .target()
+                .ok_or_else(|| anyhow::format_err!("no target OID for HEAD"))?,
+        };
+        let merge_base_id = rewrite::merge_base_with(self.repo, base, head_id)?;
 
         let mut revwalk = self
             .repo
             .revwalk()
             .context("failed to construct a revision walk")?;
         revwalk
-            .push_head()
+            .push(head_id)
             .context("failed to push HEAD onto the revision walk")?;
         revwalk
             .set_sorting(git2::Sort::TOPOLOGICAL)
             .context("failed to sort the revision walk topologically")?;
 
         self.stack.clear();
+        self.preview_cache.clear();
+        self.changed_lines.clear();
+        self.search = None;
+        let mut commits = Vec::new();
         for result in revwalk {
             let id = result.context("failed to retrieve commit from revwalk")?;
             if id == merge_base_id {
                 break;
             }
-            let commit = self
-                .repo
-                .find_commit(id)
-                .with_context(|| format!("failed to find commit {}", id))?;
-            self.stack.push(commit);
+            commits.push(
+                self.repo
+                    .find_commit(id)
+                    .with_context(|| format!("failed to find commit {}", id))?,
+            );
+        }
+        let branches = Branches::load(self.repo)?.branches_of(self.repo, &commits);
+        // The forge is asked once for every branch rather than once a commit.
+        let mut reviews = vec![None; commits.len()];
+        if let Some(forge) = &self.forge {
+            let (indices, names): (Vec<_>, Vec<_>) = branches
+                .iter()
+                .enumerate()
+                .filter_map(|(index, branch)| Some((index, branch.as_deref()?)))
+                .unzip();
+            for (index, status) in indices.into_iter().zip(forge.statuses(&names)?) {
+                reviews[index] = status;
+            }
+        }
+
+        for ((commit, branch), review) in commits.into_iter().zip(branches).zip(reviews) {
+            let id = commit.id();
+            let warnings = check::check(self.repo, &commit, &self.rules)?;
+            let mut commit_node = CommitNode::from(commit);
+            commit_node.branch = branch;
+            commit_node.review = review;
+            commit_node.warnings = warnings;
+            commit_node.is_stopped = stop.as_ref().is_some_and(|stop| stop.commit == id);
+            self.stack.push(commit_node);
         }
 
         self.stack.commits.reverse();
+        self.stack.merge_base = Some(merge_base_id);
+        self.stack.stop = stop;
+        self.base = base.to_owned();
         if self.stack.is_empty() {
             self.tree.select(None)
-        } else {
+        } else if self.tree.selected().is_none() {
             self.tree.select(Some(TreeIndex::new(0)));
         }
+        self.apply_filter()
+    }
+
+    /// Replaces the preview, scrolled to the top.
+    fn set_preview(&mut self, preview: ratatui_diff::Diff<'static>) {
+        self.preview = preview;
+        self.preview_state.scroll_to_top();
+    }
 
+    fn reload(&mut self) -> anyhow::Result<()> {
+        let base = self.base.clone();
+        self.load_commits_since_merge_base_with(&base)
+    }
+
+    pub fn edit(&mut self, commit_index: usize) -> anyhow::Result<()> {
+        if self.stack.stop.is_none() {
+            let Some(commit_node) = self.stack.get(commit_index) else {
+                return Ok(());
+            };
+            rewrite::stop_at(self.repo, commit_node.commit.id())?;
+            self.reload()?;
+        }
         Ok(())
     }
 
-    pub fn toggle_deltas(&mut self, commit_index: usize) -> anyhow::Result<()> {
-        let Some(commit_node) = self.stack.get_mut(commit_index) else {
-            // state.status = format!("invalid commit index {}", index);
-            // TODO
+    pub fn resume(&mut self) -> anyhow::Result<Option<Status>> {
+        let Some(stop) = &self.stack.stop else {
+            return Ok(None);
+        };
+        let warning = rewrite::resume(self.repo, stop, &self.metadata)?;
+        self.reload()?;
+        Ok(warning.map(Status::warning))
+    }
+
+    pub fn abort(&mut self) -> anyhow::Result<()> {
+        if let Some(stop) = &self.stack.stop {
+            rewrite::abort(self.repo, stop)?;
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, commit_index: usize, message: &str) -> anyhow::Result<Option<Status>> {
+        if self.stack.stop.is_some() {
+            anyhow::bail!("cannot insert a commit while an edit is in progress");
+        }
+        let Some(commit_node) = self.stack.get(commit_index) else {
+            return Ok(None);
+        };
+        let warning =
+            rewrite::insert_after(self.repo, commit_node.commit.id(), message, &self.metadata)?;
+        self.reload()?;
+        self.tree.select(Some(TreeIndex::new(commit_index + 1)));
+        Ok(warning.map(Status::warning))
+    }
+
+    fn edit_plan<F: FnOnce(&mut PendingPlan, Oid)>(
+        &mut self,
+        commit_index: usize,
+        f: F,
+    ) -> anyhow::Result<()> {
+        if self.stack.stop.is_some() {
+            anyhow::bail!("cannot plan a rewrite while an edit is in progress");
+        }
+        let Some(commit_node) = self.stack.get(commit_index) else {
+            return Ok(());
+        };
+        let commit = commit_node.commit.id();
+        let plan = self.plan.get_or_insert_with(|| PendingPlan {
+            todos: self.stack.todos(),
+            marked: None,
+        });
+        f(plan, commit);
+
+        if plan.marked.is_none() && plan.todos == self.stack.todos() {
+            self.plan = None;
+            self.clear_preview();
+            return Ok(());
+        }
+        self.show_plan()
+    }
+
+    pub fn plan_swap(&mut self, commit_index: usize, up: bool) -> anyhow::Result<()> {
+        self.edit_plan(commit_index, |plan, commit| {
+            if let Some(position) = plan.position(commit) {
+                let other = if up {
+                    position.checked_sub(1)
+                } else {
+                    Some(position + 1).filter(|&other| other < plan.todos.len())
+                };
+                if let Some(other) = other {
+                    plan.todos.swap(position, other);
+                }
+            }
+        })
+    }
+
+    pub fn plan_toggle(&mut self, commit_index: usize, action: Action) -> anyhow::Result<()> {
+        self.edit_plan(commit_index, |plan, commit| {
+            if let Some(position) = plan.position(commit) {
+                let todo = &mut plan.todos[position];
+                todo.action = if todo.action == action {
+                    Action::Pick
+                } else {
+                    action
+                };
+            }
+        })
+    }
+
+    /// Marks the commit to be moved, or moves the marked commit right after
+    /// this one.
+    pub fn plan_move(&mut self, commit_index: usize) -> anyhow::Result<()> {
+        self.edit_plan(commit_index, |plan, commit| match plan.marked.take() {
+            None => plan.marked = Some(commit),
+            Some(marked) if marked == commit => {}
+            Some(marked) => {
+                if let Some(from) = plan.position(marked) {
+                    let todo = plan.todos.remove(from);
+                    let to = plan.position(commit).map_or(0, |to| to + 1);
+                    plan.todos.insert(to, todo);
+                }
+            }
+        })
+    }
+
+    pub fn push(&self) -> anyhow::Result<Status> {
+        let results = push::push_stack(self.repo, &self.base)?;
+        if results.is_empty() {
+            return Ok(Status::info("no branches to push"));
+        }
+        Ok(push_summary(&results))
+    }
+
+    pub fn push_branches(&mut self) -> anyhow::Result<Status> {
+        let branches = branches::sync(self.repo, &self.base)?;
+        let results = branches
+            .into_iter()
+            .map(|branch| {
+                let outcome = push::push_branch(self.repo, &branch);
+                (branch, outcome)
+            })
+            .collect::<Vec<_>>();
+        self.reload()?;
+        if results.is_empty() {
+            return Ok(Status::info("no commits in the stack"));
+        }
+        Ok(push_summary(&results))
+    }
+
+    pub fn discard_plan(&mut self) {
+        if self.plan.take().is_some() {
+            self.clear_preview();
+        }
+    }
+
+    pub fn apply_plan(&mut self) -> anyhow::Result<Option<Status>> {
+        let (Some(plan), Some(merge_base)) = (self.plan.as_ref(), self.stack.merge_base) else {
+            return Ok(None);
+        };
+        let warning = rewrite::apply(self.repo, merge_base, &plan.todos, &self.metadata)?;
+        self.discard_plan();
+        self.reload()?;
+        Ok(warning.map(Status::warning))
+    }
+
+    fn show_plan(&mut self) -> anyhow::Result<()> {
+        let (Some(plan), Some(merge_base)) = (self.plan.as_ref(), self.stack.merge_base) else {
+            return Ok(());
+        };
+
+        let mut lines = vec![
+            Line::from("Planned rewrite").bold(),
+            Line::from("Enter to apply, Esc to discard").italic(),
+            Line::from(""),
+        ];
+        // Dropped commits are skipped, so the first kept one has nothing to squash into.
+        let first_kept = plan.todos.iter().find(|todo| todo.action != Action::Drop);
+        if first_kept.map(|todo| todo.action) == Some(Action::Squash) {
+            lines.push(
+                Line::from("The first commit that is kept cannot be squashed")
+                    .style(self.theme.removed),
+            );
+            self.set_preview(ratatui_diff::Diff::new(lines));
             return Ok(());
+        }
+
+        let onto = self.repo.find_commit(merge_base)?;
+        let onto_tree = onto.tree_id();
+        let simulation = Plan::from_todos(self.repo, onto, &plan.todos)?.simulate(self.repo)?;
+        let stats = simulation.stats(self.repo, onto_tree)?;
+
+        let mut position = 0;
+        for todo in &plan.todos {
+            let commit = self.repo.find_commit(todo.commit)?;
+            let marker = if plan.marked == Some(todo.commit) {
+                "> "
+            } else if todo.action == Action::Squash {
+                "  └ "
+            } else {
+                "  "
+            };
+            let mut spans = vec![
+                Span::from(marker),
+                Span::from(format!("{:<6} ", todo.action.name())),
+                Span::styled(format!("{:.8} ", commit.id()), self.theme.hash),
+                Span::from(commit.summary().unwrap_or("").to_owned()),
+            ];
+
+            if todo.action == Action::Drop {
+                lines.push(Line::from(spans).dim());
+                continue;
+            }
+
+            match (simulation.trees.get(position), &simulation.conflict) {
+                (Some(&tree), _) => {
+                    let (insertions, deletions) = stats[position];
+                    spans.push(Span::styled(format!(" +{}", insertions), self.theme.added));
+                    spans.push(Span::styled(format!(" -{}", deletions), self.theme.removed));
+                    if tree != commit.tree_id() {
+                        spans.push(Span::styled(" (tree changes)", self.theme.hunk));
+                    }
+                }
+                (None, Some((conflict, paths))) if *conflict == position => {
+                    let paths = paths
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    spans.push(Span::styled(
+                        format!(" conflict in {}", paths),
+                        self.theme.removed.bold(),
+                    ));
+                }
+                _ => spans.push(Span::styled(" (not predicted)", self.theme.muted)),
+            }
+            lines.push(Line::from(spans));
+            position += 1;
+        }
+
+        self.set_preview(ratatui_diff::Diff::new(lines));
+        Ok(())
+    }
+
+    /// Diffs the commit against its parent, unless that was done already.
+    fn load_diff(&mut self, commit_index: usize) -> anyhow::Result<&CommitNode<'repo>> {
+        let Some(commit_node) = self.stack.get_mut(commit_index) else {
+            anyhow::bail!("invalid commit index {}", commit_index);
         };
 
         if commit_node.diff.is_none() {
             if commit_node.commit.parent_count() != 1 {
                 return Err(anyhow::format_err!(
-                    "commit {} has {} parents",
+                    "cannot expand commit {:.8}, it has {} parents",
                     commit_node.commit.id(),
                     commit_node.commit.parent_count()
                 ));
@@ -373,227 +762,1666 @@ impl<'repo> Model<'repo> {
             commit_node.deltas = diff.deltas().map(Node::from).collect();
             commit_node.diff = Some(diff);
         }
+        Ok(commit_node)
+    }
+
+    /// Diffs the commit like `load_diff`, except that a merge, which has no
+    /// diff, is left as it is.
+    fn load_diff_unless_merge(&mut self, commit_index: usize) -> anyhow::Result<()> {
+        match self.stack.get(commit_index) {
+            Some(commit_node) if commit_node.commit.parent_count() != 1 => Ok(()),
+            _ => self.load_diff(commit_index).map(|_| ()),
+        }
+    }
 
-        commit_node.is_collapsed = !commit_node.is_collapsed;
+    pub fn toggle_deltas(&mut self, commit_index: usize) -> anyhow::Result<()> {
+        self.load_diff(commit_index)?;
+        if let Some(commit_node) = self.stack.get_mut(commit_index) {
+            commit_node.is_collapsed = !commit_node.is_collapsed;
+        }
         Ok(())
     }
 
-    pub fn show_delta(&mut self, commit_index: usize, delta_index: usize) -> anyhow::Result<()> {
-        let Some(stack_commit) = self.stack.get_mut(commit_index) else {
-            // state.status = format!("invalid commit index {}", index);
-            // TODO
-            return Ok(());
+    /// A `git show`-like view of the whole commit, with its review and checks.
+    fn commit_preview(
+        &mut self,
+        commit_index: usize,
+    ) -> anyhow::Result<ratatui_diff::Diff<'static>> {
+        // Merges have no diff to show, but their message can still be read.
+        self.load_diff_unless_merge(commit_index)?;
+        let Some(commit_node) = self.stack.get(commit_index) else {
+            anyhow::bail!("invalid commit index {}", commit_index);
         };
+        let commit = &commit_node.commit;
+        let author = commit.author();
+
+        let mut lines = vec![
+            DiffLine::from(Line::from(format!("commit {}", commit.id())).style(self.theme.hash)),
+            DiffLine::from(Line::from(format!(
+                "Author: {} <{}>",
+                author.name().unwrap_or(""),
+                author.email().unwrap_or("")
+            ))),
+            DiffLine::from(Line::from(format!(
+                "Date:   {}",
+                format_time(&author.when())
+            ))),
+        ];
+        if let Some(branch) = &commit_node.branch {
+            lines.push(DiffLine::from(Line::from(format!("Branch: {}", branch))));
+        }
+        if let Some(review) = commit_node.review {
+            lines.push(DiffLine::from(Line::from(format!("Review: {}", review))));
+        }
+        lines.push(DiffLine::from(""));
+        for line in commit.message().unwrap_or("").trim_end().lines() {
+            lines.push(DiffLine::from(Line::from(format!("    {}", line))));
+        }
 
-        let counter = RwLock::new(0usize);
-        let lines = RwLock::new(Vec::new());
-        if let Some(diff) = stack_commit.diff.as_ref() {
-            diff.foreach(
-                &mut |_delta, _x| {
-                    counter.write().unwrap().add_assign(1);
-                    true
-                },
-                Some(&mut |_delta, _binary| true),
-                Some(&mut |delta, hunk| {
-                    if *counter.read().unwrap() != delta_index + 1 {
-                        return true;
-                    }
+        if !commit_node.warnings.is_empty() {
+            lines.push(DiffLine::from(""));
+            for warning in &commit_node.warnings {
+                lines.push(DiffLine::from(Line::from(vec![
+                    Span::styled("⚠ ", self.theme.warning),
+                    format!("{}: ", warning.rule).bold(),
+                    warning.message.clone().into(),
+                ])));
+            }
+        }
 
-                    let mut text = String::from_utf8_lossy(hunk.header()).to_string();
-                    text.push_str("\n");
-                    lines.write().unwrap().push(Line::from(text));
-                    true
-                }),
-                Some(&mut |_delta, _hunk, line| {
-                    if *counter.read().unwrap() != delta_index + 1 {
-                        return true;
-                    }
+        lines.push(DiffLine::from(""));
+        let Some(diff) = commit_node.diff.as_ref() else {
+            lines.push(DiffLine::from(
+                Line::from(format!("merge of {} parents", commit.parent_count()))
+                    .style(self.theme.muted),
+            ));
+            return Ok(ratatui_diff::Diff::new(lines));
+        };
 
-                    let mut style = Style::new();
-                    let mut text = String::from_utf8_lossy(line.content()).to_string();
-                    if line.old_lineno().is_some() && line.new_lineno().is_some() {
-                        text.insert_str(0, "  ");
-                    } else if line.new_lineno().is_some() {
-                        text.insert_str(0, "+ ");
-                        style = style.green();
-                    } else if line.old_lineno().is_some() {
-                        text.insert_str(0, "- ");
-                        style = style.red();
-                    }
-                    lines.write().unwrap().push(Line::from(text).style(style));
-                    true
-                }),
-            )?;
+        let patches = (0..diff.deltas().len())
+            .map(|delta_index| {
+                git2::Patch::from_diff(diff, delta_index)
+                    .with_context(|| format!("failed to compute the diff of file {}", delta_index))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        lines.extend(diffstat(diff, &patches, &self.theme)?);
+        for (delta, patch) in diff.deltas().zip(&patches) {
+            lines.push(DiffLine::from(""));
+            lines.push(DiffLine::new(
+                Line::from(delta_path(&delta)).bold(),
+                LineKind::File,
+            ));
+            lines.extend(patch_lines(patch.as_ref(), &self.theme)?);
         }
-
-        self.preview = Paragraph::new(lines.into_inner()?);
-        Ok(())
+        Ok(ratatui_diff::Diff::new(lines))
     }
-}
-
-// MARK: Messaging
-
-enum Message {
-    Noop,
-    Load(String),
-    Terminal(Event),
-    Exit,
-}
-
-// MARK: Controller
 
-struct Controller<'repo> {
-    model: Model<'repo>,
-    queue: VecDeque<Message>,
-}
+    fn delta_preview(
+        &self,
+        commit_index: usize,
+        delta_index: usize,
+    ) -> anyhow::Result<ratatui_diff::Diff<'static>> {
+        let Some(diff) = self
+            .stack
+            .get(commit_index)
+            .and_then(|commit_node| commit_node.diff.as_ref())
+        else {
+            anyhow::bail!("invalid commit index {}", commit_index);
+        };
+        let Some(delta) = diff.get_delta(delta_index) else {
+            anyhow::bail!("invalid file index {}", delta_index);
+        };
 
-impl<'repo> Controller<'repo> {
-    fn new(model: Model<'repo>) -> Self {
-        Self {
-            model,
-            queue: VecDeque::new(),
-        }
+        let patch = git2::Patch::from_diff(diff, delta_index)
+            .with_context(|| format!("failed to compute the diff of file {}", delta_index))?;
+        let mut lines = vec![DiffLine::new(
+            Line::from(delta_path(&delta)).bold(),
+            LineKind::File,
+        )];
+        lines.extend(patch_lines(patch.as_ref(), &self.theme)?);
+        Ok(ratatui_diff::Diff::new(lines))
     }
 
-    fn message(&mut self, message: Message) {
-        self.queue.push_back(message);
+    /// The commit and file that are selected, which is what the preview shows.
+    fn selected_preview(&self) -> Option<(usize, Option<usize>)> {
+        match self.tree.selected().as_ref().map(TreeIndex::as_slice) {
+            Some(&[commit_index]) => Some((commit_index, None)),
+            Some(&[commit_index, delta_index]) => Some((commit_index, Some(delta_index))),
+            _ => None,
+        }
     }
 
-    fn exit(&mut self) {
-        self.queue.clear();
-        self.message(Message::Exit);
+    fn preview_key(&self, selected: (usize, Option<usize>)) -> Option<(Oid, Option<usize>)> {
+        let (commit_index, delta_index) = selected;
+        let commit_node = self.stack.get(commit_index)?;
+        Some((commit_node.commit.id(), delta_index))
     }
 
-    fn select_up(&mut self) {
-        self.model.tree.select(
-            self.model
-                .tree
-                .selected()
-                .as_ref()
-                .and_then(|index| self.model.stack.find_previous_relative_of(index))
-                .map(|(index, _)| index),
-        );
+    /// Shows the selected commit or file, rendering each only once.
+    pub fn show_selected(&mut self) -> anyhow::Result<()> {
+        let Some(selected) = self.selected_preview() else {
+            return Ok(());
+        };
+        let Some(key) = self.preview_key(selected) else {
+            return Ok(());
+        };
+        self.previewed = Some(key);
+
+        let preview = match self.preview_cache.get(&key) {
+            Some(preview) => preview.clone(),
+            None => {
+                let preview = match selected {
+                    (commit_index, Some(delta_index)) => {
+                        self.delta_preview(commit_index, delta_index)?
+                    }
+                    (commit_index, None) => self.commit_preview(commit_index)?,
+                };
+                self.preview_cache.insert(key, preview.clone());
+                preview
+            }
+        };
+        match &self.search {
+            // Scroll to the first hit, if it is in the diff.
+            Some(search) => {
+                let (preview, hit) = search.highlight(&preview, &self.theme);
+                self.set_preview(preview);
+                if let Some(hit) = hit {
+                    self.preview.scroll_to_line(&mut self.preview_state, hit);
+                }
+            }
+            None => self.set_preview(preview),
+        }
+        Ok(())
     }
 
-    fn select_down(&mut self) {
-        self.model.tree.select(
-            self.model
-                .tree
-                .selected()
-                .as_ref()
-                .and_then(|index| self.model.stack.find_next_relative_of(index))
-                .map(|(index, _)| index),
-        );
+    /// Shows the selection if it moved, unless the plan is being previewed.
+    pub fn follow_selection(&mut self) -> anyhow::Result<()> {
+        if self.plan.is_some() {
+            return Ok(());
+        }
+        let key = self
+            .selected_preview()
+            .and_then(|selected| self.preview_key(selected));
+        if key == self.previewed {
+            return Ok(());
+        }
+        self.show_selected()
     }
 
-    fn draw(&mut self, frame: &mut Frame) -> anyhow::Result<()> {
-        let message = self.queue.pop_front();
+    /// Searches the whole stack, loading the diffs of every commit, and expands
+    /// the commits with matching files.
+    pub fn search(&mut self, query: &str, diffs: bool) -> anyhow::Result<()> {
+        self.clear_search();
+        if query.is_empty() {
+            return Ok(());
+        }
 
-        match message.as_ref() {
-            Some(Message::Terminal(Event::Key(key))) => {
-                if key.modifiers.contains(KeyModifiers::CONTROL) {
-                    if matches!(key.code, KeyCode::Char('c' | 'q' | 'x')) {
-                        return Ok(self.exit());
-                    }
+        let mut search = Search::new(query, diffs);
+        for commit_index in 0..self.stack.len() {
+            if self
+                .stack
+                .get(commit_index)
+                .is_some_and(|node| node.is_hidden)
+            {
+                continue;
+            }
+            // Merges have no diff, but their message can still match.
+            self.load_diff_unless_merge(commit_index)?;
+            if diffs {
+                self.load_changed_lines(commit_index)?;
+            }
+            let files = self.search_files(&search, commit_index);
+            let Some(commit_node) = self.stack.get_mut(commit_index) else {
+                continue;
+            };
+            if search.is_match(commit_node.commit.message().unwrap_or("")) {
+                commit_node.is_match = true;
+                search.matches.push(TreeIndex::new(commit_index));
+            }
+            if !files.is_empty() && commit_node.is_collapsed {
+                commit_node.is_collapsed = false;
+                search.expanded.push(commit_index);
+            }
+            for delta_index in files {
+                if let Some(delta_node) = commit_node.get_mut(delta_index) {
+                    delta_node.is_match = true;
                 }
+                search
+                    .matches
+                    .push(TreeIndex::new(commit_index).pushed(delta_index));
             }
-            Some(Message::Load(base)) => self.model.load_commits_since_merge_base_with(base)?,
-            _ => {}
         }
-
-        let area = frame.area();
-        let layout = Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]);
-        let [content_area, footer_area] = layout.areas(area);
-        self.draw_content(content_area, frame.buffer_mut(), &message)?;
-        self.draw_footer(footer_area, frame.buffer_mut(), &message)?;
+        self.search = Some(search);
         Ok(())
     }
 
-    fn draw_content(
-        &mut self,
-        area: Rect,
-        buffer: &mut Buffer,
-        message: &Option<Message>,
-    ) -> anyhow::Result<()> {
-        match message.as_ref() {
-            Some(Message::Terminal(Event::Key(key))) if key.is_press() => match key.code {
-                KeyCode::Up => self.select_up(),
-                KeyCode::Down => self.select_down(),
-                KeyCode::Left => {}
-                KeyCode::Right => {}
-                KeyCode::Char(' ') => {
-                    match self.model.tree.selected().as_ref().map(TreeIndex::as_slice) {
-                        Some([commit_index]) => self.model.toggle_deltas(*commit_index)?,
-                        Some([commit_index, file_index]) => {
-                            self.model.show_delta(*commit_index, *file_index)?
+    /// Collects the added and removed lines of the commit, unless that was
+    /// done already.
+    fn load_changed_lines(&mut self, commit_index: usize) -> anyhow::Result<()> {
+        let Some(commit_node) = self.stack.get(commit_index) else {
+            return Ok(());
+        };
+        let Some(diff) = commit_node.diff.as_ref() else {
+            return Ok(());
+        };
+        if self.changed_lines.contains_key(&commit_node.commit.id()) {
+            return Ok(());
+        }
+
+        let mut files = Vec::new();
+        for delta_index in 0..diff.deltas().len() {
+            let mut lines = Vec::new();
+            let patch = git2::Patch::from_diff(diff, delta_index)
+                .with_context(|| format!("failed to compute the diff of file {}", delta_index))?;
+            if let Some(patch) = patch {
+                for hunk_index in 0..patch.num_hunks() {
+                    let length = patch
+                        .num_lines_in_hunk(hunk_index)
+                        .context("failed to retrieve diff hunk")?;
+                    for line_index in 0..length {
+                        let line = patch
+                            .line_in_hunk(hunk_index, line_index)
+                            .context("failed to retrieve diff line")?;
+                        if matches!(line.origin(), '+' | '-') {
+                            lines.push(String::from_utf8_lossy(line.content()).into_owned());
                         }
-                        _ => {}
                     }
                 }
-                KeyCode::Char('q') => {
-                    return Ok(self.exit());
-                }
-                _ => {}
-            },
-            _ => {}
+            }
+            files.push(lines);
         }
-
-        let tree = Tree::new(&self.model.stack.commits)
-            .indent_symbol("    ")
-            .highlight_style(Style::new().bold().reversed())
-            .block(
-                Block::bordered()
-                    .title("Commits")
-                    .title_alignment(Alignment::Center)
-                    .border_type(BorderType::Rounded),
-            );
-
-        let layout = Layout::horizontal([
-            Constraint::Fill(1),
-            Constraint::Length(1),
-            Constraint::Fill(2),
-        ]);
-        let [tree_area, _, preview_area] = layout.areas(area);
-        StatefulWidget::render(tree, tree_area, buffer, &mut self.model.tree);
-
-        self.model.preview.render_ref(preview_area, buffer);
-
+        self.changed_lines.insert(commit_node.commit.id(), files);
         Ok(())
     }
 
-    fn draw_footer(
-        &mut self,
-        area: Rect,
-        buffer: &mut Buffer,
-        message: &Option<Message>,
-    ) -> anyhow::Result<()> {
-        let layout = Layout::horizontal([Constraint::Fill(2), Constraint::Fill(1)]);
-        let [tooltips_area, status_area] = layout.areas(area);
-
-        let mut spans = Vec::new();
-        // for tooltip in &state.tooltips {
-        //     spans.push(" ".to_span());
-        //     spans.push(tooltip.to_span());
-        //     spans.push(" ".to_span());
-        // }
-        Line::from(spans)
-            .style(Style::new().reversed())
-            .alignment(Alignment::Left)
-            .render(tooltips_area, buffer);
+    /// The files of the commit whose path, or added or removed lines, match.
+    fn search_files(&self, search: &Search, commit_index: usize) -> Vec<usize> {
+        let Some(commit_node) = self.stack.get(commit_index) else {
+            return Vec::new();
+        };
+        let Some(diff) = commit_node.diff.as_ref() else {
+            return Vec::new();
+        };
+        let changed_lines = self.changed_lines.get(&commit_node.commit.id());
+
+        let mut files = Vec::new();
+        for (delta_index, delta) in diff.deltas().enumerate() {
+            if commit_node
+                .get(delta_index)
+                .is_some_and(|node| node.is_hidden)
+            {
+                continue;
+            }
+            let is_match = search.is_match(&delta_path(&delta))
+                || (search.diffs
+                    && changed_lines
+                        .and_then(|files| files.get(delta_index))
+                        .is_some_and(|lines| lines.iter().any(|line| search.is_match(line))));
+            if is_match {
+                files.push(delta_index);
+            }
+        }
+        files
+    }
 
-        // Line::from(state.status.to_span())
-        //     .style(Style::new().reversed())
-        //     .alignment(Alignment::Right)
-        //     .render(status_area, buffer);
+    /// Filters the stack, or shows all of it again if the filter is empty.
+    pub fn set_filter(&mut self, text: &str) -> anyhow::Result<()> {
+        self.filter = match text.trim() {
+            "" => None,
+            text => Some(Filter::parse(text)?),
+        };
+        self.clear_search();
+        self.apply_filter()
+    }
 
+    /// Hides the commits and files that do not match the filter, and moves the
+    /// selection off anything hidden.
+    fn apply_filter(&mut self) -> anyhow::Result<()> {
+        let filter = self.filter.take();
+        let hidden = self.hide_unmatched(filter.as_ref());
+        self.filter = filter;
+        hidden?;
+
+        let selected = self.tree.selected().clone();
+        if let Some(selected) = selected {
+            let nearest = self
+                .stack
+                .find_nearest_to(&selected)
+                .map(|(index, _)| index);
+            self.tree.select(nearest);
+        }
         Ok(())
     }
+
+    fn hide_unmatched(&mut self, filter: Option<&Filter>) -> anyhow::Result<()> {
+        let pattern = filter.map(|filter| &filter.pattern);
+        for commit_index in 0..self.stack.len() {
+            if matches!(pattern, Some(Pattern::Path(_))) {
+                // Merges have no files, so they cannot match.
+                self.load_diff_unless_merge(commit_index)?;
+            }
+            let Some(commit_node) = self.stack.get_mut(commit_index) else {
+                continue;
+            };
+            for delta in &mut commit_node.deltas {
+                delta.unwrap_delta_mut().is_hidden = false;
+            }
+            commit_node.is_hidden = match pattern {
+                None => false,
+                Some(Pattern::Message(regex)) => {
+                    !regex.is_match(commit_node.commit.message().unwrap_or(""))
+                }
+                Some(Pattern::Author(author)) => {
+                    let signature = commit_node.commit.author();
+                    let matches = [signature.name(), signature.email()]
+                        .into_iter()
+                        .flatten()
+                        .any(|text| text.to_lowercase().contains(author));
+                    !matches
+                }
+                Some(Pattern::Path(pathspec)) => {
+                    for node in &mut commit_node.deltas {
+                        let delta = node.unwrap_delta_mut();
+                        delta.is_hidden = ![&delta.new_file.path, &delta.old_file.path]
+                            .into_iter()
+                            .flatten()
+                            .any(|path| pathspec.matches_path(path, git2::PathspecFlags::DEFAULT));
+                    }
+                    commit_node
+                        .deltas
+                        .iter()
+                        .all(|node| node.unwrap_delta_ref().is_hidden)
+                }
+            };
+        }
+        Ok(())
+    }
+
+    pub fn clear_filter(&mut self) -> anyhow::Result<()> {
+        self.set_filter("")
+    }
+
+    /// How many commits are shown, and how many there are.
+    fn count_shown(&self) -> (usize, usize) {
+        let shown = self
+            .stack
+            .commits
+            .iter()
+            .filter(|node| !node.unwrap_commit_ref().is_hidden)
+            .count();
+        (shown, self.stack.len())
+    }
+
+    pub fn clear_search(&mut self) {
+        let Some(search) = self.search.take() else {
+            return;
+        };
+        for node in &mut self.stack.commits {
+            let commit_node = node.unwrap_commit_mut();
+            commit_node.is_match = false;
+            for delta in &mut commit_node.deltas {
+                delta.unwrap_delta_mut().is_match = false;
+            }
+        }
+        for &commit_index in &search.expanded {
+            if let Some(commit_node) = self.stack.get_mut(commit_index) {
+                commit_node.is_collapsed = true;
+            }
+        }
+        self.select_shown(self.tree.selected().clone());
+        self.previewed = None;
+    }
+
+    /// Selects `index`, or its commit if that is collapsed and hides it.
+    pub fn select_shown(&mut self, index: Option<TreeIndex>) {
+        let index = index.map(|index| match *index.as_slice() {
+            [commit_index, _]
+                if self
+                    .stack
+                    .get(commit_index)
+                    .is_some_and(|node| node.is_collapsed) =>
+            {
+                TreeIndex::new(commit_index)
+            }
+            _ => index,
+        });
+        self.tree.select(index);
+    }
+
+    /// Selects the next or previous match after `from`, or `from` itself if
+    /// it matches and `inclusive` is set, wrapping around the stack. Returns
+    /// which match it is.
+    pub fn select_match(
+        &mut self,
+        from: Option<&TreeIndex>,
+        inclusive: bool,
+        forward: bool,
+    ) -> anyhow::Result<Option<usize>> {
+        let Some(search) = &self.search else {
+            return Ok(None);
+        };
+        let matches = &search.matches;
+        if matches.is_empty() {
+            return Ok(None);
+        }
+        let position = match (from, forward) {
+            (None, _) => 0,
+            (Some(from), true) => matches
+                .iter()
+                .position(|index| index > from || (inclusive && index == from))
+                .unwrap_or(0),
+            (Some(from), false) => matches
+                .iter()
+                .rposition(|index| index < from || (inclusive && index == from))
+                .unwrap_or(matches.len() - 1),
+        };
+        self.tree.select(Some(matches[position].clone()));
+        if self.plan.is_none() {
+            self.show_selected()?;
+        }
+        Ok(Some(position))
+    }
+
+    fn clear_preview(&mut self) {
+        self.set_preview(ratatui_diff::Diff::default());
+        self.previewed = None;
+    }
+
+    fn worktree(&self) -> Option<usize> {
+        self.worktrees
+            .iter()
+            .position(|worktree| worktree.is(self.repo))
+    }
+
+    pub fn switch_worktree(&mut self) -> anyhow::Result<()> {
+        let Some(current) = self.worktree() else {
+            return Ok(());
+        };
+        let next = (current + 1) % self.worktrees.len();
+        if next != current {
+            self.discard_plan();
+            self.repo = &self.worktrees[next].repo;
+            self.tree.select(None);
+            self.clear_preview();
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    pub fn show_worktrees(&mut self) -> anyhow::Result<()> {
+        let mut lines = vec![Line::from("Worktrees").bold(), Line::from("")];
+        for (index, worktree) in self.worktrees.iter().enumerate() {
+            let marker = if Some(index) == self.worktree() {
+                "* "
+            } else {
+                "  "
+            };
+            let branch = worktree
+                .branch()?
+                .unwrap_or_else(|| "(detached)".to_owned());
+            lines.push(Line::from(vec![
+                marker.into(),
+                worktree.name.clone().bold(),
+                "  ".into(),
+                branch
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(&branch)
+                    .to_owned()
+                    .into(),
+                "  ".into(),
+                Span::styled(worktree.path().display().to_string(), self.theme.muted),
+            ]));
+        }
+        self.set_preview(ratatui_diff::Diff::new(lines));
+        Ok(())
+    }
+
+    pub fn show_range_diff(&mut self) -> anyhow::Result<()> {
+        let old = rangediff::previous_version(self.repo, &self.base)?;
+        let mut lines = vec![
+            DiffLine::from(Line::from(format!("Changes since {:.8}", old)).bold()),
+            DiffLine::from(""),
+        ];
+        for entry in rangediff::compare(self.repo, &self.base, old)? {
+            let header = Line::from(entry.header());
+            let header = match entry.marker() {
+                '=' => header.style(self.theme.muted),
+                '<' => header.style(self.theme.removed).bold(),
+                '>' => header.style(self.theme.added).bold(),
+                _ => header.style(self.theme.warning).bold(),
+            };
+            lines.push(DiffLine::new(header, LineKind::File));
+            for (origin, line) in entry.lines {
+                lines.push(match origin {
+                    '@' => {
+                        DiffLine::new(Line::from("    @@").style(self.theme.hunk), LineKind::Hunk)
+                    }
+                    '+' => DiffLine::new(
+                        Line::from(format!("    +{}", line)).style(self.theme.added),
+                        LineKind::Added,
+                    ),
+                    '-' => DiffLine::new(
+                        Line::from(format!("    -{}", line)).style(self.theme.removed),
+                        LineKind::Removed,
+                    ),
+                    origin => DiffLine::new(
+                        Line::from(format!("    {}{}", origin, line)),
+                        LineKind::Context,
+                    ),
+                });
+            }
+        }
+        self.set_preview(ratatui_diff::Diff::new(lines));
+        Ok(())
+    }
+
+    pub fn show_description(&mut self, commit_index: usize) {
+        let nodes = self.stack.commits.iter().map(Node::unwrap_commit_ref);
+        let (commits, branches): (Vec<_>, Vec<_>) = nodes
+            .map(|node| (node.commit.clone(), node.branch.clone()))
+            .unzip();
+        let current = self.stack.get(commit_index).map(|node| node.commit.id());
+        let description = describe::render(&commits, &branches, current, true);
+        self.set_preview(Text::from(description).into());
+    }
+}
+
+fn push_summary(results: &[(push::StackBranch, anyhow::Result<push::Outcome>)]) -> Status {
+    let text = results
+        .iter()
+        .map(|(branch, outcome)| push::result_line(branch, outcome))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if results.iter().any(|(_, outcome)| outcome.is_err()) {
+        Status::new(Severity::Error, text)
+    } else {
+        Status::new(Severity::Success, text)
+    }
+}
+
+// MARK: Status
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Severity {
+    Info,
+    Success,
+    Warning,
+    Error,
+}
+
+/// A message for the footer, such as the outcome of the last action.
+struct Status {
+    severity: Severity,
+    text: String,
+}
+
+impl Status {
+    fn new(severity: Severity, text: String) -> Self {
+        Self { severity, text }
+    }
+
+    fn info(text: &str) -> Self {
+        Self::new(Severity::Info, text.to_owned())
+    }
+
+    fn warning(text: String) -> Self {
+        Self::new(Severity::Warning, text)
+    }
+
+    fn error(error: &anyhow::Error) -> Self {
+        Self::new(Severity::Error, format!("{:#}", error))
+    }
+
+    fn style(&self, theme: &Theme) -> Style {
+        match self.severity {
+            Severity::Info => theme.footer,
+            Severity::Success => theme.success,
+            Severity::Warning => theme.warning,
+            Severity::Error => theme.error,
+        }
+    }
+}
+
+// MARK: Messaging
+
+enum Message {
+    Load(String),
+    Terminal(Event),
+    Shell,
+    Resume,
+    /// Performs an action after a frame has shown its progress.
+    Perform(keymap::Action),
+    Exit,
+}
+
+// MARK: Filter
+
+/// What commits must match to be shown: a pathspec that one of their files
+/// matches, part of the author's name or email, or a regex on the message.
+enum Pattern {
+    Path(git2::Pathspec),
+    Author(String),
+    Message(regex::Regex),
+}
+
+struct Filter {
+    text: String,
+    pattern: Pattern,
+}
+
+impl Filter {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let pattern = if let Some(pathspec) = text.strip_prefix("path:") {
+            Pattern::Path(
+                git2::Pathspec::new([pathspec.trim()])
+                    .with_context(|| format!("invalid pathspec {}", pathspec))?,
+            )
+        } else if let Some(author) = text.strip_prefix("author:") {
+            Pattern::Author(author.trim().to_lowercase())
+        } else {
+            // Regex errors point at the problem over several lines, but the
+            // status line only has room for the last one.
+            Pattern::Message(regex::Regex::new(text).map_err(|error| {
+                let error = error.to_string();
+                let reason = error
+                    .lines()
+                    .last()
+                    .unwrap_or("")
+                    .trim_start_matches("error: ");
+                anyhow::format_err!("invalid pattern {}: {}", text, reason)
+            })?)
+        };
+        Ok(Self {
+            text: text.to_owned(),
+            pattern,
+        })
+    }
+}
+
+// MARK: Search
+
+/// A search of the stack. Matching ignores ASCII case unless the query has
+/// capitals.
+struct Search {
+    query: String,
+    diffs: bool,
+    matches: Vec<TreeIndex>,
+    /// The commits expanded to show matching files, collapsed again when the
+    /// search is cleared.
+    expanded: Vec<usize>,
+}
+
+impl Search {
+    fn new(query: &str, diffs: bool) -> Self {
+        Self {
+            query: query.to_owned(),
+            diffs,
+            matches: Vec::new(),
+            expanded: Vec::new(),
+        }
+    }
+
+    fn find(&self, text: &str) -> Vec<std::ops::Range<usize>> {
+        let (text, query) = if self.query.chars().any(char::is_uppercase) {
+            (text.to_owned(), self.query.clone())
+        } else {
+            (text.to_ascii_lowercase(), self.query.to_ascii_lowercase())
+        };
+        text.match_indices(&query)
+            .map(|(start, hit)| start..start + hit.len())
+            .collect()
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        !self.find(text).is_empty()
+    }
+
+    /// Highlights the hits in a preview, returning the first line with one.
+    fn highlight(
+        &self,
+        preview: &ratatui_diff::Diff<'static>,
+        theme: &Theme,
+    ) -> (ratatui_diff::Diff<'static>, Option<usize>) {
+        let mut first = None;
+        let mut lines = Vec::new();
+        for (index, line) in preview.lines().iter().enumerate() {
+            let mut content = line.content().clone();
+            let mut spans = Vec::new();
+            for span in std::mem::take(&mut content.spans) {
+                let hits = self.find(&span.content);
+                if hits.is_empty() {
+                    spans.push(span);
+                    continue;
+                }
+                first.get_or_insert(index);
+                let mut at = 0;
+                for hit in hits {
+                    if hit.start > at {
+                        spans.push(Span::styled(
+                            span.content[at..hit.start].to_owned(),
+                            span.style,
+                        ));
+                    }
+                    spans.push(Span::styled(
+                        span.content[hit.clone()].to_owned(),
+                        span.style.patch(theme.search),
+                    ));
+                    at = hit.end;
+                }
+                if at < span.content.len() {
+                    spans.push(Span::styled(span.content[at..].to_owned(), span.style));
+                }
+            }
+            content.spans = spans;
+            lines.push(DiffLine::new(content, line.kind()));
+        }
+        (ratatui_diff::Diff::new(lines), first)
+    }
+}
+
+// MARK: Prompt
+
+enum PromptAction {
+    Insert(usize),
+    /// Searches as the query is typed, going back to where it started if
+    /// cancelled.
+    Search {
+        origin: Option<TreeIndex>,
+        diffs: bool,
+    },
+    Filter,
+}
+
+struct Prompt {
+    label: &'static str,
+    input: String,
+    action: PromptAction,
+}
+
+impl Prompt {
+    fn new(label: &'static str, action: PromptAction) -> Self {
+        Self {
+            label,
+            input: String::new(),
+            action,
+        }
+    }
+}
+
+// MARK: Controller
+
+/// The pane that moving the selection acts on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Focus {
+    Commits,
+    Preview,
+}
+
+/// Where the panes were last drawn, to find what the mouse is on, and the
+/// help, to know how far it scrolls.
+#[derive(Debug, Default, Clone, Copy)]
+struct Panes {
+    commits: Rect,
+    divider: Rect,
+    preview: Rect,
+    help: Rect,
+}
+
+/// The width of the commit icon, which toggles the commit when clicked.
+const ICON_WIDTH: u16 = 3;
+/// How many lines the mouse wheel scrolls the preview by.
+const WHEEL_LINES: isize = 3;
+
+struct Controller<'repo> {
+    model: Model<'repo>,
+    keymap: Keymap,
+    focus: Focus,
+    queue: VecDeque<Message>,
+    prompt: Option<Prompt>,
+    /// How far the help is scrolled, while it is shown.
+    help: Option<u16>,
+    status: Option<Status>,
+    panes: Panes,
+    /// The width of the commits pane, once the divider has been dragged.
+    split: Option<u16>,
+    is_dragging: bool,
+}
+
+impl<'repo> Controller<'repo> {
+    fn new(model: Model<'repo>, keymap: Keymap) -> Self {
+        Self {
+            model,
+            keymap,
+            focus: Focus::Commits,
+            queue: VecDeque::new(),
+            prompt: None,
+            help: None,
+            status: None,
+            panes: Panes::default(),
+            split: None,
+            is_dragging: false,
+        }
+    }
+
+    fn submit(&mut self, prompt: Prompt) -> anyhow::Result<()> {
+        match prompt.action {
+            PromptAction::Insert(commit_index) => {
+                if !prompt.input.trim().is_empty() {
+                    if let Some(status) = self.model.insert(commit_index, &prompt.input)? {
+                        self.status = Some(status);
+                    }
+                }
+            }
+            // The search already ran while typing.
+            PromptAction::Search { .. } => {}
+            PromptAction::Filter => {
+                self.model.set_filter(&prompt.input)?;
+                if self.model.filter.is_some() {
+                    let (shown, total) = self.model.count_shown();
+                    self.status = Some(Status::info(&format!(
+                        "showing {} of {} commits",
+                        shown, total
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cancel(&mut self, prompt: Prompt) {
+        if let PromptAction::Search { origin, .. } = prompt.action {
+            self.model.clear_search();
+            self.model.select_shown(origin);
+            self.status = None;
+        }
+    }
+
+    /// Runs the search in the prompt, selecting the first match from where
+    /// the search started.
+    fn update_search(&mut self) -> anyhow::Result<()> {
+        let Some(Prompt {
+            input,
+            action: PromptAction::Search { origin, diffs },
+            ..
+        }) = &self.prompt
+        else {
+            return Ok(());
+        };
+        let (query, origin, diffs) = (input.clone(), origin.clone(), *diffs);
+
+        self.model.search(&query, diffs)?;
+        if query.is_empty() {
+            self.model.select_shown(origin);
+            self.status = None;
+            return Ok(());
+        }
+        let position = self.model.select_match(origin.as_ref(), true, true)?;
+        if position.is_none() {
+            self.model.select_shown(origin);
+        }
+        self.status = Some(self.search_status(position));
+        Ok(())
+    }
+
+    fn search_status(&self, position: Option<usize>) -> Status {
+        let Some(search) = &self.model.search else {
+            return Status::info("no search");
+        };
+        match position {
+            Some(position) => Status::info(&format!(
+                "match {} of {} for {}",
+                position + 1,
+                search.matches.len(),
+                search.query
+            )),
+            None => Status::new(Severity::Error, format!("no matches for {}", search.query)),
+        }
+    }
+
+    fn handle_prompt(&mut self, key: &KeyEvent) -> anyhow::Result<()> {
+        let Some(prompt) = self.prompt.as_mut() else {
+            return Ok(());
+        };
+
+        match (key.code, &mut prompt.action) {
+            (KeyCode::Char(c), _) => prompt.input.push(c),
+            (KeyCode::Backspace, _) => {
+                prompt.input.pop();
+            }
+            (KeyCode::Tab, PromptAction::Search { diffs, .. }) => {
+                *diffs = !*diffs;
+                prompt.label = if *diffs {
+                    "Search with diffs: "
+                } else {
+                    "Search: "
+                };
+            }
+            (KeyCode::Esc, _) => {
+                if let Some(prompt) = self.prompt.take() {
+                    self.cancel(prompt);
+                }
+                return Ok(());
+            }
+            (KeyCode::Enter, _) => {
+                if let Some(prompt) = self.prompt.take() {
+                    self.submit(prompt)?;
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+        self.update_search()
+    }
+
+    fn message(&mut self, message: Message) {
+        self.queue.push_back(message);
+    }
+
+    fn exit(&mut self) {
+        self.queue.clear();
+        self.message(Message::Exit);
+    }
+
+    fn select_up(&mut self) {
+        self.model.tree.select(
+            self.model
+                .tree
+                .selected()
+                .as_ref()
+                .and_then(|index| self.model.stack.find_previous_relative_of(index))
+                .map(|(index, _)| index),
+        );
+    }
+
+    fn select_down(&mut self) {
+        self.model.tree.select(
+            self.model
+                .tree
+                .selected()
+                .as_ref()
+                .and_then(|index| self.model.stack.find_next_relative_of(index))
+                .map(|(index, _)| index),
+        );
+    }
+
+    fn select_first(&mut self) {
+        if let Some((first, _)) = self.model.stack.find_first_child() {
+            self.model.tree.select(Some(TreeIndex::new(first)));
+        }
+    }
+
+    fn select_last(&mut self) {
+        if let Some((last, _)) = self.model.stack.find_last_child() {
+            self.model.tree.select(Some(TreeIndex::new(last)));
+        }
+    }
+
+    /// The branch and the size of the stack.
+    fn context(&self) -> String {
+        let head = self.model.repo.head().ok();
+        let branch = head
+            .as_ref()
+            .and_then(|head| head.shorthand())
+            .unwrap_or("HEAD");
+        match self.model.stack.commits.len() {
+            1 => format!("{}: 1 commit on {}", branch, self.model.base),
+            n => format!("{}: {} commits on {}", branch, n, self.model.base),
+        }
+    }
+
+    /// The actions worth a reminder in the footer right now.
+    fn tooltips(&self) -> Vec<keymap::Action> {
+        let mut actions = vec![keymap::Action::Toggle];
+        if self.focus == Focus::Preview {
+            actions = vec![
+                keymap::Action::PageDown,
+                keymap::Action::NextHunk,
+                keymap::Action::NextFile,
+                keymap::Action::Focus,
+            ];
+        } else if self.model.stack.stop.is_some() {
+            actions.extend([keymap::Action::Resume, keymap::Action::Abort]);
+        } else if self.model.plan.is_some() {
+            actions.extend([keymap::Action::Confirm, keymap::Action::Cancel]);
+        } else {
+            actions.extend([
+                keymap::Action::Edit,
+                keymap::Action::Insert,
+                keymap::Action::Squash,
+                keymap::Action::Drop,
+            ]);
+        }
+        if self.model.search.is_some() && self.focus == Focus::Commits {
+            actions.extend([keymap::Action::SearchNext, keymap::Action::SearchPrevious]);
+        }
+        actions.extend([keymap::Action::Help, keymap::Action::Quit]);
+        actions
+    }
+
+    fn draw(&mut self, frame: &mut Frame) -> anyhow::Result<()> {
+        let message = self.queue.pop_front();
+
+        let action = match message {
+            // Without the stack there is nothing to show, so this one is fatal.
+            Some(Message::Load(base)) => {
+                self.model.load_commits_since_merge_base_with(&base)?;
+                None
+            }
+            Some(Message::Resume) => {
+                match self.model.resume() {
+                    Ok(status) => self.status = status,
+                    Err(error) => self.status = Some(Status::error(&error)),
+                }
+                None
+            }
+            Some(Message::Terminal(Event::Key(key))) if key.is_press() => {
+                self.handle_key(&key).unwrap_or_else(|error| {
+                    self.status = Some(Status::error(&error));
+                    None
+                })
+            }
+            Some(Message::Terminal(Event::Mouse(mouse))) => {
+                if let Err(error) = self.handle_mouse(&mouse) {
+                    self.status = Some(Status::error(&error));
+                }
+                None
+            }
+            Some(Message::Perform(action)) => {
+                if let Err(error) = self.perform(action) {
+                    self.status = Some(Status::error(&error));
+                }
+                None
+            }
+            _ => None,
+        };
+        match action {
+            Some(keymap::Action::Quit) => {
+                self.exit();
+                return Ok(());
+            }
+            Some(action) => match progress(action) {
+                // Draw the progress first, since the action may take a while.
+                Some(text) => {
+                    self.status = Some(Status::info(text));
+                    self.message(Message::Perform(action));
+                }
+                None => {
+                    self.status = None;
+                    if let Err(error) = self.perform(action) {
+                        self.status = Some(Status::error(&error));
+                    }
+                }
+            },
+            None => {}
+        }
+        if let Err(error) = self.model.follow_selection() {
+            self.status = Some(Status::error(&error));
+        }
+
+        let area = frame.area();
+        let layout = Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]);
+        let [content_area, footer_area] = layout.areas(area);
+        self.draw_content(content_area, frame.buffer_mut())?;
+        self.draw_footer(footer_area, frame.buffer_mut())?;
+        if let Some(scroll) = self.help {
+            self.draw_help(content_area, frame.buffer_mut(), scroll);
+        }
+        Ok(())
+    }
+
+    /// Resolves a key press to an action, unless the prompt or the help takes
+    /// it. The prompt takes plain keys, but chords with Ctrl still go to the
+    /// keymap so that quitting works everywhere.
+    fn handle_key(&mut self, key: &KeyEvent) -> anyhow::Result<Option<keymap::Action>> {
+        if self.prompt.is_some() && !key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.handle_prompt(key)?;
+            return Ok(None);
+        }
+        let action = self.keymap.feed(key);
+        if self.prompt.is_some() {
+            return Ok(action.filter(|&action| action == keymap::Action::Quit));
+        }
+        if let Some(scroll) = self.help {
+            // The help scrolls and any other key closes it.
+            match action {
+                Some(keymap::Action::SelectUp) => self.help = Some(scroll.saturating_sub(1)),
+                Some(keymap::Action::SelectDown) => {
+                    self.help = Some(scroll.saturating_add(1).min(self.max_help_scroll()));
+                }
+                None if self.keymap.pending().is_some() => {}
+                _ => self.help = None,
+            }
+            return Ok(None);
+        }
+        Ok(action)
+    }
+
+    /// Selects what is clicked, scrolls with the wheel and resizes the panes
+    /// by dragging the divider. The mouse does nothing while the prompt or the
+    /// help is open.
+    fn handle_mouse(&mut self, mouse: &MouseEvent) -> anyhow::Result<()> {
+        if self.prompt.is_some() || self.help.is_some() {
+            return Ok(());
+        }
+        let position = Position::new(mouse.column, mouse.row);
+        match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if self.panes.divider.contains(position) {
+                    self.is_dragging = true;
+                } else if self.panes.commits.contains(position) {
+                    self.focus = Focus::Commits;
+                    self.click_commits(position)?;
+                } else if self.panes.preview.contains(position) {
+                    self.focus = Focus::Preview;
+                }
+            }
+            MouseEventKind::Drag(MouseButton::Left) if self.is_dragging => {
+                let left = self.panes.commits.left();
+                let right = self.panes.preview.right();
+                // Keep some of both panes in view.
+                let minimum = 10.min(right.saturating_sub(left) / 2);
+                let column = mouse.column.clamp(
+                    left + minimum,
+                    right.saturating_sub(minimum).max(left + minimum),
+                );
+                self.split = Some(column - left);
+            }
+            MouseEventKind::Up(MouseButton::Left) => self.is_dragging = false,
+            MouseEventKind::ScrollUp | MouseEventKind::ScrollDown => {
+                let up = mouse.kind == MouseEventKind::ScrollUp;
+                if self.panes.preview.contains(position) {
+                    let lines = if up { -WHEEL_LINES } else { WHEEL_LINES };
+                    self.model.preview_state.scroll_by(lines);
+                } else if self.panes.commits.contains(position) {
+                    if up {
+                        self.select_up();
+                    } else {
+                        self.select_down();
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Selects the row clicked, and toggles a commit if its icon was clicked.
+    fn click_commits(&mut self, position: Position) -> anyhow::Result<()> {
+        // Only the borders of the block matter to find the row.
+        let block = Block::bordered();
+        let inner = block.inner(self.panes.commits);
+        let tree = self.model.stack.tree(&self.model.theme).block(block);
+        let index = tree.index_at_row(self.panes.commits, &self.model.tree, position.y);
+        let Some(index) = index else {
+            return Ok(());
+        };
+        let on_icon = index.len() == 1 && position.x < inner.left() + ICON_WIDTH;
+        self.model.tree.select(Some(index));
+        if on_icon {
+            self.perform_on_commits(keymap::Action::Toggle)?;
+        }
+        Ok(())
+    }
+
+    fn perform(&mut self, action: keymap::Action) -> anyhow::Result<()> {
+        let preview = &self.model.preview;
+        let preview_state = &mut self.model.preview_state;
+        match (action, self.focus) {
+            (keymap::Action::SelectUp, Focus::Preview) => preview_state.scroll_by(-1),
+            (keymap::Action::SelectDown, Focus::Preview) => preview_state.scroll_by(1),
+            (keymap::Action::SelectFirst, Focus::Preview) => preview_state.scroll_to_top(),
+            (keymap::Action::SelectLast, Focus::Preview) => preview_state.scroll_to_bottom(),
+            (keymap::Action::PageUp, _) => preview_state.page_up(),
+            (keymap::Action::PageDown, _) => preview_state.page_down(),
+            (keymap::Action::NextHunk, _) => {
+                preview.scroll_to_next(preview_state, LineKind::Hunk);
+            }
+            (keymap::Action::PreviousHunk, _) => {
+                preview.scroll_to_previous(preview_state, LineKind::Hunk);
+            }
+            (keymap::Action::NextFile, _) => {
+                preview.scroll_to_next(preview_state, LineKind::File);
+            }
+            (keymap::Action::PreviousFile, _) => {
+                preview.scroll_to_previous(preview_state, LineKind::File);
+            }
+            (keymap::Action::Focus, _) => {
+                self.focus = match self.focus {
+                    Focus::Commits => Focus::Preview,
+                    Focus::Preview => Focus::Commits,
+                }
+            }
+            _ => self.perform_on_commits(action)?,
+        }
+        Ok(())
+    }
+
+    fn perform_on_commits(&mut self, action: keymap::Action) -> anyhow::Result<()> {
+        let selected = self.model.tree.selected().as_ref().map(TreeIndex::first);
+        match action {
+            keymap::Action::SelectUp => self.select_up(),
+            keymap::Action::SelectDown => self.select_down(),
+            keymap::Action::SelectFirst => self.select_first(),
+            keymap::Action::SelectLast => self.select_last(),
+            keymap::Action::Toggle => {
+                match self.model.tree.selected().as_ref().map(TreeIndex::as_slice) {
+                    Some([commit_index]) => self.model.toggle_deltas(*commit_index)?,
+                    Some([_, _]) => self.model.show_selected()?,
+                    _ => {}
+                }
+            }
+            keymap::Action::ShowDelta => self.model.show_selected()?,
+            keymap::Action::Edit => {
+                if let Some(commit_index) = selected {
+                    self.model.edit(commit_index)?;
+                    self.message(Message::Shell);
+                }
+            }
+            keymap::Action::Insert => {
+                if let Some(commit_index) = selected {
+                    self.prompt = Some(Prompt::new(
+                        "Insert after selected commit, message: ",
+                        PromptAction::Insert(commit_index),
+                    ));
+                }
+            }
+            keymap::Action::MoveUp => {
+                if let Some(commit_index) = selected {
+                    self.model.plan_swap(commit_index, true)?
+                }
+            }
+            keymap::Action::MoveDown => {
+                if let Some(commit_index) = selected {
+                    self.model.plan_swap(commit_index, false)?
+                }
+            }
+            keymap::Action::Squash => {
+                if let Some(commit_index) = selected {
+                    self.model.plan_toggle(commit_index, Action::Squash)?
+                }
+            }
+            keymap::Action::Drop => {
+                if let Some(commit_index) = selected {
+                    self.model.plan_toggle(commit_index, Action::Drop)?
+                }
+            }
+            keymap::Action::Move => {
+                if let Some(commit_index) = selected {
+                    self.model.plan_move(commit_index)?
+                }
+            }
+            keymap::Action::Describe => {
+                if let Some(commit_index) = selected {
+                    self.model.show_description(commit_index);
+                }
+            }
+            keymap::Action::SwitchWorktree => self.model.switch_worktree()?,
+            keymap::Action::Worktrees => self.model.show_worktrees()?,
+            keymap::Action::RangeDiff => self.model.show_range_diff()?,
+            keymap::Action::Push => self.status = Some(self.model.push()?),
+            keymap::Action::PushBranches => self.status = Some(self.model.push_branches()?),
+            keymap::Action::Search => {
+                self.prompt = Some(Prompt::new(
+                    "Search: ",
+                    PromptAction::Search {
+                        origin: self.model.tree.selected().clone(),
+                        diffs: false,
+                    },
+                ));
+            }
+            keymap::Action::SearchNext | keymap::Action::SearchPrevious => {
+                let forward = action == keymap::Action::SearchNext;
+                let from = self.model.tree.selected().clone();
+                let position = self.model.select_match(from.as_ref(), false, forward)?;
+                if self.model.search.is_some() {
+                    self.status = Some(self.search_status(position));
+                }
+            }
+            keymap::Action::Filter => {
+                let mut prompt =
+                    Prompt::new("Filter (path:, author: or regex): ", PromptAction::Filter);
+                if let Some(filter) = &self.model.filter {
+                    prompt.input = filter.text.clone();
+                }
+                self.prompt = Some(prompt);
+            }
+            keymap::Action::Confirm => self.status = self.model.apply_plan()?,
+            keymap::Action::Cancel if self.model.plan.is_some() => self.model.discard_plan(),
+            keymap::Action::Cancel if self.model.search.is_some() => self.model.clear_search(),
+            keymap::Action::Cancel => self.model.clear_filter()?,
+            keymap::Action::Resume => self.status = self.model.resume()?,
+            keymap::Action::Abort => self.model.abort()?,
+            keymap::Action::Help => self.help = Some(0),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Dims the border of the pane without focus.
+    fn border(&self, focus: Focus) -> Style {
+        if self.focus == focus {
+            self.model.theme.border
+        } else {
+            self.model.theme.inactive_border
+        }
+    }
+
+    fn draw_content(&mut self, area: Rect, buffer: &mut Buffer) -> anyhow::Result<()> {
+        let mut title = match (&self.model.stack.stop, &self.model.plan) {
+            (Some(stop), _) => format!("Commits (editing {:.8})", stop.commit),
+            (None, Some(_)) => "Commits (rewrite planned)".to_owned(),
+            (None, None) => "Commits".to_owned(),
+        };
+        let worktree = self
+            .model
+            .worktree()
+            .filter(|_| self.model.worktrees.len() > 1);
+        if let Some(index) = worktree {
+            title.push_str(&format!(" in {}", self.model.worktrees[index].name));
+        }
+        if let Some(filter) = &self.model.filter {
+            title.push_str(&format!(" matching {}", filter.text));
+        }
+        let preview_block = Block::bordered()
+            .title("Preview")
+            .title_alignment(Alignment::Center)
+            .border_type(BorderType::Rounded)
+            .border_style(self.border(Focus::Preview));
+        let tree = self.model.stack.tree(&self.model.theme).block(
+            Block::bordered()
+                .title(title)
+                .title_alignment(Alignment::Center)
+                .border_type(BorderType::Rounded)
+                .border_style(self.border(Focus::Commits)),
+        );
+
+        let commits_width = match self.split {
+            Some(split) => Constraint::Length(split),
+            None => Constraint::Fill(1),
+        };
+        let layout =
+            Layout::horizontal([commits_width, Constraint::Length(1), Constraint::Fill(2)]);
+        let [tree_area, divider_area, preview_area] = layout.areas(area);
+        StatefulWidget::render(tree, tree_area, buffer, &mut self.model.tree);
+        self.panes = Panes {
+            commits: tree_area,
+            divider: divider_area,
+            preview: preview_area,
+            ..self.panes
+        };
+
+        let preview_inner = preview_block.inner(preview_area);
+        preview_block.render(preview_area, buffer);
+        self.model
+            .preview
+            .render_ref(preview_inner, buffer, &mut self.model.preview_state);
+
+        Ok(())
+    }
+
+    fn help_lines(&self) -> Vec<Line<'static>> {
+        let mut lines = Vec::new();
+        for group in keymap::Group::ALL {
+            if !lines.is_empty() {
+                lines.push(Line::default());
+            }
+            lines.push(Line::from(group.title().bold()));
+            for action in keymap::Action::ALL
+                .into_iter()
+                .filter(|action| action.group() == group)
+            {
+                let bindings = self.keymap.bindings(action);
+                let keys = if bindings.is_empty() {
+                    "unbound".to_owned()
+                } else {
+                    bindings.join(", ")
+                };
+                lines.push(Line::from(vec![
+                    format!("  {:<16}", keys).bold(),
+                    Span::styled(format!("{:<16}", action.name()), self.model.theme.muted),
+                    action.description().into(),
+                ]));
+            }
+        }
+        lines
+    }
+
+    /// How far the help scrolls before its last line is at the bottom.
+    fn max_help_scroll(&self) -> u16 {
+        (self.help_lines().len() as u16 + 2).saturating_sub(self.panes.help.height)
+    }
+
+    fn draw_help(&mut self, area: Rect, buffer: &mut Buffer, scroll: u16) {
+        let lines = self.help_lines();
+        let width = lines.iter().map(Line::width).max().unwrap_or(0) as u16 + 4;
+        let height = lines.len() as u16 + 2;
+        let [area] = Layout::horizontal([Constraint::Length(width)])
+            .flex(Flex::Center)
+            .areas(area);
+        let [area] = Layout::vertical([Constraint::Length(height)])
+            .flex(Flex::Center)
+            .areas(area);
+        self.panes.help = area;
+        let scroll = scroll.min(height.saturating_sub(area.height));
+        Clear.render(area, buffer);
+        Paragraph::new(lines)
+            .scroll((scroll, 0))
+            .block(
+                Block::bordered()
+                    .title("Keys")
+                    .title_alignment(Alignment::Center)
+                    .border_type(BorderType::Rounded)
+                    .padding(Padding::horizontal(1)),
+            )
+            .render(area, buffer);
+    }
+
+    fn draw_footer(&mut self, area: Rect, buffer: &mut Buffer) -> anyhow::Result<()> {
+        // Without a status, show where we are instead.
+        let status = match &self.status {
+            Some(status) => {
+                Line::from(format!(" {} ", status.text)).style(status.style(&self.model.theme))
+            }
+            None => Line::from(format!(" {} ", self.context()))
+                .style(self.model.theme.footer.patch(self.model.theme.muted)),
+        };
+        let layout =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Max(status.width() as u16)]);
+        let [tooltips_area, status_area] = layout.areas(area);
+
+        if let Some(prompt) = &self.prompt {
+            Line::from(vec![
+                " ".to_span(),
+                prompt.label.to_span(),
+                prompt.input.to_span(),
+                "_".to_span(),
+            ])
+            .style(self.model.theme.footer)
+            .render(tooltips_area, buffer);
+            status.render(status_area, buffer);
+            return Ok(());
+        }
+
+        let mut spans: Vec<Span> = Vec::new();
+        match self.keymap.pending() {
+            Some(pending) => spans.push(Span::raw(format!(" {} …", pending))),
+            None => {
+                for (keys, label) in self.keymap.tooltips(&self.tooltips()) {
+                    spans.push(" ".to_span());
+                    spans.push(keys.bold());
+                    spans.push(" ".to_span());
+                    spans.push(Span::raw(label));
+                    spans.push(" ".to_span());
+                }
+            }
+        }
+        Line::from(spans)
+            .style(self.model.theme.footer)
+            .alignment(Alignment::Left)
+            .render(tooltips_area, buffer);
+
+        status.render(status_area, buffer);
+
+        Ok(())
+    }
+}
+
+/// Formats a signature time the way `git log` does, in the author's timezone.
+fn format_time(time: &git2::Time) -> String {
+    let offset = chrono::FixedOffset::east_opt(time.offset_minutes() * 60)
+        .unwrap_or_else(|| chrono::FixedOffset::east_opt(0).unwrap());
+    match chrono::DateTime::from_timestamp(time.seconds(), 0) {
+        Some(date) => date
+            .with_timezone(&offset)
+            .format("%a %b %-d %H:%M:%S %Y %z")
+            .to_string(),
+        None => time.seconds().to_string(),
+    }
+}
+
+/// The path of a changed file, or both paths if it was renamed.
+fn delta_path(delta: &DiffDelta) -> String {
+    let old = delta.old_file().path().map(Path::display);
+    let new = delta.new_file().path().map(Path::display);
+    match (delta.status(), old, new) {
+        (Delta::Renamed | Delta::Copied, Some(old), Some(new)) => format!("{} → {}", old, new),
+        (_, _, Some(new)) => new.to_string(),
+        (_, Some(old), None) => old.to_string(),
+        (_, None, None) => "?".to_owned(),
+    }
+}
+
+/// One row per file with its added and removed lines, then the totals.
+fn diffstat(
+    diff: &Diff,
+    patches: &[Option<git2::Patch>],
+    theme: &Theme,
+) -> anyhow::Result<Vec<DiffLine<'static>>> {
+    const BAR: usize = 30;
+
+    let mut rows = Vec::new();
+    for (delta, patch) in diff.deltas().zip(patches) {
+        let changes = match patch {
+            Some(patch) if !delta.flags().is_binary() => {
+                let (_, insertions, deletions) = patch
+                    .line_stats()
+                    .context("failed to compute the lines changed")?;
+                Some((insertions, deletions))
+            }
+            _ => None,
+        };
+        rows.push((delta_path(&delta), changes));
+    }
+
+    let width = rows
+        .iter()
+        .map(|(path, _)| path.chars().count())
+        .max()
+        .unwrap_or(0);
+    let most = rows
+        .iter()
+        .filter_map(|(_, changes)| changes.map(|(insertions, deletions)| insertions + deletions))
+        .max()
+        .unwrap_or(0);
+    let digits = most.to_string().len();
+
+    let mut lines = Vec::new();
+    for (path, changes) in &rows {
+        let mut spans = vec![Span::raw(format!(" {:width$} | ", path))];
+        match changes {
+            Some((insertions, deletions)) => {
+                let total = insertions + deletions;
+                // Scale the bar down only when the largest change would not fit.
+                let scale = |count: usize| {
+                    if most > BAR {
+                        (count * BAR).div_ceil(most)
+                    } else {
+                        count
+                    }
+                };
+                spans.push(Span::raw(format!("{:>digits$} ", total)));
+                spans.push(Span::styled("+".repeat(scale(*insertions)), theme.added));
+                spans.push(Span::styled("-".repeat(scale(*deletions)), theme.removed));
+            }
+            None => spans.push(Span::raw("Bin")),
+        }
+        lines.push(DiffLine::from(Line::from(spans)));
+    }
+
+    let stats = diff.stats().context("failed to compute diff stats")?;
+    lines.push(DiffLine::from(Line::from(vec![
+        match stats.files_changed() {
+            1 => " 1 file changed".into(),
+            n => format!(" {} files changed", n).into(),
+        },
+        Span::styled(format!(", +{}", stats.insertions()), theme.added),
+        Span::styled(format!(" -{}", stats.deletions()), theme.removed),
+    ])));
+    Ok(lines)
+}
+
+/// The hunks of a file, or a note when there is no text to show.
+fn patch_lines(
+    patch: Option<&git2::Patch>,
+    theme: &Theme,
+) -> anyhow::Result<Vec<DiffLine<'static>>> {
+    let Some(patch) = patch.filter(|patch| !patch.delta().flags().is_binary()) else {
+        return Ok(vec![DiffLine::from(
+            Line::from("binary file").style(theme.muted),
+        )]);
+    };
+
+    let mut lines = Vec::new();
+    for hunk_index in 0..patch.num_hunks() {
+        let (hunk, length) = patch
+            .hunk(hunk_index)
+            .context("failed to retrieve diff hunk")?;
+        let text = String::from_utf8_lossy(hunk.header());
+        lines.push(DiffLine::new(
+            Line::from(text.trim_end().to_owned()).style(theme.hunk),
+            LineKind::Hunk,
+        ));
+
+        for line_index in 0..length {
+            let line = patch
+                .line_in_hunk(hunk_index, line_index)
+                .context("failed to retrieve diff line")?;
+            let text = String::from_utf8_lossy(line.content());
+            let text = text.trim_end_matches('\n');
+            lines.push(match line.origin() {
+                '+' => DiffLine::new(
+                    Line::from(format!("+ {}", text)).style(theme.added),
+                    LineKind::Added,
+                ),
+                '-' => DiffLine::new(
+                    Line::from(format!("- {}", text)).style(theme.removed),
+                    LineKind::Removed,
+                ),
+                ' ' => DiffLine::new(Line::from(format!("  {}", text)), LineKind::Context),
+                _ => DiffLine::from(Line::from(text.trim().to_owned()).style(theme.muted)),
+            });
+        }
+    }
+    Ok(lines)
+}
+
+/// What to show while an action that may take a while runs.
+fn progress(action: keymap::Action) -> Option<&'static str> {
+    match action {
+        keymap::Action::Confirm => Some("rewriting the stack…"),
+        keymap::Action::Resume => Some("amending and continuing…"),
+        keymap::Action::Push => Some("pushing…"),
+        keymap::Action::PushBranches => Some("pushing branches…"),
+        keymap::Action::RangeDiff => Some("comparing with the previous version…"),
+        _ => None,
+    }
 }
 
 // MARK: Main
 
 pub struct Options {
     pub base: String,
+    pub metadata: Metadata,
 }
 
 pub fn with_terminal<T, F: FnOnce(DefaultTerminal) -> T>(f: F) -> T {
@@ -603,23 +2431,89 @@ pub fn with_terminal<T, F: FnOnce(DefaultTerminal) -> T>(f: F) -> T {
     result
 }
 
-pub fn main(repo: &Repository, options: Options) -> anyhow::Result<()> {
-    with_terminal(|mut terminal| {
-        let mut controller = Controller::new(Model::new(repo));
-        controller.message(Message::Load(options.base));
+/// Captures the mouse until dropped, which happens on a panic too, so the
+/// shell is not left receiving mouse events.
+struct MouseCapture;
 
-        loop {
-            let mut result = Ok(());
-            terminal.draw(|frame| result = controller.draw(frame))?;
-            result?;
+impl MouseCapture {
+    fn enable() -> Self {
+        // Without the mouse the terminal still works, so this is not an error.
+        let _ = crossterm::execute!(std::io::stdout(), crossterm::event::EnableMouseCapture);
+        Self
+    }
+}
 
-            match controller.queue.front() {
-                Some(Message::Exit) => break,
-                None => controller.message(Message::Terminal(crossterm::event::read()?)),
-                _ => {}
+impl Drop for MouseCapture {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(std::io::stdout(), crossterm::event::DisableMouseCapture);
+    }
+}
+
+fn shell(repo: &Repository, stop: &Stop) -> anyhow::Result<()> {
+    let commit = repo.find_commit(stop.commit)?;
+    eprintln!(
+        "Stopped at {:.8} {}",
+        commit.id(),
+        commit.summary().unwrap_or("")
+    );
+    eprintln!("Make your changes, then exit the shell to amend and continue the stack.");
+
+    let program = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned());
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow::format_err!("cannot edit in a bare repository"))?;
+    Command::new(&program)
+        .current_dir(workdir)
+        .status()
+        .with_context(|| format!("failed to run {}", program))?;
+    Ok(())
+}
+
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<()> {
+    let worktrees = worktree::list(repo)?;
+    let repo = worktrees
+        .iter()
+        .find(|worktree| worktree.is(repo))
+        .map_or(repo, |worktree| &worktree.repo);
+    let forge = forge::from_config(repo)?;
+    let rules = Rules::from_config(repo)?;
+    let keymap = Keymap::from_config(repo)?;
+    let theme = Theme::from_config(repo)?;
+    let mut controller = Controller::new(
+        Model::new(repo, &worktrees, options.metadata, forge, rules, theme),
+        keymap,
+    );
+    controller.message(Message::Load(options.base));
+
+    loop {
+        with_terminal(|mut terminal| -> anyhow::Result<()> {
+            let _mouse = MouseCapture::enable();
+            loop {
+                let mut result = Ok(());
+                terminal.draw(|frame| result = controller.draw(frame))?;
+                result?;
+
+                match controller.queue.front() {
+                    Some(Message::Exit | Message::Shell) => break,
+                    None => controller.message(Message::Terminal(crossterm::event::read()?)),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })?;
+
+        match controller.queue.pop_front() {
+            Some(Message::Shell) => {
+                if let Some(stop) = &controller.model.stack.stop {
+                    match shell(controller.model.repo, stop) {
+                        Ok(()) => controller.message(Message::Resume),
+                        Err(error) => controller.status = Some(Status::error(&error)),
+                    }
+                }
             }
+            _ => break,
         }
+    }
 
-        Ok(())
-    })
+    Ok(())
 }
@@ -0,0 +1,486 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use crossterm::event::Event;
+use git2::{BranchType, ErrorCode, Oid, Repository};
+use ratatui::layout::Alignment;
+use ratatui::prelude::Stylize;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, BorderType};
+use ratatui::DefaultTerminal;
+use ratatui_tree::{Tree, TreeIndex, TreeItem, TreeState, TreeView};
+
+use crate::keymap;
+use crate::keymap::Keymap;
+use crate::rewrite;
+use crate::rewrite::Metadata;
+use crate::stack;
+use crate::stack::with_terminal;
+use crate::theme::Theme;
+use crate::worktree;
+
+// MARK: Node
+
+/// A branch, or a group of branches forking from the same commit that no
+/// branch points at, and the commits they add. The refname is the branch to
+/// open, which the commits of a group do not have.
+enum StackNode {
+    Branch {
+        name: String,
+        refname: Option<String>,
+        children: Vec<StackNode>,
+        is_collapsed: bool,
+    },
+    Commit {
+        id: Oid,
+        summary: String,
+        refname: Option<String>,
+    },
+}
+
+impl StackNode {
+    fn refname(&self) -> Option<&str> {
+        match self {
+            StackNode::Branch { refname, .. } | StackNode::Commit { refname, .. } => {
+                refname.as_deref()
+            }
+        }
+    }
+
+    fn item(&self, theme: &Theme) -> TreeItem<'_> {
+        match self {
+            StackNode::Branch {
+                name,
+                children,
+                is_collapsed,
+                ..
+            } => {
+                let commits = children
+                    .iter()
+                    .filter(|child| matches!(child, StackNode::Commit { .. }))
+                    .count();
+                let icon: &'static str = if *is_collapsed { " + " } else { " - " };
+                let label = Line::from(vec![
+                    icon.into(),
+                    name.as_str().bold(),
+                    Span::styled(
+                        match commits {
+                            1 => " (1 commit)".to_owned(),
+                            n => format!(" ({} commits)", n),
+                        },
+                        theme.muted,
+                    ),
+                ]);
+                if *is_collapsed {
+                    TreeItem::new_empty(label)
+                } else {
+                    TreeItem::new(label, children.iter().map(|child| child.item(theme)))
+                }
+            }
+            StackNode::Commit { id, summary, .. } => TreeItem::new_empty(Line::from(vec![
+                "   ".into(),
+                Span::styled(format!("{:.8}", id), theme.hash),
+                " ".into(),
+                summary.as_str().into(),
+            ])),
+        }
+    }
+}
+
+impl TreeView<StackNode> for StackNode {
+    type ChildIter<'a> = std::slice::Iter<'a, StackNode>;
+
+    fn iter_children(&self) -> Self::ChildIter<'_> {
+        match self {
+            StackNode::Branch {
+                children,
+                is_collapsed: false,
+                ..
+            } => children.iter(),
+            _ => std::slice::Iter::default(),
+        }
+    }
+}
+
+struct Overview {
+    roots: Vec<StackNode>,
+}
+
+impl TreeView<StackNode> for Overview {
+    type ChildIter<'a> = std::slice::Iter<'a, StackNode>;
+
+    fn iter_children(&self) -> Self::ChildIter<'_> {
+        self.roots.iter()
+    }
+}
+
+// MARK: Load
+
+struct BranchInfo {
+    name: String,
+    refname: String,
+    tip: Oid,
+    commits: Vec<Oid>,
+}
+
+fn commit_node(repo: &Repository, id: Oid, refname: Option<&str>) -> anyhow::Result<StackNode> {
+    let commit = repo
+        .find_commit(id)
+        .with_context(|| format!("failed to find commit {}", id))?;
+    Ok(StackNode::Commit {
+        id,
+        summary: commit.summary().unwrap_or("").to_owned(),
+        refname: refname.map(str::to_owned),
+    })
+}
+
+/// Builds the node of a branch, leaving out the `shared` commits shown by the
+/// group it is in, if any.
+fn build(
+    repo: &Repository,
+    branches: &[BranchInfo],
+    parents: &[Option<usize>],
+    index: usize,
+    shared: &HashSet<Oid>,
+) -> anyhow::Result<StackNode> {
+    let branch = &branches[index];
+    let mut children = Vec::new();
+
+    // Only the commits this branch adds on top of its parent belong to it.
+    let inherited = match parents[index] {
+        Some(parent) => branches[parent].commits.iter().copied().collect(),
+        None => shared.clone(),
+    };
+    for &id in branch.commits.iter().filter(|id| !inherited.contains(id)) {
+        children.push(commit_node(repo, id, Some(&branch.refname))?);
+    }
+    for child in (0..branches.len()).filter(|&child| parents[child] == Some(index)) {
+        children.push(build(repo, branches, parents, child, &HashSet::new())?);
+    }
+
+    Ok(StackNode::Branch {
+        name: branch.name.clone(),
+        refname: Some(branch.refname.clone()),
+        children,
+        is_collapsed: false,
+    })
+}
+
+/// Builds a group of the `members`, branches with no parent that fork from
+/// the same commit, below the commits they all share.
+fn build_group(
+    repo: &Repository,
+    branches: &[BranchInfo],
+    parents: &[Option<usize>],
+    members: &[usize],
+) -> anyhow::Result<StackNode> {
+    let first = &branches[members[0]];
+    let shared = first
+        .commits
+        .iter()
+        .copied()
+        .filter(|id| {
+            members[1..]
+                .iter()
+                .all(|&member| branches[member].commits.contains(id))
+        })
+        .collect::<Vec<_>>();
+
+    let mut children = Vec::new();
+    for &id in &shared {
+        children.push(commit_node(repo, id, None)?);
+    }
+    let shared = shared.into_iter().collect::<HashSet<_>>();
+    for &member in members {
+        children.push(build(repo, branches, parents, member, &shared)?);
+    }
+
+    Ok(StackNode::Branch {
+        name: format!("fork at {:.8}", first.commits[0]),
+        refname: None,
+        children,
+        is_collapsed: false,
+    })
+}
+
+/// Groups the local branches with commits that are not on `base` into trees,
+/// where a branch is the child of the nearest other branch it is stacked on
+/// and branches forking from the same unbranched commit are grouped.
+fn load(repo: &Repository, base: &str) -> anyhow::Result<Vec<StackNode>> {
+    let base_id = repo
+        .revparse_single(base)
+        .and_then(|object| object.peel_to_commit())
+        .with_context(|| format!("failed to resolve base commit {}", base))?
+        .id();
+    let mut branches = Vec::new();
+    for result in repo
+        .branches(Some(BranchType::Local))
+        .context("failed to list branches")?
+    {
+        let (branch, _) = result.context("failed to read branch")?;
+        let reference = branch.into_reference();
+        let (Some(refname), Some(name), Some(tip)) =
+            (reference.name(), reference.shorthand(), reference.target())
+        else {
+            continue;
+        };
+        // Branches with unrelated history, like gh-pages, are not stacks.
+        let merge_base = match repo.merge_base(base_id, tip) {
+            Ok(merge_base) => merge_base,
+            Err(error) if error.code() == ErrorCode::NotFound => continue,
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("failed to resolve merge base of {}", name))
+            }
+        };
+        let commits = rewrite::commits_between(repo, merge_base, tip)?;
+        if !commits.is_empty() {
+            branches.push(BranchInfo {
+                name: name.to_owned(),
+                refname: refname.to_owned(),
+                tip,
+                commits,
+            });
+        }
+    }
+    branches.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let parents = branches
+        .iter()
+        .map(|branch| {
+            branches
+                .iter()
+                .enumerate()
+                .filter(|(_, other)| other.tip != branch.tip && branch.commits.contains(&other.tip))
+                .max_by_key(|(_, other)| other.commits.len())
+                .map(|(index, _)| index)
+        })
+        .collect::<Vec<_>>();
+
+    // Branches stacked on no other branch but starting from the same commit
+    // forked from an unbranched commit, so they go in a group together.
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for index in (0..branches.len()).filter(|&index| parents[index].is_none()) {
+        let oldest = branches[index].commits[0];
+        match groups
+            .iter_mut()
+            .find(|group| branches[group[0]].commits[0] == oldest)
+        {
+            Some(group) => group.push(index),
+            None => groups.push(vec![index]),
+        }
+    }
+    groups
+        .iter()
+        .map(|group| match group.as_slice() {
+            [index] => build(repo, &branches, &parents, *index, &HashSet::new()),
+            members => build_group(repo, &branches, &parents, members),
+        })
+        .collect()
+}
+
+// MARK: Picker
+
+/// Shows the overview until a branch is picked, returning its refname.
+fn pick(
+    mut terminal: DefaultTerminal,
+    overview: &mut Overview,
+    state: &mut TreeState,
+    keymap: &mut Keymap,
+    theme: &Theme,
+    base: &str,
+) -> anyhow::Result<Option<String>> {
+    loop {
+        terminal.draw(|frame| {
+            let tree = Tree::new(overview.roots.iter().map(|node| node.item(theme)))
+                .indent_symbol("    ")
+                .highlight_style(theme.selection)
+                .block(
+                    Block::bordered()
+                        .title(format!("Stacks on {}", base))
+                        .title_alignment(Alignment::Center)
+                        .border_type(BorderType::Rounded),
+                );
+            frame.render_stateful_widget(tree, frame.area(), state);
+        })?;
+
+        let Event::Key(key) = crossterm::event::read()? else {
+            continue;
+        };
+        if !key.is_press() {
+            continue;
+        }
+        match keymap.feed(&key) {
+            Some(keymap::Action::Quit) => return Ok(None),
+            Some(keymap::Action::SelectUp) => state.select(
+                state
+                    .selected()
+                    .as_ref()
+                    .and_then(|index| overview.find_previous_relative_of(index))
+                    .map(|(index, _)| index),
+            ),
+            Some(keymap::Action::SelectDown) => state.select(
+                state
+                    .selected()
+                    .as_ref()
+                    .and_then(|index| overview.find_next_relative_of(index))
+                    .map(|(index, _)| index),
+            ),
+            Some(keymap::Action::Toggle) => {
+                let Some(index) = state.selected().clone() else {
+                    continue;
+                };
+                let mut node = &mut overview.roots[index.first()];
+                for &i in index.iter_rest() {
+                    let StackNode::Branch { children, .. } = node else {
+                        break;
+                    };
+                    node = &mut children[i];
+                }
+                if let StackNode::Branch { is_collapsed, .. } = node {
+                    *is_collapsed = !*is_collapsed;
+                }
+            }
+            Some(keymap::Action::Confirm) => {
+                let selected = state
+                    .selected()
+                    .as_ref()
+                    .and_then(|index| overview.get_descendant(index));
+                if let Some(refname) = selected.and_then(StackNode::refname) {
+                    return Ok(Some(refname.to_owned()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// MARK: Main
+
+pub struct Options {
+    pub base: String,
+    pub metadata: Metadata,
+}
+
+/// Lists the stacks in the repository and opens the stack view for the one
+/// picked, in the worktree it is checked out in or by checking it out here.
+pub fn main(repo: &Repository, options: Options) -> anyhow::Result<()> {
+    let mut state = TreeState::new();
+    let mut keymap = Keymap::from_config(repo)?;
+    let theme = Theme::from_config(repo)?;
+    loop {
+        let mut overview = Overview {
+            roots: load(repo, &options.base)?,
+        };
+        if state.selected().is_none() && !overview.roots.is_empty() {
+            state.select(Some(TreeIndex::new(0)));
+        }
+        let Some(refname) = with_terminal(|terminal| {
+            pick(
+                terminal,
+                &mut overview,
+                &mut state,
+                &mut keymap,
+                &theme,
+                &options.base,
+            )
+        })?
+        else {
+            return Ok(());
+        };
+
+        let worktrees = worktree::list(repo)?;
+        let mut target = None;
+        for worktree in &worktrees {
+            if worktree.branch()?.as_deref() == Some(refname.as_str()) {
+                target = Some(&worktree.repo);
+            }
+        }
+        let target = match target {
+            Some(target) => target,
+            None => {
+                rewrite::switch_branch(repo, &refname)?;
+                repo
+            }
+        };
+        stack::main(
+            target,
+            stack::Options {
+                base: options.base.clone(),
+                metadata: options.metadata,
+            },
+        )?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    /// The tree as indented `name` and `id` lines.
+    fn outline(nodes: &[StackNode], depth: usize, lines: &mut Vec<String>) {
+        for node in nodes {
+            let indent = "  ".repeat(depth);
+            match node {
+                StackNode::Branch { name, children, .. } => {
+                    lines.push(format!("{}{}", indent, name));
+                    outline(children, depth + 1, lines);
+                }
+                StackNode::Commit { summary, .. } => lines.push(format!("{}{}", indent, summary)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_groups_stacks() {
+        let repo = TempRepo::new("stacks-load");
+        let base = repo.commit("base.txt", "base\n", "base");
+
+        // Two branches forking from an unbranched commit, one stacked on the
+        // other, and another stack.
+        repo.switch("first");
+        let shared = repo.commit("shared.txt", "shared\n", "shared");
+        repo.commit("a.txt", "a\n", "a");
+        repo.switch("first-child");
+        repo.commit("a2.txt", "a2\n", "a2");
+        repo.repo.set_head_detached(shared).unwrap();
+        repo.switch("second");
+        repo.commit("b.txt", "b\n", "b");
+        repo.repo.set_head_detached(base).unwrap();
+        repo.switch("other");
+        repo.commit("c.txt", "c\n", "c");
+
+        // A branch with no history in common with main is left out.
+        let signature = TempRepo::signature();
+        let tree = repo.repo.find_commit(base).unwrap().tree().unwrap();
+        repo.repo
+            .commit(
+                Some("refs/heads/gh-pages"),
+                &signature,
+                &signature,
+                "pages",
+                &tree,
+                &[],
+            )
+            .unwrap();
+
+        let mut lines = Vec::new();
+        outline(&load(&repo.repo, "main").unwrap(), 0, &mut lines);
+        assert_eq!(
+            lines,
+            vec![
+                format!("fork at {:.8}", shared),
+                "  shared".to_owned(),
+                "  first".to_owned(),
+                "    a".to_owned(),
+                "    first-child".to_owned(),
+                "      a2".to_owned(),
+                "  second".to_owned(),
+                "    b".to_owned(),
+                "other".to_owned(),
+                "  c".to_owned(),
+            ]
+        );
+    }
+}
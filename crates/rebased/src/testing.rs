@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use git2::Oid;
+use git2::Repository;
+use git2::RepositoryInitOptions;
+use git2::Signature;
+
+/// A directory under the system temporary directory, removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rebased-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A scratch repository on `main` with an identity configured.
+pub struct TempRepo {
+    pub repo: Repository,
+    dir: TempDir,
+}
+
+impl TempRepo {
+    pub fn new(name: &str) -> Self {
+        Self::init(name, false)
+    }
+
+    pub fn bare(name: &str) -> Self {
+        Self::init(name, true)
+    }
+
+    fn init(name: &str, bare: bool) -> Self {
+        let dir = TempDir::new(name);
+        let repo = Repository::init_opts(
+            dir.path(),
+            RepositoryInitOptions::new().bare(bare).initial_head("main"),
+        )
+        .unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Tester").unwrap();
+        config.set_str("user.email", "tester@example.com").unwrap();
+        Self { repo, dir }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn signature() -> Signature<'static> {
+        Signature::new(
+            "Tester",
+            "tester@example.com",
+            &git2::Time::new(1_700_000_000, 0),
+        )
+        .unwrap()
+    }
+
+    /// Writes a file, stages it and commits it on HEAD.
+    pub fn commit(&self, path: &str, content: &str, message: &str) -> Oid {
+        fs::write(self.path().join(path), content).unwrap();
+        let mut index = self.repo.index().unwrap();
+        index.add_path(Path::new(path)).unwrap();
+        index.write().unwrap();
+        let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = self
+            .repo
+            .head()
+            .ok()
+            .and_then(|head| head.peel_to_commit().ok());
+        let signature = Self::signature();
+        self.repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parent.iter().collect::<Vec<_>>(),
+            )
+            .unwrap()
+    }
+
+    /// Points `branch` at HEAD and checks it out.
+    pub fn switch(&self, branch: &str) {
+        let head = self.repo.head().unwrap().peel_to_commit().unwrap();
+        self.repo.branch(branch, &head, true).unwrap();
+        self.repo
+            .set_head(&format!("refs/heads/{}", branch))
+            .unwrap();
+    }
+}
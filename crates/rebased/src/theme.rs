@@ -0,0 +1,368 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use anyhow::Context;
+use git2::Repository;
+use ratatui::style::Color;
+use ratatui::style::Modifier;
+use ratatui::style::Style;
+use ratatui::style::Stylize;
+
+use crate::keymap::config_path;
+
+// MARK: Theme
+
+/// The styles of everything drawn in colour, named as in the config.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Theme {
+    /// The selected row of a tree.
+    pub selection: Style,
+    pub added: Style,
+    pub removed: Style,
+    pub hunk: Style,
+    pub hash: Style,
+    /// Branch names and other references.
+    pub decoration: Style,
+    /// The border of the focused pane.
+    pub border: Style,
+    /// The border of the other pane.
+    pub inactive_border: Style,
+    pub footer: Style,
+    /// Notes and other text that is less important than what is around it.
+    pub muted: Style,
+    pub search: Style,
+    pub warning: Style,
+    pub success: Style,
+    pub error: Style,
+}
+
+impl Theme {
+    /// Colours for a dark terminal background.
+    pub fn dark() -> Self {
+        Self {
+            selection: Style::new().bold().reversed(),
+            added: Style::new().green(),
+            removed: Style::new().red(),
+            hunk: Style::new().cyan(),
+            hash: Style::new().yellow(),
+            decoration: Style::new().dark_gray(),
+            border: Style::new(),
+            inactive_border: Style::new().dark_gray(),
+            footer: Style::new().reversed(),
+            muted: Style::new().dark_gray(),
+            search: Style::new().black().on_yellow(),
+            warning: Style::new().yellow(),
+            success: Style::new().black().on_green(),
+            error: Style::new().white().on_red().bold(),
+        }
+    }
+
+    /// Colours that stay readable on a light background, where yellow is not.
+    pub fn light() -> Self {
+        Self {
+            hash: Style::new().blue(),
+            decoration: Style::new().magenta(),
+            warning: Style::new().magenta().bold(),
+            ..Self::dark()
+        }
+    }
+
+    /// No colours at all, only bold, reversed and the like.
+    pub fn none() -> Self {
+        Self {
+            selection: Style::new().bold().reversed(),
+            added: Style::new(),
+            removed: Style::new(),
+            hunk: Style::new(),
+            hash: Style::new(),
+            decoration: Style::new(),
+            border: Style::new(),
+            inactive_border: Style::new().dim(),
+            footer: Style::new().reversed(),
+            muted: Style::new().dim(),
+            search: Style::new().underlined(),
+            warning: Style::new().bold(),
+            success: Style::new().reversed(),
+            error: Style::new().reversed().bold(),
+        }
+    }
+
+    pub fn preset(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "dark" => Ok(Self::dark()),
+            "light" => Ok(Self::light()),
+            "none" => Ok(Self::none()),
+            other => anyhow::bail!("unknown theme preset {}", other),
+        }
+    }
+
+    fn slot_mut(&mut self, name: &str) -> Option<&mut Style> {
+        match name {
+            "selection" => Some(&mut self.selection),
+            "added" => Some(&mut self.added),
+            "removed" => Some(&mut self.removed),
+            "hunk" => Some(&mut self.hunk),
+            "hash" => Some(&mut self.hash),
+            "decoration" => Some(&mut self.decoration),
+            "border" => Some(&mut self.border),
+            "inactive-border" => Some(&mut self.inactive_border),
+            "footer" => Some(&mut self.footer),
+            "muted" => Some(&mut self.muted),
+            "search" => Some(&mut self.search),
+            "warning" => Some(&mut self.warning),
+            "success" => Some(&mut self.success),
+            "error" => Some(&mut self.error),
+            _ => None,
+        }
+    }
+
+    /// Loads the theme from `~/.config/rebased/config.toml` and then git
+    /// config, which takes precedence. Both pick a preset and restyle slots,
+    /// either in a `[theme]` table:
+    ///
+    /// ```toml
+    /// [theme]
+    /// preset = "light"
+    /// hash = "bold blue"
+    /// search = "black on #ffd700"
+    /// ```
+    ///
+    /// or with `rebased.theme` and `rebased.theme.<slot>`. The preset is
+    /// `none` when `NO_COLOR` is set, unless the config picks one.
+    pub fn from_config(repo: &Repository) -> anyhow::Result<Self> {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        let mut settings = Settings::new(no_color);
+        if let Some(path) = config_path() {
+            match fs::read_to_string(&path) {
+                Ok(text) => settings
+                    .read_toml(&text)
+                    .with_context(|| format!("invalid theme in {}", path.display()))?,
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                Err(error) => {
+                    return Err(error).with_context(|| format!("failed to read {}", path.display()))
+                }
+            }
+        }
+        settings
+            .read_git_config(repo)
+            .context("invalid theme in git config")?;
+        settings.theme()
+    }
+}
+
+// MARK: Styles
+
+/// Parses a style the way git parses colours: attributes and up to two
+/// colours, the foreground then the background, which may also be given
+/// after `on`, as in `bold white on red`. As in git, `normal` leaves its
+/// colour alone, so `normal red` only sets the background, and `default` is
+/// the terminal's own colour.
+fn parse_style(text: &str) -> anyhow::Result<Style> {
+    let mut style = Style::new();
+    let mut colors = 0;
+    let mut words = text.split_whitespace();
+    while let Some(word) = words.next() {
+        let modifier = match word {
+            "bold" => Modifier::BOLD,
+            "dim" => Modifier::DIM,
+            "italic" => Modifier::ITALIC,
+            "underline" | "underlined" => Modifier::UNDERLINED,
+            "blink" => Modifier::SLOW_BLINK,
+            "reverse" | "reversed" => Modifier::REVERSED,
+            "strike" | "crossed-out" => Modifier::CROSSED_OUT,
+            "on" => {
+                let color = words
+                    .next()
+                    .ok_or_else(|| anyhow::format_err!("missing colour after on"))?;
+                if let Some(color) = parse_color(color)? {
+                    style = style.bg(color);
+                }
+                colors = 2;
+                continue;
+            }
+            _ => {
+                let color = parse_color(word)?;
+                style = match (colors, color) {
+                    (2.., _) => anyhow::bail!("too many colours in {}", text),
+                    (_, None) => style,
+                    (0, Some(color)) => style.fg(color),
+                    (_, Some(color)) => style.bg(color),
+                };
+                colors += 1;
+                continue;
+            }
+        };
+        style = style.add_modifier(modifier);
+    }
+    Ok(style)
+}
+
+/// Parses a colour, or `normal` for none.
+fn parse_color(word: &str) -> anyhow::Result<Option<Color>> {
+    match word {
+        "normal" => Ok(None),
+        "default" => Ok(Some(Color::Reset)),
+        _ => Color::from_str(word)
+            .map(Some)
+            .map_err(|_| anyhow::format_err!("unknown colour or attribute {}", word)),
+    }
+}
+
+// MARK: Config
+
+/// The preset and the slots that replace its own, in the order read.
+struct Settings {
+    preset: String,
+    overrides: Vec<(String, String)>,
+}
+
+impl Settings {
+    /// No overrides on the `dark` preset, or `none` if `NO_COLOR` is set.
+    fn new(no_color: bool) -> Self {
+        Self {
+            preset: if no_color { "none" } else { "dark" }.to_owned(),
+            overrides: Vec::new(),
+        }
+    }
+
+    fn theme(self) -> anyhow::Result<Theme> {
+        let mut theme = Theme::preset(&self.preset)?;
+        for (slot, text) in self.overrides {
+            let style =
+                parse_style(&text).with_context(|| format!("invalid style for {}", slot))?;
+            if let Some(slot) = theme.slot_mut(&slot) {
+                *slot = style;
+            }
+        }
+        Ok(theme)
+    }
+
+    fn push(&mut self, slot: &str, text: String) -> anyhow::Result<()> {
+        if Theme::none().slot_mut(slot).is_none() {
+            anyhow::bail!("unknown theme slot {}", slot);
+        }
+        self.overrides.push((slot.to_owned(), text));
+        Ok(())
+    }
+
+    fn read_toml(&mut self, text: &str) -> anyhow::Result<()> {
+        let table = text.parse::<toml::Table>().context("failed to parse")?;
+        let Some(theme) = table.get("theme") else {
+            return Ok(());
+        };
+        let theme = theme
+            .as_table()
+            .ok_or_else(|| anyhow::format_err!("theme is not a table"))?;
+        for (name, value) in theme {
+            let value = value
+                .as_str()
+                .ok_or_else(|| anyhow::format_err!("theme.{} is not a string", name))?;
+            match name.as_str() {
+                "preset" => self.preset = value.to_owned(),
+                slot => self.push(slot, value.to_owned())?,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_git_config(&mut self, repo: &Repository) -> anyhow::Result<()> {
+        let config = repo.config().context("failed to open git config")?;
+        if let Ok(preset) = config.get_string("rebased.theme") {
+            self.preset = preset;
+        }
+
+        let mut entries = config
+            .entries(Some("rebased\\.theme\\..*"))
+            .context("failed to read git config")?;
+        while let Some(entry) = entries.next() {
+            let entry = entry.context("failed to read git config")?;
+            let (Some(name), Some(value)) = (entry.name(), entry.value()) else {
+                continue;
+            };
+            self.push(name.trim_start_matches("rebased.theme."), value.to_owned())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempRepo;
+
+    #[test]
+    fn test_parse_style() {
+        assert_eq!(
+            parse_style("bold white on red").unwrap(),
+            Style::new().bold().white().on_red()
+        );
+        assert_eq!(
+            parse_style("yellow blue").unwrap(),
+            Style::new().yellow().on_blue()
+        );
+        assert_eq!(
+            parse_style("#ffd700 reverse").unwrap(),
+            Style::new().fg(Color::Rgb(0xff, 0xd7, 0x00)).reversed()
+        );
+        // `normal` takes the foreground slot without setting it.
+        assert_eq!(parse_style("normal red").unwrap(), Style::new().on_red());
+        assert_eq!(
+            parse_style("default").unwrap(),
+            Style::new().fg(Color::Reset)
+        );
+        assert_eq!(parse_style("").unwrap(), Style::new());
+        assert!(parse_style("red green blue").is_err());
+        assert!(parse_style("red on").is_err());
+        assert!(parse_style("sparkly").is_err());
+    }
+
+    #[test]
+    fn test_presets() {
+        let theme = |toml: &str| {
+            let mut settings = Settings::new(false);
+            settings.read_toml(toml).unwrap();
+            settings.theme().unwrap()
+        };
+        assert_eq!(theme(""), Theme::dark());
+        assert_eq!(theme("[theme]\npreset = \"light\""), Theme::light());
+        assert_eq!(
+            theme("[theme]\npreset = \"none\"\nhash = \"bold blue\""),
+            Theme {
+                hash: Style::new().bold().blue(),
+                ..Theme::none()
+            }
+        );
+
+        let mut settings = Settings::new(false);
+        assert!(settings.read_toml("[theme]\nsparkle = \"red\"").is_err());
+        settings.read_toml("[theme]\npreset = \"neon\"").unwrap();
+        assert!(settings.theme().is_err());
+    }
+
+    #[test]
+    fn test_no_color() {
+        assert_eq!(Settings::new(true).theme().unwrap(), Theme::none());
+
+        // A preset picked in the config wins over NO_COLOR, in either place.
+        let mut settings = Settings::new(true);
+        settings.read_toml("[theme]\npreset = \"light\"").unwrap();
+        assert_eq!(settings.theme().unwrap(), Theme::light());
+
+        let repo = TempRepo::new("theme");
+        let mut config = repo.repo.config().unwrap();
+        config.set_str("rebased.theme", "dark").unwrap();
+        config
+            .set_str("rebased.theme.search", "normal green")
+            .unwrap();
+        let mut settings = Settings::new(true);
+        settings.read_git_config(&repo.repo).unwrap();
+        assert_eq!(
+            settings.theme().unwrap(),
+            Theme {
+                search: Style::new().on_green(),
+                ..Theme::dark()
+            }
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use anyhow::Context;
+use git2::Repository;
+
+use crate::rewrite::Stop;
+
+// MARK: Worktree
+
+pub struct Worktree {
+    pub name: String,
+    pub repo: Repository,
+}
+
+impl Worktree {
+    pub fn path(&self) -> &Path {
+        self.repo.workdir().unwrap_or_else(|| self.repo.path())
+    }
+
+    /// The branch checked out in this worktree, counting the branch of an
+    /// edit in progress, during which HEAD is detached.
+    pub fn branch(&self) -> anyhow::Result<Option<String>> {
+        if let Some(stop) = Stop::load(&self.repo)? {
+            return Ok(stop.branch);
+        }
+        let head = self
+            .repo
+            .find_reference("HEAD")
+            .with_context(|| format!("failed to resolve HEAD in {}", self.path().display()))?;
+        Ok(head.symbolic_target().map(str::to_owned))
+    }
+
+    pub fn is(&self, repo: &Repository) -> bool {
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        canonical(self.repo.path()) == canonical(repo.path())
+    }
+}
+
+/// Lists the main worktree, unless the repository is bare, followed by the
+/// linked worktrees that still exist.
+pub fn list(repo: &Repository) -> anyhow::Result<Vec<Worktree>> {
+    let main = Repository::open(repo.commondir()).context("failed to open main worktree")?;
+    let names = main.worktrees().context("failed to list worktrees")?;
+
+    let mut worktrees = Vec::new();
+    for name in names.iter().flatten() {
+        let worktree = main
+            .find_worktree(name)
+            .with_context(|| format!("failed to find worktree {}", name))?;
+        if worktree.validate().is_err() {
+            continue;
+        }
+        worktrees.push(Worktree {
+            name: name.to_owned(),
+            repo: Repository::open_from_worktree(&worktree)
+                .with_context(|| format!("failed to open worktree {}", name))?,
+        });
+    }
+    if !main.is_bare() {
+        worktrees.insert(
+            0,
+            Worktree {
+                name: "main".to_owned(),
+                repo: main,
+            },
+        );
+    }
+    Ok(worktrees)
+}
+
+/// Fails if `refname` is checked out in a worktree other than `repo`, since
+/// moving it would leave that worktree out of sync with its HEAD.
+pub fn check_not_checked_out(repo: &Repository, refname: &str) -> anyhow::Result<()> {
+    for worktree in list(repo)? {
+        if !worktree.is(repo) && worktree.branch()?.as_deref() == Some(refname) {
+            anyhow::bail!(
+                "{} is checked out in worktree {}",
+                refname.strip_prefix("refs/heads/").unwrap_or(refname),
+                worktree.path().display()
+            );
+        }
+    }
+    Ok(())
+}